@@ -0,0 +1,315 @@
+use super::{BackendInfo, GenerationRequest, LlmBackend, TokenSink};
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A cached response, stored in its own file named after the hash of
+/// the request.
+#[derive(Serialize, Deserialize, Debug)]
+struct CacheEntry {
+    /// Seconds since the Unix epoch.
+    created: u64,
+    response: String,
+}
+
+/// How the response cache is used.
+#[derive(Debug, Clone)]
+pub struct CacheSettings {
+    pub path: PathBuf,
+
+    /// Never serve cached responses, but still store new ones. Useful
+    /// to refresh the cache after changing a prompt's wording in a
+    /// way that does not change the prompt itself.
+    pub bypass: bool,
+
+    /// Cached responses older than this are generated again.
+    pub max_age: Option<Duration>,
+}
+
+/// Hash of everything in a request that determines the response,
+/// including which backend and model generate it. The gen key is
+/// random per conversation, so it is not included. The JSON schema is
+/// generated from the same type as the grammar, so it does not need
+/// to be either. Retries get a different sampler seed, so the attempt
+/// is covered by the sampler.
+fn request_hash(backend: &str, model: &str, request: &GenerationRequest<'_>) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(backend.as_bytes());
+    hasher.update([0]);
+    hasher.update(model.as_bytes());
+    hasher.update([0]);
+    hasher.update(request.prompt.as_bytes());
+    hasher.update([0]);
+    hasher.update(request.grammar.unwrap_or("").as_bytes());
+    hasher.update([0]);
+    hasher.update(request.max_tokens.to_le_bytes());
+    hasher.update(serde_json::to_vec(request.sampler)?);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+/// Wraps another backend, and stores every response on disk. The same
+/// request is then answered from disk instead of generating it again,
+/// which saves minutes when repeatedly creating the same scenes and
+/// people during development.
+pub struct CachingBackend {
+    inner: Box<dyn LlmBackend>,
+
+    /// Identifies the backend, e.g. its kind and endpoint, so that
+    /// backends sharing the cache do not answer for each other.
+    backend: String,
+
+    /// The model the backend reported when probed, if any. Loading
+    /// another model in the same backend must not serve the
+    /// responses of the previous one.
+    model: Mutex<Option<String>>,
+    settings: CacheSettings,
+}
+
+impl CachingBackend {
+    pub fn new(
+        inner: Box<dyn LlmBackend>,
+        backend: &str,
+        settings: CacheSettings,
+    ) -> Result<CachingBackend> {
+        std::fs::create_dir_all(&settings.path)?;
+        Ok(CachingBackend {
+            inner,
+            backend: backend.to_string(),
+            model: Mutex::new(None),
+            settings,
+        })
+    }
+
+    fn entry_path(&self, hash: &str) -> PathBuf {
+        self.settings.path.join(format!("{}.json", hash))
+    }
+
+    /// The cached response for a request, if there is one that has
+    /// not expired. Unreadable entries are treated as missing, and
+    /// will be overwritten. Retries are never served from the cache,
+    /// since the cached response may be the one that failed.
+    fn lookup(&self, request: &GenerationRequest<'_>, path: &Path) -> Option<String> {
+        if self.settings.bypass || request.attempt > 1 {
+            return None;
+        }
+
+        let json = std::fs::read_to_string(path).ok()?;
+        let entry: CacheEntry = serde_json::from_str(&json).ok()?;
+
+        let expired = self
+            .settings
+            .max_age
+            .is_some_and(|max_age| now().saturating_sub(entry.created) > max_age.as_secs());
+
+        match expired {
+            true => None,
+            false => Some(entry.response),
+        }
+    }
+
+    fn store(&self, path: &Path, response: &str) -> Result<()> {
+        let entry = CacheEntry {
+            created: now(),
+            response: response.to_string(),
+        };
+
+        std::fs::write(path, serde_json::to_string(&entry)?)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LlmBackend for CachingBackend {
+    async fn generate(&self, request: &GenerationRequest<'_>) -> Result<String> {
+        self.generate_streaming(request, &mut |_: &str| ()).await
+    }
+
+    async fn generate_streaming(
+        &self,
+        request: &GenerationRequest<'_>,
+        sink: &mut dyn TokenSink,
+    ) -> Result<String> {
+        // A continuation depends on the grammar state the backend kept
+        // from the generation before it, which a cached response does
+        // not restore.
+        if request.retain_grammar_state {
+            return self.inner.generate_streaming(request, sink).await;
+        }
+
+        let model = self.model.lock().unwrap().clone().unwrap_or_default();
+        let path = self.entry_path(&request_hash(&self.backend, &model, request)?);
+
+        if let Some(response) = self.lookup(request, &path) {
+            sink.token(&response);
+            return Ok(response);
+        }
+
+        let response = self.inner.generate_streaming(request, sink).await?;
+        self.store(&path, &response)?;
+        Ok(response)
+    }
+
+    async fn abort(&self, gen_key: &str) -> Result<()> {
+        self.inner.abort(gen_key).await
+    }
+
+    fn can_continue(&self) -> bool {
+        self.inner.can_continue()
+    }
+
+    async fn count_tokens(&self, text: &str) -> Result<usize> {
+        self.inner.count_tokens(text).await
+    }
+
+    async fn probe(&self) -> Result<BackendInfo> {
+        let info = self.inner.probe().await?;
+        *self.model.lock().unwrap() = info.model.clone();
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::sampling::SamplerProfile;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Answers every request with the number of requests so far, and
+    /// reports the given model when probed.
+    struct Counter(Arc<AtomicUsize>, Option<&'static str>);
+
+    #[async_trait]
+    impl LlmBackend for Counter {
+        async fn generate(&self, _request: &GenerationRequest<'_>) -> Result<String> {
+            Ok((self.0.fetch_add(1, Ordering::SeqCst) + 1).to_string())
+        }
+
+        async fn probe(&self) -> Result<BackendInfo> {
+            Ok(BackendInfo {
+                model: self.1.map(String::from),
+                ..Default::default()
+            })
+        }
+    }
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ai-game-cache-{}-{}", name, now()));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    fn backend_in(path: &Path, backend: &str, counter: Counter) -> CachingBackend {
+        let settings = CacheSettings {
+            path: path.to_path_buf(),
+            bypass: false,
+            max_age: None,
+        };
+
+        CachingBackend::new(Box::new(counter), backend, settings).unwrap()
+    }
+
+    fn caching_backend(name: &str) -> CachingBackend {
+        let counter = Counter(Arc::new(AtomicUsize::new(0)), None);
+        backend_in(&cache_dir(name), "test", counter)
+    }
+
+    fn request(sampler: &SamplerProfile) -> GenerationRequest<'_> {
+        GenerationRequest {
+            gen_key: "test",
+            prompt: "Describe a room.",
+            messages: &[],
+            stop_sequences: &[],
+            grammar: None,
+            json_schema: None,
+            max_tokens: 32,
+            max_context_length: 4096,
+            sampler,
+            retain_grammar_state: false,
+            attempt: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_the_same_request_from_the_cache() {
+        let backend = caching_backend("same");
+        let sampler = SamplerProfile::default();
+
+        assert_eq!(backend.generate(&request(&sampler)).await.unwrap(), "1");
+        assert_eq!(backend.generate(&request(&sampler)).await.unwrap(), "1");
+        std::fs::remove_dir_all(&backend.settings.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn retries_replace_the_cached_response() {
+        let backend = caching_backend("retry");
+        let sampler = SamplerProfile::default();
+        let retry = GenerationRequest {
+            attempt: 2,
+            ..request(&sampler)
+        };
+
+        assert_eq!(backend.generate(&request(&sampler)).await.unwrap(), "1");
+        assert_eq!(backend.generate(&retry).await.unwrap(), "2");
+        assert_eq!(backend.generate(&request(&sampler)).await.unwrap(), "2");
+        std::fs::remove_dir_all(&backend.settings.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn continuations_are_not_cached() {
+        let backend = caching_backend("continuation");
+        let sampler = SamplerProfile::default();
+        let continuation = GenerationRequest {
+            retain_grammar_state: true,
+            ..request(&sampler)
+        };
+
+        assert_eq!(backend.generate(&continuation).await.unwrap(), "1");
+        assert_eq!(backend.generate(&continuation).await.unwrap(), "2");
+        assert_eq!(backend.generate(&request(&sampler)).await.unwrap(), "3");
+        std::fs::remove_dir_all(&backend.settings.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn backends_do_not_share_responses() {
+        let path = cache_dir("backends");
+        let count = Arc::new(AtomicUsize::new(0));
+        let sampler = SamplerProfile::default();
+
+        let kobold = backend_in(&path, "kobold", Counter(count.clone(), None));
+        let openai = backend_in(&path, "openai", Counter(count.clone(), None));
+
+        assert_eq!(kobold.generate(&request(&sampler)).await.unwrap(), "1");
+        assert_eq!(openai.generate(&request(&sampler)).await.unwrap(), "2");
+        assert_eq!(kobold.generate(&request(&sampler)).await.unwrap(), "1");
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn models_do_not_share_responses() {
+        let path = cache_dir("models");
+        let count = Arc::new(AtomicUsize::new(0));
+        let sampler = SamplerProfile::default();
+
+        let small = backend_in(&path, "kobold", Counter(count.clone(), Some("small")));
+        let large = backend_in(&path, "kobold", Counter(count.clone(), Some("large")));
+        small.probe().await.unwrap();
+        large.probe().await.unwrap();
+
+        assert_eq!(small.generate(&request(&sampler)).await.unwrap(), "1");
+        assert_eq!(large.generate(&request(&sampler)).await.unwrap(), "2");
+        assert_eq!(small.generate(&request(&sampler)).await.unwrap(), "1");
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+use super::context::estimate_tokens;
+use super::sampling::SamplerProfile;
+use super::template::ChatMessage;
+use anyhow::Result;
+use async_trait::async_trait;
+use eventsource_client as es;
+use thiserror::Error;
+
+pub mod cache;
+pub mod replay;
+
+/// Ways a generation can fail. Backends and the conversation layer
+/// return these wrapped in `anyhow`, so callers can downcast to find
+/// out whether trying again makes sense.
+#[derive(Error, Debug)]
+pub enum GenerationError {
+    #[error("could not communicate with the LLM backend: {0}")]
+    Transport(String),
+
+    #[error("the LLM backend timed out")]
+    Timeout,
+
+    #[error("the LLM generated malformed JSON: {0}")]
+    MalformedJson(#[from] serde_json::Error),
+
+    #[error("the LLM backend rejected the grammar: {0}")]
+    GrammarUnsupported(String),
+
+    #[error("the LLM response left the grammar at byte {position}, at `{near}`")]
+    GrammarViolation { position: usize, near: String },
+
+    #[error("prompt needs {needed} tokens, but the context budget is {budget}")]
+    BudgetExceeded { needed: usize, budget: u64 },
+
+    #[error("the generation was cancelled")]
+    Cancelled,
+}
+
+impl GenerationError {
+    /// Whether the same request might succeed if sent again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            GenerationError::Transport(_) => true,
+            GenerationError::Timeout => true,
+            GenerationError::MalformedJson(_) => true,
+            GenerationError::GrammarUnsupported(_) => false,
+            GenerationError::GrammarViolation { .. } => false,
+            GenerationError::BudgetExceeded { .. } => false,
+            GenerationError::Cancelled => false,
+        }
+    }
+}
+
+impl From<es::Error> for GenerationError {
+    fn from(value: es::Error) -> Self {
+        match value {
+            es::Error::TimedOut => GenerationError::Timeout,
+            err => GenerationError::Transport(format!("{:?}", err)),
+        }
+    }
+}
+
+/// Receives the text of a response while it is being generated.
+pub trait TokenSink: Send {
+    fn token(&mut self, token: &str);
+
+    /// The response is thrown away and will be generated again from
+    /// the start, e.g. when a failed generation is retried.
+    fn restart(&mut self) {}
+}
+
+impl<F: FnMut(&str) + Send> TokenSink for F {
+    fn token(&mut self, token: &str) {
+        self(token)
+    }
+}
+
+/// Everything a backend needs to know in order to produce a single
+/// completion for a conversation. The prompt is the entire
+/// conversation so far, already rendered with the chat template.
+/// Backends that apply their own template can use the messages
+/// instead.
+#[derive(Debug, Clone, Copy)]
+pub struct GenerationRequest<'a> {
+    /// Identifies the generation on backends that support multiple
+    /// users or aborting in-flight requests.
+    pub gen_key: &'a str,
+    pub prompt: &'a str,
+    pub messages: &'a [ChatMessage],
+    pub stop_sequences: &'a [&'a str],
+    pub grammar: Option<&'a str>,
+
+    /// The same constraint as the grammar, as a JSON Schema, for
+    /// backends that take a schema instead of GBNF.
+    pub json_schema: Option<&'a str>,
+    pub max_tokens: u64,
+
+    /// Size of the context window the conversation is budgeted for,
+    /// prompt and response included.
+    pub max_context_length: u64,
+    pub sampler: &'a SamplerProfile,
+
+    /// Continue with the grammar state of the previous generation,
+    /// instead of starting the grammar over. Used when the model
+    /// stopped in the middle of a JSON response.
+    pub retain_grammar_state: bool,
+
+    /// Which attempt at the prompt this is, starting at 1. A retry
+    /// means that an earlier response could not be used.
+    pub attempt: u32,
+}
+
+/// What a backend reported about itself and the loaded model. Fields
+/// are None if the backend cannot tell.
+#[derive(Debug, Clone, Default)]
+pub struct BackendInfo {
+    pub model: Option<String>,
+
+    /// The context length the model was loaded with.
+    pub max_context_length: Option<u64>,
+
+    /// Whether generations can be constrained with a GBNF grammar.
+    pub grammar_support: Option<bool>,
+}
+
+/// A large language model that can complete prompts. The
+/// conversation layer only talks to the LLM through this trait, so
+/// that the backend can be swapped without touching the prompts or
+/// coherence code.
+#[async_trait]
+pub trait LlmBackend: Send + Sync {
+    /// Generate a completion for the request, returning the raw text
+    /// produced by the model.
+    async fn generate(&self, request: &GenerationRequest<'_>) -> Result<String>;
+
+    /// Like `generate`, but every token is also handed to the sink as
+    /// soon as it arrives. Backends that cannot stream hand over the
+    /// whole response at once.
+    async fn generate_streaming(
+        &self,
+        request: &GenerationRequest<'_>,
+        sink: &mut dyn TokenSink,
+    ) -> Result<String> {
+        let response = self.generate(request).await?;
+        sink.token(&response);
+        Ok(response)
+    }
+
+    /// Stop the generation with the given key on the server. Closing
+    /// the connection is enough for most servers, so by default this
+    /// does nothing.
+    async fn abort(&self, _gen_key: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether the backend can carry on with a response that stopped
+    /// in the middle, as requested with `retain_grammar_state`.
+    /// Incomplete responses from backends that cannot are generated
+    /// again from the start instead.
+    fn can_continue(&self) -> bool {
+        true
+    }
+
+    /// Count the tokens in the text with the model's tokenizer.
+    /// Backends without a tokenizer fall back to an estimate.
+    async fn count_tokens(&self, text: &str) -> Result<usize> {
+        Ok(estimate_tokens(text))
+    }
+
+    /// Check that the backend can be reached, and find out what it
+    /// can do. Called once at startup, so that configuration problems
+    /// are reported before anything is generated.
+    async fn probe(&self) -> Result<BackendInfo> {
+        Ok(BackendInfo::default())
+    }
+}
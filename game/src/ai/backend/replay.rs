@@ -0,0 +1,253 @@
+use super::{BackendInfo, GenerationRequest, LlmBackend, TokenSink};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// A single recorded generation. The prompt and grammar are kept in
+/// the fixture file only so that it can be read and diffed by
+/// humans. Lookups happen by hash.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Fixture {
+    pub prompt: String,
+    pub grammar: Option<String>,
+    pub response: String,
+}
+
+/// All recorded generations, keyed by prompt hash. A BTreeMap keeps
+/// the file stable between recordings.
+pub type Fixtures = BTreeMap<String, Fixture>;
+
+/// Hash of the parts of a request that determine the response. The
+/// gen key is random per conversation, so it is not included.
+pub fn prompt_hash(request: &GenerationRequest<'_>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(request.prompt.as_bytes());
+    hasher.update([0]);
+    hasher.update(request.grammar.unwrap_or("").as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+pub fn load_fixtures(path: &Path) -> Result<Fixtures> {
+    if !path.exists() {
+        return Ok(Fixtures::new());
+    }
+
+    let file = std::fs::File::open(path)?;
+    let fixtures = serde_json::from_reader(std::io::BufReader::new(file))?;
+    Ok(fixtures)
+}
+
+fn save_fixtures(path: &Path, fixtures: &Fixtures) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let json = serde_json::to_string_pretty(fixtures)?;
+    std::fs::write(path, json)?;
+    Ok(())
+}
+
+/// The fixtures of a file that is being recorded to.
+type SharedFixtures = Arc<Mutex<Fixtures>>;
+
+/// The fixtures of the file, loaded the first time a backend records
+/// to it. Every backend recording to the same file shares them, so
+/// that they do not overwrite each other's fixtures.
+fn shared_fixtures(path: &Path) -> Result<SharedFixtures> {
+    static OPEN: OnceLock<Mutex<HashMap<PathBuf, SharedFixtures>>> = OnceLock::new();
+
+    let key = std::path::absolute(path)?;
+    let mut open = OPEN.get_or_init(Default::default).lock().unwrap();
+    if let Some(fixtures) = open.get(&key) {
+        return Ok(fixtures.clone());
+    }
+
+    let fixtures = Arc::new(Mutex::new(load_fixtures(path)?));
+    open.insert(key, fixtures.clone());
+    Ok(fixtures)
+}
+
+/// Wraps another backend, and saves every prompt and response that
+/// goes through it to a fixture file. Existing fixtures in the file
+/// are kept, including ones recorded by other backends at the same
+/// time.
+pub struct RecordingBackend {
+    inner: Box<dyn LlmBackend>,
+    path: PathBuf,
+    fixtures: SharedFixtures,
+}
+
+impl RecordingBackend {
+    pub fn new(inner: Box<dyn LlmBackend>, path: impl Into<PathBuf>) -> Result<RecordingBackend> {
+        let path = path.into();
+        let fixtures = shared_fixtures(&path)?;
+
+        Ok(RecordingBackend {
+            inner,
+            path,
+            fixtures,
+        })
+    }
+
+    fn record(&self, request: &GenerationRequest<'_>, response: &str) -> Result<()> {
+        let fixture = Fixture {
+            prompt: request.prompt.to_string(),
+            grammar: request.grammar.map(String::from),
+            response: response.to_string(),
+        };
+
+        // The lock is held while saving, so that the file is never
+        // written with fixtures older than the ones already in it.
+        let mut fixtures = self.fixtures.lock().unwrap();
+        fixtures.insert(prompt_hash(request), fixture);
+        save_fixtures(&self.path, &fixtures)
+    }
+}
+
+#[async_trait]
+impl LlmBackend for RecordingBackend {
+    async fn generate(&self, request: &GenerationRequest<'_>) -> Result<String> {
+        let response = self.inner.generate(request).await?;
+        self.record(request, &response)?;
+        Ok(response)
+    }
+
+    async fn generate_streaming(
+        &self,
+        request: &GenerationRequest<'_>,
+        sink: &mut dyn TokenSink,
+    ) -> Result<String> {
+        let response = self.inner.generate_streaming(request, sink).await?;
+        self.record(request, &response)?;
+        Ok(response)
+    }
+
+    async fn abort(&self, gen_key: &str) -> Result<()> {
+        self.inner.abort(gen_key).await
+    }
+
+    fn can_continue(&self) -> bool {
+        self.inner.can_continue()
+    }
+
+    async fn count_tokens(&self, text: &str) -> Result<usize> {
+        self.inner.count_tokens(text).await
+    }
+
+    async fn probe(&self) -> Result<BackendInfo> {
+        self.inner.probe().await
+    }
+}
+
+/// Serves responses from previously recorded fixtures, without ever
+/// talking to a model. Makes the whole AI pipeline deterministic, so
+/// it can run on machines with no LLM available.
+pub struct ReplayBackend {
+    fixtures: Fixtures,
+}
+
+impl ReplayBackend {
+    pub fn new(fixtures: Fixtures) -> ReplayBackend {
+        ReplayBackend { fixtures }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<ReplayBackend> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(anyhow!("fixture file {} does not exist", path.display()));
+        }
+
+        Ok(ReplayBackend::new(load_fixtures(path)?))
+    }
+}
+
+#[async_trait]
+impl LlmBackend for ReplayBackend {
+    async fn generate(&self, request: &GenerationRequest<'_>) -> Result<String> {
+        let hash = prompt_hash(request);
+
+        self.fixtures
+            .get(&hash)
+            .map(|fixture| fixture.response.clone())
+            .ok_or_else(|| anyhow!("no fixture recorded for prompt hash {}", hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::sampling::SamplerProfile;
+    use futures::future::join_all;
+
+    /// Answers every prompt with the prompt itself.
+    struct Echo;
+
+    #[async_trait]
+    impl LlmBackend for Echo {
+        async fn generate(&self, request: &GenerationRequest<'_>) -> Result<String> {
+            Ok(request.prompt.to_string())
+        }
+    }
+
+    fn request<'a>(prompt: &'a str, sampler: &'a SamplerProfile) -> GenerationRequest<'a> {
+        GenerationRequest {
+            gen_key: "test",
+            prompt,
+            messages: &[],
+            stop_sequences: &[],
+            grammar: Some("root ::= \"x\""),
+            json_schema: None,
+            max_tokens: 32,
+            max_context_length: 4096,
+            sampler,
+            retain_grammar_state: false,
+            attempt: 1,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn backends_recording_to_the_same_file_keep_each_others_fixtures() {
+        let path =
+            std::env::temp_dir().join(format!("ai-game-fixtures-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let backends = [
+            Arc::new(RecordingBackend::new(Box::new(Echo), &path).unwrap()),
+            Arc::new(RecordingBackend::new(Box::new(Echo), &path).unwrap()),
+        ];
+
+        let prompts: Vec<String> = (0..40).map(|n| format!("prompt {}", n)).collect();
+        let generations = prompts.iter().enumerate().map(|(n, prompt)| {
+            let backend = backends[n % 2].clone();
+            let prompt = prompt.clone();
+            tokio::spawn(async move {
+                let sampler = SamplerProfile::default();
+                backend.generate(&request(&prompt, &sampler)).await
+            })
+        });
+
+        for result in join_all(generations).await {
+            result.unwrap().unwrap();
+        }
+
+        let sampler = SamplerProfile::default();
+        let replay = ReplayBackend::from_file(&path).unwrap();
+        for prompt in &prompts {
+            let response = replay.generate(&request(prompt, &sampler)).await.unwrap();
+            assert_eq!(&response, prompt);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unrecorded_prompts_are_errors() {
+        let sampler = SamplerProfile::default();
+        let replay = ReplayBackend::new(Fixtures::new());
+        assert!(replay.generate(&request("prompt", &sampler)).await.is_err());
+    }
+}
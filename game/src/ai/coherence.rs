@@ -1,7 +1,6 @@
 use anyhow::{anyhow, Result};
-use std::cell::RefCell;
 use std::mem;
-use std::rc::Rc;
+use std::sync::Arc;
 
 use itertools::Itertools;
 
@@ -83,11 +82,11 @@ fn is_duplicate_recorded(failures: &[CoherenceFailure], exit: &Exit) -> bool {
 /// the world. It's not doing coherence to fix things like command
 /// execution.
 pub(super) struct AiCoherence {
-    generator: Rc<AiGenerator>,
+    generator: Arc<AiGenerator>,
 }
 
 impl AiCoherence {
-    pub fn new(generator: Rc<AiGenerator>) -> AiCoherence {
+    pub fn new(generator: Arc<AiGenerator>) -> AiCoherence {
         AiCoherence { generator }
     }
 
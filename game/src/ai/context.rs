@@ -0,0 +1,182 @@
+use super::template::{ChatMessage, Role};
+use anyhow::anyhow;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Marks the system message that replaces summarized turns, so it is
+/// not mistaken for the instruction header.
+pub const SUMMARY_PREFIX: &str = "Summary of the conversation so far: ";
+
+/// Rough number of characters per token for English text, used when
+/// the backend cannot count tokens itself.
+const CHARS_PER_TOKEN: usize = 4;
+
+/// Estimate the number of tokens in a piece of text, without a
+/// tokenizer. Errs on the side of overestimating.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+/// What to do with the oldest turns of a conversation when it no
+/// longer fits in the context window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrimPolicy {
+    /// Remove the oldest turns entirely.
+    DropOldest,
+
+    /// Ask the LLM to summarize the oldest turns, and replace them
+    /// with the summary.
+    Summarize,
+}
+
+impl FromStr for TrimPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_ref() {
+            "drop" | "drop_oldest" => Ok(TrimPolicy::DropOldest),
+            "summarize" => Ok(TrimPolicy::Summarize),
+            _ => Err(anyhow!("unknown context trim policy: {}", value)),
+        }
+    }
+}
+
+/// How many tokens a conversation may use, including the response.
+#[derive(Debug, Clone, Copy)]
+pub struct ContextBudget {
+    pub max_tokens: u64,
+    pub policy: TrimPolicy,
+}
+
+impl Default for ContextBudget {
+    fn default() -> Self {
+        ContextBudget {
+            max_tokens: 4096,
+            policy: TrimPolicy::DropOldest,
+        }
+    }
+}
+
+fn is_summary(message: &ChatMessage) -> bool {
+    message.role == Role::System && message.content.starts_with(SUMMARY_PREFIX)
+}
+
+/// The instruction header is every system message at the start of
+/// the conversation, which is never trimmed.
+fn header_len(history: &[ChatMessage]) -> usize {
+    history
+        .iter()
+        .take_while(|msg| msg.role == Role::System && !is_summary(msg))
+        .count()
+}
+
+/// The messages that can be trimmed: everything between the header
+/// and the last `protected` messages, which belong to the turn in
+/// progress. An existing summary is included, so that it can be
+/// folded into a new one.
+pub fn trimmable_range(history: &[ChatMessage], protected: usize) -> Range<usize> {
+    let start = header_len(history);
+    let end = history.len().saturating_sub(protected).max(start);
+    start..end
+}
+
+/// Length of the oldest turn in the range: a user message and the
+/// responses to it.
+pub fn oldest_turn_len(history: &[ChatMessage], range: &Range<usize>) -> usize {
+    let turn = &history[range.clone()];
+    let rest = turn
+        .iter()
+        .skip(1)
+        .take_while(|msg| msg.role != Role::User)
+        .count();
+
+    (1 + rest).min(turn.len())
+}
+
+/// Flatten turns into plain text, so they can be summarized.
+pub fn transcript(messages: &[ChatMessage]) -> String {
+    messages
+        .iter()
+        .map(|msg| format!("{}: {}", msg.role.as_str(), msg.content.trim()))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+pub fn summary_message(summary: &str) -> ChatMessage {
+    ChatMessage::system(&format!("{}{}", SUMMARY_PREFIX, summary.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history() -> Vec<ChatMessage> {
+        vec![
+            ChatMessage::system("instructions"),
+            ChatMessage::system("more instructions"),
+            ChatMessage::user("first"),
+            ChatMessage::assistant("first answer"),
+            ChatMessage::user("second"),
+            ChatMessage::assistant("second answer"),
+            ChatMessage::assistant("more of the second answer"),
+            ChatMessage::user("third"),
+        ]
+    }
+
+    #[test]
+    fn the_header_and_the_turn_in_progress_are_not_trimmable() {
+        let history = history();
+        assert_eq!(trimmable_range(&history, 1), 2..7);
+        assert_eq!(trimmable_range(&history, 0), 2..8);
+        assert_eq!(trimmable_range(&history, 8), 2..2);
+    }
+
+    #[test]
+    fn summaries_are_trimmable() {
+        let mut history = history();
+        history.insert(2, summary_message("they talked"));
+        assert!(is_summary(&history[2]));
+        assert_eq!(trimmable_range(&history, 1), 2..8);
+    }
+
+    #[test]
+    fn turns_are_trimmed_oldest_first() {
+        let mut history = history();
+        let mut trimmed = vec![];
+
+        loop {
+            let range = trimmable_range(&history, 1);
+            if range.is_empty() {
+                break;
+            }
+
+            let turn_len = oldest_turn_len(&history, &range);
+            let turn = history.drain(range.start..range.start + turn_len);
+            trimmed.push(turn.map(|msg| msg.content).collect::<Vec<_>>());
+        }
+
+        assert_eq!(
+            trimmed,
+            vec![
+                vec!["first", "first answer"],
+                vec!["second", "second answer", "more of the second answer"],
+            ]
+        );
+
+        let kept: Vec<_> = history.iter().map(|msg| msg.content.as_str()).collect();
+        assert_eq!(kept, vec!["instructions", "more instructions", "third"]);
+    }
+
+    #[test]
+    fn parses_trim_policies() {
+        assert_eq!(
+            "drop".parse::<TrimPolicy>().unwrap(),
+            TrimPolicy::DropOldest
+        );
+        assert_eq!(
+            "Summarize".parse::<TrimPolicy>().unwrap(),
+            TrimPolicy::Summarize
+        );
+        assert!("forget".parse::<TrimPolicy>().is_err());
+    }
+}
@@ -1,125 +1,428 @@
-use crate::kobold_api::{create_input, Client as KoboldClient, SseGenerationExt};
+use super::backend::{GenerationError, GenerationRequest, LlmBackend, TokenSink};
+use super::context::{self, ContextBudget, TrimPolicy};
+use super::prompts::summary_prompts::summary_prompt;
+use super::repair::repair_truncated_json;
+use super::sampling::{self, PromptKind, SamplerProfile, SamplerSettings};
+use super::template::{ChatMessage, ChatTemplate, Role};
+use super::transcript::{Outcome, TranscriptEntry, TranscriptLogger};
 use crate::models::new_uuid_string;
+use crate::telemetry::METRICS_TARGET;
 use anyhow::Result;
-use async_recursion::async_recursion;
+use gbnf::{GbnfRecognizer, ParsedGrammar, Recognition};
 use serde::de::DeserializeOwned;
 use serde_json::error::Category;
 use serde_json::Value;
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::iter;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
-/// Characters which can break the JSON deserialization. Do not rely
-/// on model to NOT print these (though it shouldn't). Make sure they
-/// are removed from JSON responses.
-const ILLEGAL_CHARACTERS: [char; 1] = ['\\'];
+/// Characters that can follow a backslash in a JSON string, other
+/// than `u` and four hex digits. Any other character after a
+/// backslash breaks the JSON deserialization. Do not rely on the
+/// model to NOT print these (though the grammar should stop it).
+const JSON_ESCAPES: [char; 8] = ['"', '\\', '/', 'b', 'f', 'n', 'r', 't'];
 
-fn sanitize_json_response(mut json: String) -> String {
-    for illegal_char in ILLEGAL_CHARACTERS {
-        json = json.replace(illegal_char, "");
+/// Remove the backslashes that do not start a valid escape, and keep
+/// the ones that do, so that quotes and newlines in strings survive.
+fn sanitize_json_response(json: String) -> String {
+    let mut sanitized = String::with_capacity(json.len());
+    let mut chars = json.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            sanitized.push(c);
+            continue;
+        }
+
+        // A backslash at the very end is kept, since the rest of the
+        // escape may come with the continuation of the response.
+        let rest = chars.as_str();
+        let valid = match rest.chars().next() {
+            None => true,
+            Some('u') => rest.chars().skip(1).take(4).all(|c| c.is_ascii_hexdigit()),
+            Some(next) => JSON_ESCAPES.contains(&next),
+        };
+
+        if valid {
+            sanitized.push(c);
+
+            // An escaped backslash must not start another escape.
+            if rest.starts_with('\\') {
+                sanitized.push('\\');
+                chars.next();
+            }
+        }
     }
 
-    json
+    sanitized
+}
+
+/// A recognizer for the grammar of the prompt, if it has one. The
+/// grammars are generated, so one that does not compile is a bug,
+/// but not a reason to fail the generation.
+fn prompt_recognizer(prompt: &AiPrompt) -> Option<GbnfRecognizer> {
+    let grammar = prompt.grammar.as_deref()?;
+    let recognizer = ParsedGrammar::parse(grammar)
+        .map_err(anyhow::Error::from)
+        .and_then(|grammar| Ok(GbnfRecognizer::new(&grammar)?));
+
+    match recognizer {
+        Ok(recognizer) => Some(recognizer),
+        Err(err) => {
+            tracing::warn!(error = %err, "could not compile the grammar");
+            None
+        }
+    }
+}
+
+fn grammar_violation(response: &str, position: usize) -> anyhow::Error {
+    let near = response[position..].chars().take(20).collect();
+    GenerationError::GrammarViolation { position, near }.into()
+}
+
+/// How the conversations with the LLM are rendered and kept within
+/// the model's context window.
+#[derive(Debug, Clone, Default)]
+pub struct ConversationSettings {
+    pub template: ChatTemplate,
+    pub context: ContextBudget,
+    pub samplers: SamplerSettings,
+
+    /// How failed generations are tried again.
+    pub retry: RetryPolicy,
+
+    /// Seed of the world, from which the sampler seed of every
+    /// generation is derived, unless the profile sets one.
+    pub world_seed: Option<u64>,
+
+    /// Records every turn of the conversation, if set.
+    pub transcript: Option<Arc<TranscriptLogger>>,
+}
+
+/// What it took to get a response to a prompt, across all attempts.
+#[derive(Debug, Default)]
+struct GenerationStats {
+    attempts: u32,
+    generations: u32,
+    continuations: u32,
+    prompt_tokens: usize,
+    response_tokens: usize,
+    repaired: bool,
+}
+
+impl GenerationStats {
+    fn report<T>(&self, kind: Option<PromptKind>, elapsed: Duration, result: &Result<T>) {
+        let error = result.as_ref().err().map(|err| err.to_string());
+
+        tracing::info!(
+            target: METRICS_TARGET,
+            metric = "llm_call",
+            kind = kind.map(<&str>::from),
+            attempts = self.attempts,
+            generations = self.generations,
+            continuations = self.continuations,
+            prompt_tokens = self.prompt_tokens,
+            response_tokens = self.response_tokens,
+            repaired = self.repaired,
+            elapsed_ms = elapsed.as_millis() as u64,
+            success = result.is_ok(),
+            error = error.as_deref(),
+        );
+    }
 }
 
 struct AiExecution<'a> {
-    client: &'a KoboldClient,
+    backend: &'a dyn LlmBackend,
+    settings: &'a ConversationSettings,
     gen_key: &'a str,
     prompt: &'a AiPrompt,
-    prompt_so_far: &'a mut String,
+    history: &'a mut Vec<ChatMessage>,
+
+    /// Receives the response as it is generated.
+    sink: &'a mut dyn TokenSink,
+    stats: &'a mut GenerationStats,
+
+    /// Checks responses against the prompt's grammar, which not
+    /// every backend enforces.
+    recognizer: Option<GbnfRecognizer>,
+
+    /// Everything generated for the prompt, for the transcript.
+    responses: Vec<String>,
 }
 
-async fn converse<'a, T: DeserializeOwned>(details: &mut AiExecution<'a>) -> Result<T> {
-    // Handle Mistral-instruct begin instruct mode.
-    // https://huggingface.co/mistralai/Mistral-7B-Instruct-v0.2
-    // Only the very first one begins with <s>, not subsequent.
-    if details.prompt_so_far.is_empty() {
-        details.prompt_so_far.push_str("<s>");
-    }
-
-    details.prompt_so_far.push_str(&details.prompt.prompt);
-
-    let input = create_input(
-        details.gen_key.to_string(),
-        &details.prompt_so_far,
-        details.prompt.grammar.clone(),
-        details.prompt.max_tokens,
-        false,
-        details.prompt.creativity,
-    );
-
-    // TODO working on removing trait bounds issue so we can use ? operator.
-    let mut str_resp = details
-        .client
-        .sse_generate(input)
-        .await
-        .map(sanitize_json_response)
-        .unwrap();
-
-    details.prompt_so_far.push_str(&str_resp);
-
-    let resp: T = match serde_json::from_str(&str_resp) {
-        Ok(obj) => obj,
-        Err(e) => {
-            // If the resp is not fully valid JSON, request more
-            // from the LLM.
-            match e.classify() {
-                Category::Eof => {
-                    continue_execution(
-                        details,
-                        &mut str_resp,
-                    )
-                    .await?
+impl AiExecution<'_> {
+    /// The sampler profile for a generation. With a world seed, the
+    /// same prompt and grammar always get the same sampler seed. Each
+    /// retry gets another seed, or it would get the same response.
+    fn sampler(&self, prompt: &AiPrompt, rendered: &str, grammar: Option<&str>) -> SamplerProfile {
+        let mut sampler = self.settings.samplers.profile(prompt.creativity, prompt.kind);
+        if let (None, Some(world_seed)) = (sampler.seed, self.settings.world_seed) {
+            sampler.seed = Some(sampling::derive_seed(world_seed, rendered, grammar));
+        }
+
+        let retries = self.stats.attempts.saturating_sub(1);
+        sampler.seed = sampler.seed.map(|seed| sampling::retry_seed(seed, retries));
+
+        sampler
+    }
+
+    /// Render the conversation so far and send it to the backend.
+    async fn generate(&mut self, retain_grammar_state: bool) -> Result<String> {
+        let template = self.settings.template;
+        let rendered = template.render(self.history.as_slice());
+        let grammar = self.prompt.grammar.as_deref();
+        let sampler = self.sampler(self.prompt, &rendered, grammar);
+
+        let request = GenerationRequest {
+            gen_key: self.gen_key,
+            prompt: &rendered,
+            messages: self.history.as_slice(),
+            stop_sequences: template.stop_sequences(),
+            grammar,
+            json_schema: self.prompt.json_schema.as_deref(),
+            max_tokens: self.prompt.max_tokens,
+            max_context_length: self.settings.context.max_tokens,
+            sampler: &sampler,
+            retain_grammar_state,
+            attempt: self.stats.attempts,
+        };
+
+        let response = self.backend.generate_streaming(&request, self.sink).await?;
+
+        // The prompt of the first generation is the one that was
+        // asked. Continuations only add the response so far to it.
+        if self.stats.generations == 0 {
+            self.stats.prompt_tokens = context::estimate_tokens(&rendered);
+        }
+
+        self.responses.push(response.clone());
+        self.stats.generations += 1;
+        self.stats.response_tokens += context::estimate_tokens(&response);
+        Ok(response)
+    }
+
+    /// Ask the LLM for a plain text summary of part of the
+    /// conversation. Runs outside of the conversation itself.
+    async fn summarize(&self, messages: &[ChatMessage]) -> Result<String> {
+        let template = self.settings.template;
+        let prompt = summary_prompt(&context::transcript(messages));
+        let rendered = template.render(&prompt.messages);
+        let sampler = self.sampler(&prompt, &rendered, None);
+
+        let request = GenerationRequest {
+            gen_key: self.gen_key,
+            prompt: &rendered,
+            messages: &prompt.messages,
+            stop_sequences: template.stop_sequences(),
+            grammar: None,
+            json_schema: None,
+            max_tokens: prompt.max_tokens,
+            max_context_length: self.settings.context.max_tokens,
+            sampler: &sampler,
+            retain_grammar_state: false,
+            attempt: self.stats.attempts,
+        };
+
+        self.backend.generate(&request).await
+    }
+
+    /// Trim the oldest turns of the conversation until the prompt and
+    /// the response fit in the context budget. The instruction header
+    /// and the last `protected` messages (the turn in progress) are
+    /// always kept.
+    async fn fit_context(&mut self, protected: usize) -> Result<()> {
+        let budget = self.settings.context;
+        let limit = budget.max_tokens.saturating_sub(self.prompt.max_tokens) as usize;
+        let mut policy = budget.policy;
+
+        loop {
+            let rendered = self.settings.template.render(self.history.as_slice());
+            let used = self.backend.count_tokens(&rendered).await?;
+            if used <= limit {
+                return Ok(());
+            }
+
+            let range = context::trimmable_range(self.history, protected);
+            if range.is_empty() {
+                let needed = used + self.prompt.max_tokens as usize;
+                let budget = budget.max_tokens;
+                return Err(GenerationError::BudgetExceeded { needed, budget }.into());
+            }
+
+            match policy {
+                TrimPolicy::DropOldest => {
+                    let turn_len = context::oldest_turn_len(self.history, &range);
+                    self.history.drain(range.start..range.start + turn_len);
+                }
+                TrimPolicy::Summarize => {
+                    let summary = self.summarize(&self.history[range.clone()]).await?;
+                    let summary = context::summary_message(&summary);
+                    self.history.splice(range, iter::once(summary));
+
+                    // If the summary alone is still too long, fall
+                    // back to dropping turns instead of summarizing
+                    // the summary over and over.
+                    policy = TrimPolicy::DropOldest;
                 }
-                _ => return Err(e.into()),
             }
         }
-    };
+    }
+
+    /// Where the response stands against the prompt's grammar, if it
+    /// has one. Whitespace around the response is ignored, as serde
+    /// does.
+    fn recognize(&self, response: &str) -> Option<Recognition> {
+        let recognizer = self.recognizer.as_ref()?;
+        let trimmed = response.trim_start();
+        let offset = response.len() - trimmed.len();
+
+        match recognizer.recognize(trimmed.trim_end()) {
+            Recognition::Diverged { position } => Some(Recognition::Diverged {
+                position: position + offset,
+            }),
+            recognition => Some(recognition),
+        }
+    }
 
-    // mistral 7b end of response token (for when BNF is used)
-    if !details.prompt_so_far.trim().ends_with("</s>") {
-        details.prompt_so_far.push_str("</s>");
+    /// Add more text to the model's response at the end of the
+    /// conversation.
+    fn extend_response(&mut self, text: &str) {
+        match self.history.last_mut() {
+            Some(message) if message.role == Role::Assistant => message.content.push_str(text),
+            _ => self.history.push(ChatMessage::assistant(text)),
+        }
     }
 
-    Ok(resp)
+    /// Replace the model's response at the end of the conversation,
+    /// e.g. with a repaired version of it.
+    fn replace_response(&mut self, text: &str) {
+        if let Some(message) = self.history.last_mut() {
+            if message.role == Role::Assistant {
+                message.content = text.to_string();
+            }
+        }
+    }
+}
+
+/// Hands the response on to another sink as it is generated, and
+/// keeps it, so that a response that was cancelled half-way can still
+/// be logged.
+struct StreamedText<'a> {
+    sink: &'a mut dyn TokenSink,
+    text: String,
+}
+
+impl TokenSink for StreamedText<'_> {
+    fn token(&mut self, token: &str) {
+        self.text.push_str(token);
+        self.sink.token(token);
+    }
+
+    fn restart(&mut self) {
+        self.text.clear();
+        self.sink.restart();
+    }
+}
+
+async fn converse<'a, T: DeserializeOwned>(details: &mut AiExecution<'a>) -> Result<T> {
+    details
+        .history
+        .extend(details.prompt.messages.iter().cloned());
+
+    let new_messages = details.prompt.messages.len();
+    details.fit_context(new_messages).await?;
+
+    let str_resp = details
+        .generate(false)
+        .await
+        .map(sanitize_json_response)?;
+
+    details.extend_response(&str_resp);
+
+    match serde_json::from_str(&str_resp) {
+        Ok(obj) => Ok(obj),
+        Err(e) => match details.recognize(&str_resp) {
+            // More text will not make the response valid, so there is
+            // no point in asking for it.
+            Some(Recognition::Diverged { position }) => Err(grammar_violation(&str_resp, position)),
+
+            // If the resp is not fully valid JSON, request more from
+            // the LLM. Backends that cannot continue a response fail
+            // it instead, so that it is retried from the start.
+            Some(Recognition::Prefix) if details.backend.can_continue() => {
+                continue_execution(details, str_resp).await
+            }
+            None if e.classify() == Category::Eof && details.backend.can_continue() => {
+                continue_execution(details, str_resp).await
+            }
+            _ => Err(GenerationError::MalformedJson(e).into()),
+        },
+    }
 }
 
-#[async_recursion(?Send)]
+/// Ask the LLM to finish a JSON response that it stopped generating
+/// in the middle of. Gives up after the prompt's continuation limit
+/// or total token budget is reached, and then tries to salvage the
+/// partial response instead. Stops early if the response leaves the
+/// grammar, which continuations are not always constrained by.
 async fn continue_execution<'a, T: DeserializeOwned>(
     details: &mut AiExecution<'a>,
-    resp_so_far: &mut String,
+    mut resp_so_far: String,
 ) -> Result<T> {
-    // Grammar state is retained here (as opposed to false
-    // normally) to let the model continue to generate JSON.
-    let input = create_input(
-        details.gen_key.to_string(),
-        details.prompt_so_far,
-        details.prompt.grammar.clone(),
-        details.prompt.max_tokens,
-        true,
-        details.prompt.creativity,
-    );
-
-    // TODO convert error to remove trait bound issue
-    let resp = details.client.sse_generate(input).await.unwrap();
-
-    details.prompt_so_far.push_str(&resp);
-    resp_so_far.push_str(&resp);
-
-    let resp: Value = match serde_json::from_str(&resp_so_far) {
-        Ok(obj) => obj,
-        Err(e) => match e.classify() {
-            Category::Eof | Category::Syntax => {
-                continue_execution(details, resp_so_far).await?
-            }
-            _ => {
-                return Err(e.into());
+    let max_continuations = details.prompt.max_continuations;
+    let max_total_tokens = details.prompt.max_total_tokens as usize;
+    let mut tokens_used = details.backend.count_tokens(&resp_so_far).await?;
+
+    for _ in 0..max_continuations {
+        if tokens_used >= max_total_tokens {
+            break;
+        }
+
+        let protected = details.prompt.messages.len() + 1;
+        details.fit_context(protected).await?;
+
+        // Grammar state is retained here (as opposed to false
+        // normally) to let the model continue to generate JSON.
+        let resp = details.generate(true).await?;
+        details.stats.continuations += 1;
+
+        details.extend_response(&resp);
+        tokens_used += details.backend.count_tokens(&resp).await?;
+        resp_so_far.push_str(&resp);
+
+        match details.recognize(&resp_so_far) {
+            Some(Recognition::Diverged { position }) => {
+                return Err(grammar_violation(&resp_so_far, position));
             }
-        },
-    };
+            Some(Recognition::Prefix) => continue,
+            Some(Recognition::Complete) => {
+                return serde_json::from_str(&resp_so_far)
+                    .map_err(|e| GenerationError::MalformedJson(e).into());
+            }
+            None => (),
+        }
+
+        // Without a grammar, only serde can tell whether the response
+        // is incomplete.
+        match serde_json::from_str::<Value>(&resp_so_far) {
+            Ok(obj) => {
+                return serde_json::from_value(obj)
+                    .map_err(|e| GenerationError::MalformedJson(e).into());
+            }
+            Err(e) => match e.classify() {
+                Category::Eof | Category::Syntax => continue,
+                _ => return Err(GenerationError::MalformedJson(e).into()),
+            },
+        }
+    }
 
-    let resp: T = serde_json::from_value(resp)?;
+    let repaired = repair_truncated_json(&resp_so_far);
+    details.replace_response(&repaired);
+    details.stats.repaired = true;
 
-    Ok(resp)
+    serde_json::from_str(&repaired).map_err(|e| GenerationError::MalformedJson(e).into())
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -129,95 +432,691 @@ pub enum AiCreativity {
     Creative,
 }
 
+/// How often, and how patiently, a failed generation is tried again.
+/// Only transient failures (see `GenerationError::is_transient`) are
+/// retried.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub backoff_factor: u32,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_delay: Duration::from_secs(1),
+            backoff_factor: 2,
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait after the given (1-based) failed attempt.
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.backoff_factor.saturating_pow(attempt.saturating_sub(1));
+        self.initial_delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<GenerationError>()
+        .map(|gen_err| gen_err.is_transient())
+        .unwrap_or(false)
+}
+
+const DEFAULT_MAX_CONTINUATIONS: u32 = 3;
+
+/// Enough for the first generation and every continuation to use all
+/// of their tokens.
+fn default_total_tokens(max_tokens: u64) -> u64 {
+    max_tokens * (1 + DEFAULT_MAX_CONTINUATIONS as u64)
+}
+
+/// A single turn in a conversation with the LLM: the messages to
+/// add to the conversation, and how the response should be
+/// generated.
 pub struct AiPrompt {
-    pub prompt: String,
+    pub messages: Vec<ChatMessage>,
     pub grammar: Option<String>,
+
+    /// The grammar as a JSON Schema, for backends that cannot use
+    /// GBNF.
+    pub json_schema: Option<String>,
     pub max_tokens: u64,
     pub creativity: AiCreativity,
+
+    /// Which task the prompt is for, so that it can have its own
+    /// sampler settings.
+    pub kind: Option<PromptKind>,
+
+    /// How many times the LLM may be asked to continue a response
+    /// that stopped in the middle of the JSON.
+    pub max_continuations: u32,
+
+    /// Tokens the response may use across the first generation and
+    /// all continuations.
+    pub max_total_tokens: u64,
 }
 
 impl AiPrompt {
     pub fn new(prompt: &str) -> AiPrompt {
         AiPrompt {
-            prompt: prompt.to_string(),
+            messages: vec![ChatMessage::user(prompt)],
             grammar: None,
+            json_schema: None,
             max_tokens: 150,
             creativity: AiCreativity::Normal,
+            kind: None,
+            max_continuations: DEFAULT_MAX_CONTINUATIONS,
+            max_total_tokens: default_total_tokens(150),
         }
     }
 
     pub fn new_with_grammar(prompt: &str, grammar: &str) -> AiPrompt {
         AiPrompt {
-            prompt: prompt.to_string(),
+            messages: vec![ChatMessage::user(prompt)],
             grammar: Some(grammar.to_string()),
+            json_schema: None,
             max_tokens: 150,
             creativity: AiCreativity::Normal,
+            kind: None,
+            max_continuations: DEFAULT_MAX_CONTINUATIONS,
+            max_total_tokens: default_total_tokens(150),
         }
     }
 
     pub fn new_with_grammar_and_size(prompt: &str, grammar: &str, tokens: u64) -> AiPrompt {
         AiPrompt {
-            prompt: prompt.to_string(),
+            messages: vec![ChatMessage::user(prompt)],
             grammar: Some(grammar.to_string()),
+            json_schema: None,
             max_tokens: tokens,
             creativity: AiCreativity::Normal,
+            kind: None,
+            max_continuations: DEFAULT_MAX_CONTINUATIONS,
+            max_total_tokens: default_total_tokens(tokens),
         }
     }
 
     pub fn creative_with_grammar(prompt: &str, grammar: &str) -> AiPrompt {
         AiPrompt {
-            prompt: prompt.to_string(),
+            messages: vec![ChatMessage::user(prompt)],
             grammar: Some(grammar.to_string()),
+            json_schema: None,
             max_tokens: 150,
             creativity: AiCreativity::Creative,
+            kind: None,
+            max_continuations: DEFAULT_MAX_CONTINUATIONS,
+            max_total_tokens: default_total_tokens(150),
         }
     }
 
     pub fn creative_with_grammar_and_size(prompt: &str, grammar: &str, tokens: u64) -> AiPrompt {
         AiPrompt {
-            prompt: prompt.to_string(),
+            messages: vec![ChatMessage::user(prompt)],
             grammar: Some(grammar.to_string()),
+            json_schema: None,
             max_tokens: tokens,
             creativity: AiCreativity::Creative,
+            kind: None,
+            max_continuations: DEFAULT_MAX_CONTINUATIONS,
+            max_total_tokens: default_total_tokens(tokens),
         }
     }
+
+    pub fn with_continuation_limits(
+        mut self,
+        max_continuations: u32,
+        max_total_tokens: u64,
+    ) -> AiPrompt {
+        self.max_continuations = max_continuations;
+        self.max_total_tokens = max_total_tokens;
+        self
+    }
+
+    pub fn with_json_schema(mut self, schema: &str) -> AiPrompt {
+        self.json_schema = Some(schema.to_string());
+        self
+    }
+
+    pub fn with_kind(mut self, kind: PromptKind) -> AiPrompt {
+        self.kind = Some(kind);
+        self
+    }
+
+    /// Put instructions for the model in a system message ahead of
+    /// the prompt.
+    pub fn with_instructions(mut self, instructions: &str) -> AiPrompt {
+        self.messages.insert(0, ChatMessage::system(instructions));
+        self
+    }
 }
 
 pub struct AiConversation {
+    /// Identifies the conversation in transcripts.
+    name: &'static str,
     gen_key: String,
-    prompt_so_far: Rc<RefCell<String>>,
-    client: Rc<KoboldClient>,
+    history: Mutex<Vec<ChatMessage>>,
+    settings: ConversationSettings,
+    backend: Arc<dyn LlmBackend>,
 }
 
 impl AiConversation {
-    pub fn new(client: Rc<KoboldClient>) -> AiConversation {
+    pub fn new(
+        name: &'static str,
+        backend: Arc<dyn LlmBackend>,
+        settings: ConversationSettings,
+    ) -> AiConversation {
         AiConversation {
-            prompt_so_far: Rc::new(RefCell::new(String::new())),
+            name,
+            history: Mutex::new(vec![]),
             gen_key: new_uuid_string(),
-            client,
+            settings,
+            backend,
         }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.prompt_so_far.borrow().is_empty()
+        self.history.lock().unwrap().is_empty()
     }
 
     pub fn reset(&self) {
-        let mut prompt_so_far = RefCell::borrow_mut(&self.prompt_so_far);
-        prompt_so_far.clear();
+        self.history.lock().unwrap().clear();
+    }
+
+    /// Run the prompt in this conversation, retrying according to
+    /// the conversation's retry policy.
+    pub async fn execute<T: DeserializeOwned>(
+        &self,
+        prompt: &AiPrompt,
+        cancel: &CancellationToken,
+    ) -> Result<T> {
+        self.execute_streaming(prompt, &mut |_: &str| (), cancel).await
     }
 
-    pub async fn execute<T: DeserializeOwned>(&self, prompt: &AiPrompt) -> Result<T> {
-        let mut prompt_so_far = RefCell::borrow_mut(&self.prompt_so_far);
-        let prompt_so_far = &mut *prompt_so_far;
+    /// Like `execute`, but the raw response is handed to the sink as
+    /// it is generated.
+    pub async fn execute_streaming<T: DeserializeOwned>(
+        &self,
+        prompt: &AiPrompt,
+        sink: &mut dyn TokenSink,
+        cancel: &CancellationToken,
+    ) -> Result<T> {
+        let span = tracing::info_span!("llm_call", kind = prompt.kind.map(<&str>::from));
+        let started = Instant::now();
+        let mut stats = GenerationStats::default();
+
+        let result = self
+            .execute_with_retries(prompt, sink, cancel, &mut stats)
+            .instrument(span.clone())
+            .await;
+
+        span.in_scope(|| stats.report(prompt.kind, started.elapsed(), &result));
+        result
+    }
+
+    async fn execute_with_retries<T: DeserializeOwned>(
+        &self,
+        prompt: &AiPrompt,
+        sink: &mut dyn TokenSink,
+        cancel: &CancellationToken,
+        stats: &mut GenerationStats,
+    ) -> Result<T> {
+        let retry = self.settings.retry;
+        let mut attempt = 1;
+
+        loop {
+            stats.attempts = attempt;
+
+            let result = self.execute_once(prompt, &mut *sink, cancel, &mut *stats).await;
+
+            if result.is_err() && cancel.is_cancelled() {
+                // The client stream is already closed. The server
+                // may still need to be told, but failing to do so
+                // should not hide that the generation was cancelled.
+                let _ = self.backend.abort(&self.gen_key).await;
+                return Err(GenerationError::Cancelled.into());
+            }
+
+            match result {
+                Err(err) if attempt < retry.max_attempts && is_transient(&err) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep(retry.delay(attempt)) => (),
+                        _ = cancel.cancelled() => return Err(GenerationError::Cancelled.into()),
+                    }
+
+                    attempt += 1;
+                    sink.restart();
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn execute_once<T: DeserializeOwned>(
+        &self,
+        prompt: &AiPrompt,
+        sink: &mut dyn TokenSink,
+        cancel: &CancellationToken,
+        stats: &mut GenerationStats,
+    ) -> Result<T> {
+        // The prompt runs on a copy of the conversation, which only
+        // replaces the conversation if it succeeds. A failed or
+        // cancelled attempt leaves the conversation as it was, so it
+        // can be retried without repeating the prompt. The lock is
+        // not held while generating, which keeps the future Send.
+        let mut history = self.history.lock().unwrap().clone();
+        let mut streamed = StreamedText {
+            sink,
+            text: String::new(),
+        };
 
         let mut details = AiExecution {
-            prompt_so_far,
-            client: &self.client,
+            history: &mut history,
+            backend: self.backend.as_ref(),
+            settings: &self.settings,
             gen_key: &self.gen_key,
-            prompt: &prompt
+            prompt: &prompt,
+            sink: &mut streamed,
+            stats,
+            recognizer: prompt_recognizer(prompt),
+            responses: vec![],
+        };
+
+        let mut result = tokio::select! {
+            result = converse(&mut details) => result,
+            _ = cancel.cancelled() => Err(GenerationError::Cancelled.into()),
+        };
+
+        let mut responses = details.responses;
+        if result.is_err() && cancel.is_cancelled() {
+            result = Err(GenerationError::Cancelled.into());
+
+            // The generation in progress was dropped, so what it had
+            // generated so far was only handed to the sink, after the
+            // generations before it.
+            let finished = responses.iter().map(String::len).sum();
+            match streamed.text.get(finished..) {
+                Some(partial) if !partial.is_empty() => responses.push(partial.to_string()),
+                _ => (),
+            }
+        }
+
+        self.log_transcript(prompt, responses, &history, &result);
+
+        if result.is_ok() {
+            *self.history.lock().unwrap() = history;
+        }
+
+        result
+    }
+
+    fn log_transcript<T>(
+        &self,
+        prompt: &AiPrompt,
+        responses: Vec<String>,
+        history: &[ChatMessage],
+        result: &Result<T>,
+    ) {
+        let Some(transcript) = &self.settings.transcript else {
+            return;
+        };
+
+        let grammar = prompt.grammar.as_deref();
+        let mut entry = TranscriptEntry::new(self.name, &prompt.messages, grammar);
+        entry.responses = responses;
+
+        // The response at the end of the conversation is the one the
+        // result was parsed from, after sanitizing and repairing.
+        match result {
+            Ok(_) => {
+                entry.result = history
+                    .last()
+                    .and_then(|message| serde_json::from_str(&message.content).ok())
+            }
+            Err(err) => {
+                entry.outcome = match err.downcast_ref::<GenerationError>() {
+                    Some(GenerationError::Cancelled) => Outcome::Cancelled,
+                    _ => Outcome::Failed,
+                };
+                entry.error = Some(err.to_string());
+            }
+        }
+
+        if let Err(err) = transcript.log(&entry) {
+            tracing::warn!(error = %err, "could not write transcript");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::transcript::read_session;
+    use async_trait::async_trait;
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug)]
+    struct Named {
+        name: String,
+    }
+
+    /// Streams the start of a response, then never finishes it.
+    struct Stalls;
+
+    #[async_trait]
+    impl LlmBackend for Stalls {
+        async fn generate(&self, _request: &GenerationRequest<'_>) -> Result<String> {
+            std::future::pending().await
+        }
+
+        async fn generate_streaming(
+            &self,
+            _request: &GenerationRequest<'_>,
+            sink: &mut dyn TokenSink,
+        ) -> Result<String> {
+            sink.token(r#"{"na"#);
+            std::future::pending().await
+        }
+    }
+
+    /// Fails every generation in a way that is not worth retrying.
+    struct Rejects;
+
+    #[async_trait]
+    impl LlmBackend for Rejects {
+        async fn generate(&self, _request: &GenerationRequest<'_>) -> Result<String> {
+            Err(GenerationError::GrammarUnsupported("rejected".to_string()).into())
+        }
+    }
+
+    /// Answers every prompt with the same response.
+    struct Answers(&'static str);
+
+    #[async_trait]
+    impl LlmBackend for Answers {
+        async fn generate(&self, _request: &GenerationRequest<'_>) -> Result<String> {
+            Ok(self.0.to_string())
+        }
+    }
+
+    /// Stops in the middle of the first response. Continuations and
+    /// retries finish it.
+    struct Truncates {
+        can_continue: bool,
+        continued: Mutex<Vec<bool>>,
+    }
+
+    impl Truncates {
+        fn new(can_continue: bool) -> Truncates {
+            Truncates {
+                can_continue,
+                continued: Mutex::new(vec![]),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl LlmBackend for Truncates {
+        async fn generate(&self, request: &GenerationRequest<'_>) -> Result<String> {
+            self.continued
+                .lock()
+                .unwrap()
+                .push(request.retain_grammar_state);
+
+            match (request.retain_grammar_state, request.attempt) {
+                (true, _) => Ok(r#"g"}"#.to_string()),
+                (false, 1) => Ok(r#"{"name": "mu"#.to_string()),
+                (false, _) => Ok(r#"{"name": "mug"}"#.to_string()),
+            }
+        }
+
+        fn can_continue(&self) -> bool {
+            self.can_continue
+        }
+    }
+
+    /// Answers every prompt with the same response, and keeps the
+    /// sampler seed of every request.
+    struct Seeds(Mutex<Vec<Option<u64>>>);
+
+    #[async_trait]
+    impl LlmBackend for Seeds {
+        async fn generate(&self, request: &GenerationRequest<'_>) -> Result<String> {
+            self.0.lock().unwrap().push(request.sampler.seed);
+            Ok(r#"{"name": "mug"}"#.to_string())
+        }
+    }
+
+    /// Answers every prompt with the same response, and keeps the
+    /// messages of every request.
+    struct Records(Mutex<Vec<Vec<ChatMessage>>>);
+
+    #[async_trait]
+    impl LlmBackend for Records {
+        async fn generate(&self, request: &GenerationRequest<'_>) -> Result<String> {
+            self.0.lock().unwrap().push(request.messages.to_vec());
+            Ok(r#"{"name": "mug"}"#.to_string())
+        }
+    }
+
+    /// A conversation whose prompts and responses fit in 350 tokens.
+    fn budgeted(backend: Arc<Records>, policy: TrimPolicy) -> AiConversation {
+        let settings = ConversationSettings {
+            context: ContextBudget {
+                max_tokens: 350 + prompt().max_tokens,
+                policy,
+            },
+            ..Default::default()
+        };
+
+        AiConversation::new("test", backend, settings)
+    }
+
+    /// A prompt of about 100 tokens.
+    fn long_prompt(n: usize) -> AiPrompt {
+        AiPrompt::new(&format!("{} {}", n, "word ".repeat(80)))
+    }
+
+    fn conversation(name: &str, backend: impl LlmBackend + 'static) -> AiConversation {
+        let root = std::env::temp_dir().join(format!(
+            "ai-game-transcript-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+
+        let settings = ConversationSettings {
+            transcript: Some(Arc::new(TranscriptLogger::create(root).unwrap())),
+            ..Default::default()
+        };
+
+        AiConversation::new("test", Arc::new(backend), settings)
+    }
+
+    fn transcript(convo: &AiConversation) -> Vec<TranscriptEntry> {
+        let logger = convo.settings.transcript.as_ref().unwrap();
+        let entries = read_session(logger.session_dir()).unwrap();
+        std::fs::remove_dir_all(logger.session_dir().parent().unwrap()).unwrap();
+        entries
+    }
+
+    fn prompt() -> AiPrompt {
+        AiPrompt::new("Name something.")
+    }
+
+    #[tokio::test]
+    async fn logs_successful_attempts() {
+        let convo = conversation("success", Answers(r#"{"name": "mug"}"#));
+        let cancel = CancellationToken::new();
+
+        let named: Named = convo.execute(&prompt(), &cancel).await.unwrap();
+        assert_eq!(named.name, "mug");
+
+        let entries = transcript(&convo);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].outcome, Outcome::Succeeded);
+        assert_eq!(
+            entries[0].result,
+            Some(serde_json::json!({ "name": "mug" }))
+        );
+    }
+
+    #[tokio::test]
+    async fn continues_truncated_responses() {
+        let backend = Arc::new(Truncates::new(true));
+        let convo = AiConversation::new("test", backend.clone(), Default::default());
+
+        let cancel = CancellationToken::new();
+        let named: Named = convo.execute(&prompt(), &cancel).await.unwrap();
+
+        assert_eq!(named.name, "mug");
+        assert_eq!(*backend.continued.lock().unwrap(), vec![false, true]);
+    }
+
+    #[tokio::test]
+    async fn retries_truncated_responses_if_the_backend_cannot_continue() {
+        let backend = Arc::new(Truncates::new(false));
+        let settings = ConversationSettings {
+            retry: RetryPolicy {
+                initial_delay: Duration::ZERO,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let convo = AiConversation::new("test", backend.clone(), settings);
+
+        let cancel = CancellationToken::new();
+        let named: Named = convo.execute(&prompt(), &cancel).await.unwrap();
+
+        assert_eq!(named.name, "mug");
+        assert_eq!(*backend.continued.lock().unwrap(), vec![false, false]);
+    }
+
+    #[tokio::test]
+    async fn the_same_world_seed_gives_the_same_sampler_seeds() {
+        let seeds = |world_seed: u64| async move {
+            let backend = Arc::new(Seeds(Mutex::new(vec![])));
+            let settings = ConversationSettings {
+                world_seed: Some(world_seed),
+                ..Default::default()
+            };
+            let convo = AiConversation::new("test", backend.clone(), settings);
+
+            let cancel = CancellationToken::new();
+            for prompt in ["Name something.", "Name something else."] {
+                let _: Named = convo
+                    .execute(&AiPrompt::new(prompt), &cancel)
+                    .await
+                    .unwrap();
+            }
+
+            let seeds = backend.0.lock().unwrap().clone();
+            seeds
         };
 
-        converse(&mut details).await
+        let first = seeds(42).await;
+        assert!(first.iter().all(Option::is_some));
+        assert_ne!(first[0], first[1]);
+        assert_eq!(first, seeds(42).await);
+        assert_ne!(first, seeds(43).await);
+    }
+
+    #[tokio::test]
+    async fn drops_the_oldest_turns_and_keeps_the_instructions() {
+        let backend = Arc::new(Records(Mutex::new(vec![])));
+        let convo = budgeted(backend.clone(), TrimPolicy::DropOldest);
+        let cancel = CancellationToken::new();
+
+        let first = long_prompt(0).with_instructions("Name things.");
+        let _: Named = convo.execute(&first, &cancel).await.unwrap();
+        for n in 1..6 {
+            let _: Named = convo.execute(&long_prompt(n), &cancel).await.unwrap();
+        }
+
+        let requests = backend.0.lock().unwrap();
+        let last = requests.last().unwrap();
+        assert_eq!(last[0], ChatMessage::system("Name things."));
+
+        // Only the most recent turns are kept, in order.
+        let turns: Vec<_> = last[1..]
+            .iter()
+            .filter(|msg| msg.role == Role::User)
+            .map(|msg| msg.content.split(' ').next().unwrap())
+            .collect();
+        assert_eq!(turns, vec!["3", "4", "5"]);
+        assert_eq!(
+            last.last().unwrap().content,
+            long_prompt(5).messages[0].content
+        );
+    }
+
+    #[tokio::test]
+    async fn a_prompt_that_does_not_fit_exceeds_the_budget() {
+        let backend = Arc::new(Records(Mutex::new(vec![])));
+        let convo = budgeted(backend.clone(), TrimPolicy::DropOldest);
+        let cancel = CancellationToken::new();
+
+        let _: Named = convo.execute(&long_prompt(0), &cancel).await.unwrap();
+
+        let too_long = AiPrompt::new(&"word ".repeat(400));
+        let err = convo
+            .execute::<Named>(&too_long, &cancel)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GenerationError>(),
+            Some(GenerationError::BudgetExceeded { budget: 500, .. })
+        ));
+
+        // The conversation is left as it was, and nothing was sent.
+        assert_eq!(backend.0.lock().unwrap().len(), 1);
+        let _: Named = convo.execute(&long_prompt(1), &cancel).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn logs_failed_attempts() {
+        let convo = conversation("failure", Rejects);
+        let cancel = CancellationToken::new();
+
+        let result = convo.execute::<Named>(&prompt(), &cancel).await;
+        assert!(result.is_err());
+
+        let entries = transcript(&convo);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].outcome, Outcome::Failed);
+        assert!(entries[0].error.as_deref().unwrap().contains("rejected"));
+    }
+
+    #[tokio::test]
+    async fn logs_cancelled_attempts_with_the_partial_response() {
+        let convo = conversation("cancelled", Stalls);
+        let cancel = CancellationToken::new();
+
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            canceller.cancel();
+        });
+
+        let err = convo
+            .execute::<Named>(&prompt(), &cancel)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GenerationError>(),
+            Some(GenerationError::Cancelled)
+        ));
+
+        let entries = transcript(&convo);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].outcome, Outcome::Cancelled);
+        assert_eq!(entries[0].responses, vec![r#"{"na"#.to_string()]);
+        assert!(convo.is_empty());
     }
 }
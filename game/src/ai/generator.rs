@@ -1,17 +1,23 @@
 use anyhow::{anyhow, Result};
 use itertools::Itertools;
 
-use super::convo::AiConversation;
+use super::backend::{LlmBackend, TokenSink};
+use super::convo::{AiConversation, ConversationSettings};
 use super::prompts::{execution_prompts, parsing_prompts, world_prompts};
 
-use crate::kobold_api::Client as KoboldClient;
 use crate::models::coherence::{CoherenceFailure, SceneFix};
 use crate::models::commands::{ParsedCommand, ParsedCommands, RawCommandExecution, VerbsResponse};
 use crate::models::world::raw::{
     ExitSeed, ItemDetails, ItemSeed, PersonDetails, PersonSeed, SceneSeed,
 };
 use crate::models::world::scenes::{Exit, Scene, SceneStub, Stage};
-use std::rc::Rc;
+use crate::telemetry::METRICS_TARGET;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use strum::IntoStaticStr;
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
 
 fn find_exit_position(exits: &[Exit], exit_to_find: &Exit) -> Result<usize> {
     let (pos, _) = exits
@@ -22,6 +28,51 @@ fn find_exit_position(exits: &[Exit], exit_to_find: &Exit) -> Result<usize> {
     Ok(pos)
 }
 
+/// The conversations the generator has with the LLM. Each can be
+/// routed to its own backend and model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, IntoStaticStr)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ConversationKind {
+    Parsing,
+    Execution,
+    WorldCreation,
+    PersonCreation,
+}
+
+/// A backend, and the settings for conversations with it.
+#[derive(Clone)]
+pub struct ConversationBackend {
+    pub backend: Arc<dyn LlmBackend>,
+    pub settings: ConversationSettings,
+}
+
+/// Which backend each conversation goes to. Conversations without a
+/// route of their own go to the default backend.
+#[derive(Clone)]
+pub struct BackendRoutes {
+    default: ConversationBackend,
+    routes: HashMap<ConversationKind, ConversationBackend>,
+}
+
+impl BackendRoutes {
+    pub fn new(default: ConversationBackend) -> BackendRoutes {
+        BackendRoutes {
+            default,
+            routes: HashMap::new(),
+        }
+    }
+
+    pub fn route(&mut self, kind: ConversationKind, backend: ConversationBackend) {
+        self.routes.insert(kind, backend);
+    }
+
+    fn conversation(&self, kind: ConversationKind) -> AiConversation {
+        let route = self.routes.get(&kind).unwrap_or(&self.default);
+        AiConversation::new(kind.into(), route.backend.clone(), route.settings.clone())
+    }
+}
+
 /// Intermediate level struct that is charged with creating 'raw'
 /// information via the LLM and doing basic coherence on it. Things
 /// like ID creation, data management, and advanced coherence are done
@@ -29,20 +80,39 @@ fn find_exit_position(exits: &[Exit], exit_to_find: &Exit) -> Result<usize> {
 pub struct AiGenerator {
     parsing_convo: AiConversation,
     world_creation_convo: AiConversation,
-    person_creation_convo: AiConversation,
     execution_convo: AiConversation,
+
+    /// People are each created in a conversation of their own, so
+    /// that they can be created concurrently.
+    routes: BackendRoutes,
+
+    /// Cancels the generations of the operation in progress.
+    cancellation: Mutex<CancellationToken>,
 }
 
 impl AiGenerator {
-    pub fn new(client: Rc<KoboldClient>) -> AiGenerator {
+    pub fn new(routes: &BackendRoutes) -> AiGenerator {
         AiGenerator {
-            parsing_convo: AiConversation::new(client.clone()),
-            world_creation_convo: AiConversation::new(client.clone()),
-            person_creation_convo: AiConversation::new(client.clone()),
-            execution_convo: AiConversation::new(client.clone()),
+            parsing_convo: routes.conversation(ConversationKind::Parsing),
+            world_creation_convo: routes.conversation(ConversationKind::WorldCreation),
+            execution_convo: routes.conversation(ConversationKind::Execution),
+            routes: routes.clone(),
+            cancellation: Mutex::new(CancellationToken::new()),
         }
     }
 
+    /// Start a new cancellable operation. Cancelling the returned
+    /// token stops every generation until the next call.
+    pub fn new_cancellation_token(&self) -> CancellationToken {
+        let token = CancellationToken::new();
+        *self.cancellation.lock().unwrap() = token.clone();
+        token
+    }
+
+    fn cancellation(&self) -> CancellationToken {
+        self.cancellation.lock().unwrap().clone()
+    }
+
     pub fn reset_commands(&self) {
         self.parsing_convo.reset();
         self.execution_convo.reset();
@@ -52,10 +122,12 @@ impl AiGenerator {
         self.world_creation_convo.reset();
     }
 
-    pub fn reset_person_creation(&self) {
-        self.person_creation_convo.reset();
+    /// A new conversation to create a person in.
+    pub fn person_creation_convo(&self) -> AiConversation {
+        self.routes.conversation(ConversationKind::PersonCreation)
     }
 
+    #[instrument(skip_all)]
     pub async fn parse(&self, cmd: &str) -> Result<ParsedCommands> {
         // If convo so far is empty, add the instruction header,
         // otherwise only append to existing convo.
@@ -64,7 +136,10 @@ impl AiGenerator {
             false => parsing_prompts::continuation_prompt(&cmd),
         };
 
-        let mut cmds: ParsedCommands = self.parsing_convo.execute(&prompt).await?;
+        let mut cmds: ParsedCommands = self
+            .parsing_convo
+            .execute(&prompt, &self.cancellation())
+            .await?;
         cmds.original = cmd.to_owned();
 
         let verbs = self.find_verbs(cmd).await?;
@@ -72,9 +147,10 @@ impl AiGenerator {
         Ok(cmds)
     }
 
+    #[instrument(skip_all)]
     async fn find_verbs(&self, cmd: &str) -> Result<Vec<String>> {
         let prompt = parsing_prompts::find_verbs_prompt(cmd);
-        let verbs: VerbsResponse = self.parsing_convo.execute(&prompt).await?;
+        let verbs: VerbsResponse = self.parsing_convo.execute(&prompt, &self.cancellation()).await?;
 
         // Basic coherence filtering to make sure the 'verb' is
         // actually in the text.
@@ -104,10 +180,12 @@ impl AiGenerator {
         Ok(())
     }
 
+    #[instrument(skip_all)]
     pub async fn execute_raw(
         &self,
         stage: &Stage,
         parsed_cmds: &ParsedCommands,
+        sink: &mut dyn TokenSink,
     ) -> Result<RawCommandExecution> {
         //TODO handle multiple commands in list
         if parsed_cmds.commands.is_empty() {
@@ -116,40 +194,54 @@ impl AiGenerator {
 
         let cmd = &parsed_cmds.commands[0];
         let prompt = execution_prompts::execution_prompt(&parsed_cmds.original, stage, &cmd);
-        let raw_exec: RawCommandExecution = self.execution_convo.execute(&prompt).await?;
+        let raw_exec: RawCommandExecution = self
+            .execution_convo
+            .execute_streaming(&prompt, sink, &self.cancellation())
+            .await?;
         Ok(raw_exec)
     }
 
+    #[instrument(skip_all)]
     pub async fn create_scene_seed(
         &self,
         scene_type: &str,
         fantasticalness: &str,
     ) -> Result<SceneSeed> {
         let prompt = world_prompts::scene_creation_prompt(scene_type, fantasticalness);
-        let scene: SceneSeed = self.world_creation_convo.execute(&prompt).await?;
+        let scene: SceneSeed = self
+            .world_creation_convo
+            .execute(&prompt, &self.cancellation())
+            .await?;
         Ok(scene)
     }
 
+    #[instrument(skip_all)]
     pub async fn create_scene_seed_from_stub(
         &self,
         stub: &SceneStub,
         connected_scene: &Scene,
     ) -> Result<SceneSeed> {
         let prompt = world_prompts::scene_from_stub_prompt(connected_scene, stub);
-        let scene: SceneSeed = self.world_creation_convo.execute(&prompt).await?;
+        let scene: SceneSeed = self
+            .world_creation_convo
+            .execute(&prompt, &self.cancellation())
+            .await?;
         Ok(scene)
     }
 
+    #[instrument(skip_all)]
     pub async fn create_person_details(
         &self,
+        convo: &AiConversation,
         scene: &SceneSeed,
         seed: &PersonSeed,
     ) -> Result<PersonDetails> {
         let prompt = world_prompts::person_creation_prompt(scene, seed);
-        let person: PersonDetails = self.person_creation_convo.execute(&prompt).await?;
+        let person: PersonDetails = convo.execute(&prompt, &self.cancellation()).await?;
         Ok(person)
     }
 
+    /// Placeholder details, until there is a prompt for items.
     pub async fn create_item_details(
         &self,
         scene: &SceneSeed,
@@ -164,6 +256,7 @@ impl AiGenerator {
         Ok(item_details)
     }
 
+    #[instrument(skip_all)]
     pub(super) async fn fix_scene<'a>(
         &self,
         scene: &Scene,
@@ -176,10 +269,13 @@ impl AiGenerator {
         for failure in failures {
             let fix = match failure {
                 CoherenceFailure::InvalidExitName(original_exit) => {
-                    println!("invalid exit name: {}", original_exit.name);
+                    tracing::debug!(exit = %original_exit.name, "invalid exit name");
                     let prompt = world_prompts::fix_exit_prompt(scene, original_exit);
-                    let fixed: ExitSeed = self.world_creation_convo.execute(&prompt).await?;
-                    println!("fixed with: {:?}", fixed);
+                    let fixed: ExitSeed = self
+                        .world_creation_convo
+                        .execute(&prompt, &self.cancellation())
+                        .await?;
+                    tracing::debug!(fixed = ?fixed, "fixed exit name");
                     let position = find_exit_position(&scene.exits, original_exit)?;
 
                     SceneFix::FixedExit {
@@ -188,7 +284,7 @@ impl AiGenerator {
                     }
                 }
                 CoherenceFailure::DuplicateExits(bad_exits) => {
-                    println!("found duplicate exits {:?}", bad_exits);
+                    tracing::debug!(exits = ?bad_exits, "found duplicate exits");
                     let position = find_exit_position(&scene.exits, bad_exits[0])?;
                     SceneFix::DeleteExit(position)
                 }
@@ -197,6 +293,13 @@ impl AiGenerator {
             fixes.push(fix);
         }
 
+        tracing::info!(
+            target: METRICS_TARGET,
+            metric = "coherence_fixes",
+            scene = %scene.name,
+            fixes = fixes.len(),
+        );
+
         Ok(fixes)
     }
 
@@ -204,3 +307,70 @@ impl AiGenerator {
     //     //
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::backend::replay::ReplayBackend;
+    use crate::models::world::items::Category;
+    use crate::models::world::people::Sex;
+
+    /// Responses recorded for the prompts below, so that the
+    /// generator can be tested without a model.
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/replay.json");
+
+    fn replay_generator() -> AiGenerator {
+        let backend = ReplayBackend::from_file(FIXTURES).unwrap();
+        let routes = BackendRoutes::new(ConversationBackend {
+            backend: Arc::new(backend),
+            settings: ConversationSettings::default(),
+        });
+
+        AiGenerator::new(&routes)
+    }
+
+    #[tokio::test]
+    async fn creates_a_scene_seed_from_fixtures() {
+        let generator = replay_generator();
+        let scene = generator.create_scene_seed("tavern", "low").await.unwrap();
+
+        assert_eq!(scene.name, "The Rusty Tankard");
+        assert_eq!(scene.people.len(), 1);
+        assert!(matches!(scene.items[0].category, Category::Other));
+        assert_eq!(scene.exits[0].direction, "north");
+    }
+
+    #[tokio::test]
+    async fn creates_person_details_from_fixtures() {
+        let generator = replay_generator();
+        let scene = generator.create_scene_seed("tavern", "low").await.unwrap();
+
+        let convo = generator.person_creation_convo();
+        let person = generator
+            .create_person_details(&convo, &scene, &scene.people[0])
+            .await
+            .unwrap();
+
+        assert!(matches!(person.sex, Sex::Female));
+        assert_eq!(person.age, 42);
+    }
+
+    #[tokio::test]
+    async fn parses_commands_from_fixtures() {
+        let generator = replay_generator();
+        let cmds = generator.parse("take the mug").await.unwrap();
+
+        assert_eq!(cmds.original, "take the mug");
+        assert_eq!(cmds.count, 1);
+        assert_eq!(cmds.commands[0].verb, "take");
+        assert_eq!(cmds.commands[0].target, "mug");
+    }
+
+    #[tokio::test]
+    async fn unrecorded_prompts_fail() {
+        let generator = replay_generator();
+        let result = generator.create_scene_seed("castle", "high").await;
+
+        assert!(result.is_err());
+    }
+}
@@ -1,20 +1,24 @@
 use crate::db::Database;
-use crate::kobold_api::Client as KoboldClient;
 use crate::models::commands::{
     AiCommand, ParsedCommands, ExecutionConversionResult, RawCommandExecution,
 };
-use crate::models::world::items::{Category, Item, Rarity};
-use crate::models::world::people::{Gender, Person, Sex};
+use crate::models::world::items::Item;
+use crate::models::world::people::Person;
 use crate::models::world::raw::{ItemSeed, PersonSeed, SceneSeed};
 use crate::models::world::scenes::{Exit, Scene, SceneStub, Stage};
 use crate::models::{new_uuid_string, Content, ContentContainer, ContentRelation};
 use crate::commands::converter as command_converter;
 use anyhow::{bail, Result};
+use futures::future::Either;
+use futures::{stream, StreamExt, TryStreamExt};
 use itertools::Itertools;
-use std::rc::Rc;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
 
+use super::backend::TokenSink;
 use super::coherence::AiCoherence;
-use super::generator::AiGenerator;
+use super::convo::AiConversation;
+use super::generator::{AiGenerator, BackendRoutes};
 
 /// Highest-level AI/LLM construct, which returns fully converted game
 /// objects to us. Basically, call the mid-level `client` to create
@@ -22,30 +26,50 @@ use super::generator::AiGenerator;
 /// entities from their seeds. Then, stick a DB ID on them and put
 /// them in the database(?).
 pub struct AiLogic {
-    generator: Rc<AiGenerator>,
+    generator: Arc<AiGenerator>,
     coherence: AiCoherence,
-    db: Rc<Database>,
+    db: Arc<Database>,
+
+    /// How many people and items of a scene are generated at once.
+    parallel_generations: usize,
 }
 
 impl AiLogic {
-    pub fn new(api_client: Rc<KoboldClient>, db: &Rc<Database>) -> AiLogic {
-        let generator = Rc::new(AiGenerator::new(api_client));
+    pub fn new(routes: &BackendRoutes, db: &Arc<Database>) -> AiLogic {
+        let generator = Arc::new(AiGenerator::new(routes));
         let coherence = AiCoherence::new(generator.clone());
 
         AiLogic {
             generator,
             coherence,
             db: db.clone(),
+            parallel_generations: 1,
         }
     }
 
+    /// Generate up to this many people and items of a scene at once.
+    /// Only worth raising if the backend has as many parallel slots.
+    pub fn with_parallel_generations(mut self, limit: usize) -> AiLogic {
+        self.parallel_generations = limit.max(1);
+        self
+    }
+
+    /// Start a new cancellable operation, like executing a command.
+    /// Cancelling the token stops all of its generations.
+    pub fn new_cancellation_token(&self) -> CancellationToken {
+        self.generator.new_cancellation_token()
+    }
+
+    /// Parse and execute a command. The raw response of the execution
+    /// is handed to the sink as it is generated.
     pub async fn execute(
         &self,
         stage: &Stage,
         cmd: &str,
+        sink: &mut dyn TokenSink,
     ) -> Result<(ParsedCommands, RawCommandExecution)> {
         let parsed_cmd = self.generator.parse(cmd).await?;
-        let execution = self.execute_parsed(stage, &parsed_cmd).await?;
+        let execution = self.execute_parsed(stage, &parsed_cmd, sink).await?;
         Ok((parsed_cmd, execution))
     }
 
@@ -53,42 +77,31 @@ impl AiLogic {
         &self,
         stage: &Stage,
         parsed_cmd: &ParsedCommands,
+        sink: &mut dyn TokenSink,
     ) -> Result<RawCommandExecution> {
-        let raw_exec: RawCommandExecution = self.generator.execute_raw(stage, parsed_cmd).await?;
+        let raw_exec: RawCommandExecution =
+            self.generator.execute_raw(stage, parsed_cmd, sink).await?;
         self.generator.reset_commands();
         Ok(raw_exec)
     }
 
-    pub async fn create_person(&self, scene: &SceneSeed, seed: &PersonSeed) -> Result<Person> {
-        self.generator.reset_person_creation();
-        let details = self.generator.create_person_details(scene, seed).await?;
-
-        let gender = match details.gender.to_lowercase().as_ref() {
-            "male" | "man" | "boy" | "transmasc" => Gender::Male,
-            "female" | "woman" | "girl" | "transfem" => Gender::Female,
-            "nonbinary" => Gender::NonBinary,
-            // fall back to using sex
-            _ => match details.sex.to_lowercase().as_ref() {
-                "male" | "man" | "boy" | "transmasc" => Gender::Male,
-                "female" | "woman" | "girl" | "transfem" => Gender::Female,
-                _ => Gender::NonBinary, // TODO 1/3 chance!
-            },
-        };
-
-        let sex = match details.sex.to_lowercase().as_ref() {
-            "male" | "man" | "boy" | "transfem" => Sex::Male,
-            "female" | "woman" | "girl" | "transmasc" => Sex::Female,
-            _ => match gender {
-                Gender::Male => Sex::Male,
-                Gender::Female => Sex::Male,
-                _ => Sex::Male, // TODO 50/50 chance!
-            },
-        };
-
-        self.generator.reset_person_creation();
+    /// Create a person in a conversation of its own. The key is given
+    /// rather than created here, so that people created concurrently
+    /// get the same keys no matter which finishes first.
+    pub async fn create_person(
+        &self,
+        convo: &AiConversation,
+        key: String,
+        scene: &SceneSeed,
+        seed: &PersonSeed,
+    ) -> Result<Person> {
+        let details = self
+            .generator
+            .create_person_details(convo, scene, seed)
+            .await?;
 
         Ok(Person {
-            _key: Some(new_uuid_string()),
+            _key: Some(key),
             name: seed.name.to_string(),
             description: details.description,
             age: details.age,
@@ -96,26 +109,27 @@ impl AiLogic {
             current_activity: details.current_activity,
             occupation: seed.occupation.to_string(),
             race: seed.race.clone(),
-            sex,
+            sex: details.sex,
+            gender: details.gender,
             ..Default::default()
         })
     }
 
-    pub async fn create_item(&self, scene: &SceneSeed, seed: &ItemSeed) -> Result<Item> {
+    pub async fn create_item(
+        &self,
+        key: String,
+        scene: &SceneSeed,
+        seed: &ItemSeed,
+    ) -> Result<Item> {
         let details = self.generator.create_item_details(scene, seed).await?;
 
-        // TODO these have to be sent to the AI
-        let category = Category::Other;
-        let rarity = Rarity::Common;
-
         Ok(Item {
-            _key: Some(new_uuid_string()),
+            _key: Some(key),
             name: seed.name.to_string(),
             description: details.description,
             attributes: details.attributes,
             secret_attributes: details.secret_attributes,
-            category,
-            rarity,
+            category: seed.category.clone(),
             ..Default::default()
         })
     }
@@ -193,19 +207,44 @@ impl AiLogic {
     async fn fill_in_scene(&self, mut scene_seed: SceneSeed) -> Result<ContentContainer> {
         let mut content_in_scene = vec![];
 
-        // People in scene
-        let mut people = vec![];
-        for person_seed in scene_seed.people.as_slice() {
-            let person = self.create_person(&scene_seed, person_seed).await?;
-            people.push(ContentRelation::person(person));
-        }
+        // Keys and conversations are handed out up front, in seed
+        // order, so that a seeded world gets the same keys however the
+        // generations below interleave.
+        let person_jobs: Vec<_> = scene_seed
+            .people
+            .iter()
+            .map(|seed| (self.generator.person_creation_convo(), new_uuid_string(), seed))
+            .collect();
 
-        // Items in scene
-        let mut items = vec![];
-        for item_seed in scene_seed.items.as_slice() {
-            let item = self.create_item(&scene_seed, item_seed).await?;
-            items.push(ContentRelation::item(item));
-        }
+        let item_jobs: Vec<_> = scene_seed
+            .items
+            .iter()
+            .map(|seed| (new_uuid_string(), seed))
+            .collect();
+
+        let scene = &scene_seed;
+
+        let people = stream::iter(person_jobs).map(|(convo, key, seed)| {
+            Either::Left(async move {
+                let person = self.create_person(&convo, key, scene, seed).await?;
+                Ok::<_, anyhow::Error>(ContentRelation::person(person))
+            })
+        });
+
+        let items = stream::iter(item_jobs).map(|(key, seed)| {
+            Either::Right(async move {
+                let item = self.create_item(key, scene, seed).await?;
+                Ok::<_, anyhow::Error>(ContentRelation::item(item))
+            })
+        });
+
+        // People and items share the limit, so that items do not wait
+        // for the last person. They come out in seed order.
+        let mut people_and_items: Vec<_> = people
+            .chain(items)
+            .buffered(self.parallel_generations)
+            .try_collect()
+            .await?;
 
         // TODO items on people, which will require 'recursive' ContentContainers.
 
@@ -231,8 +270,7 @@ impl AiLogic {
             ..Default::default()
         };
 
-        content_in_scene.append(&mut people);
-        content_in_scene.append(&mut items);
+        content_in_scene.append(&mut people_and_items);
         content_in_scene.append(&mut stubs);
 
         Ok(ContentContainer {
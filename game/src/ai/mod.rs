@@ -1,6 +1,12 @@
+pub mod backend;
 pub(self) mod coherence;
-pub mod gbnf;
+pub mod context;
 pub mod convo;
 pub mod generator;
 pub mod prompts;
 pub mod logic;
+pub mod repair;
+pub mod sampling;
+pub mod stream;
+pub mod template;
+pub mod transcript;
@@ -1,5 +1,8 @@
 use crate::ai::convo::AiPrompt;
-use crate::models::commands::{CommandEvent, EventConversionFailure, ParsedCommand};
+use crate::ai::sampling::PromptKind;
+use crate::models::commands::{
+    CommandEvent, EventConversionFailure, ParsedCommand, RawCommandExecution,
+};
 use crate::models::world::items::Item;
 use crate::models::world::people::Person;
 use crate::models::world::scenes::{Exit, Prop, Scene, Stage};
@@ -72,21 +75,7 @@ impl<'a> From<&'a Exit> for ExitTableRow<'a> {
     }
 }
 
-const COMMAND_EXECUTION_BNF: &'static str = r#"
-root ::= CommandExecution
-CommandEvent ::= "{"   ws   "\"eventName\":"   ws   string   ","   ws   "\"appliesTo\":"   ws   string   ","   ws   "\"parameter\":"   ws   string   "}"
-CommandExecution ::= "{"   ws   "\"valid\":"   ws   boolean   ","   ws   "\"reason\":"   ws   string   ","   ws   "\"narration\":"   ws   string   ","   ws   "\"event\":"   ws   CommandEvent   "}"
-CommandExecutionlist ::= "[]" | "["   ws   CommandExecution   (","   ws   CommandExecution)*   "]"
-string ::= "\""   ([^"]*)   "\""
-boolean ::= "true" | "false"
-ws ::= [ \t\n]*
-number ::= [0-9]+   "."?   [0-9]*
-stringlist ::= "["   ws   "]" | "["   ws   string   (","   ws   string)*   ws   "]"
-numberlist ::= "["   ws   "]" | "["   ws   string   (","   ws   number)*   ws   "]"
-"#;
-
 const COMMAND_EXECUTION_PROMPT: &'static str = r#"
-[INST]
 You are running a text-based adventure game. You have been given a command to execute. Your response must be in JSON.
 
 You can only execute the command if it is valid. A command is invalid if:
@@ -113,7 +102,7 @@ The `events` field must be filled with entries if the command is valid. It is a
  - `appliesTo`: The player, item, NPC, or other entity in the scene.
    - The event applies only to one target.
    - The `appliesTo` field should be the `key` of the target. If no key was provided, use the target's name instead. The `key` is usualy a UUID.
- - `parameter`: Optional parameter with a string value that will be parsed. Parameters allowed depend on the type of event, and are detailed below.
+ - `parameter`: Optional parameter of the event. Its type and the values allowed depend on the type of event, and are detailed below.
 
 The following events can be generated:
  - `change_scene`: The player's current scene is changed.
@@ -160,7 +149,6 @@ The original command is the raw text entered by the player.
  - Target: `{TARGET}`
  - Location: `{LOCATION}`
  - Using: `{USING}`
-[/INST]
 "#;
 
 pub const FIX_PROMPT: &'static str = r#"
@@ -255,7 +243,9 @@ pub fn execution_prompt(original_cmd: &str, stage: &Stage, cmd: &ParsedCommand)
         .replacen("{LOCATION}", &cmd.location, 1)
         .replacen("{USING}", &cmd.using, 1);
 
-    AiPrompt::new_with_grammar_and_size(&prompt, COMMAND_EXECUTION_BNF, 512)
+    AiPrompt::new_with_grammar_and_size(&prompt, RawCommandExecution::to_grammar(), 512)
+        .with_json_schema(RawCommandExecution::to_json_schema())
+        .with_kind(PromptKind::Execution)
 }
 
 pub fn fix_prompt(scene: &Scene, failures: &EventConversionFailure) -> AiPrompt {
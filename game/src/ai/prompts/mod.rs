@@ -1,3 +1,4 @@
 pub mod execution_prompts;
 pub mod parsing_prompts;
+pub mod summary_prompts;
 pub mod world_prompts;
@@ -1,10 +1,9 @@
 use crate::{
-    ai::convo::AiPrompt,
+    ai::{convo::AiPrompt, sampling::PromptKind},
     models::commands::{ParsedCommands, VerbsResponse},
 };
 
 pub const INTRO_PROMPT: &'static str = r#"
-[INST]
 You are running a text-based adventure game, and the player is providing you commands as input.
  - The commands must be parsed into structured data for command execution.
  - Every message provided after these instructions that starts with `Player Input` is considered Player Input.
@@ -54,13 +53,11 @@ Final instructions:
  - Make sure the `target` field makes sense.
  - Make sure all commands that are coherent and make sense remain in the list.
  - Make sure commands that are not coherent or don't make sense are removed from the list.
-
-Player Input: `{}`
-[/INST]
 "#;
 
+pub const PLAYER_INPUT_PROMPT: &'static str = "Player Input: `{}`";
+
 pub const COHERENCE_PROMPT: &'static str = r#"
-[INST]
 Check the generated commands for coherence according to these instructions. Your response must be in JSON.
  - If the `verb` field does not actually contain a verb, remove it from the list.
  - The action in the `verb` field must be present in the original Player Input. If not, remove
@@ -69,38 +66,34 @@ Check the generated commands for coherence according to these instructions. Your
  - Make sure the `target` field makes sense.
  - Make sure all commands that are coherent and make sense remain in the list.
  - Make sure commands that are not coherent or don't make sense are removed from the list.
-[/INST]
 "#;
 
 pub const FIND_VERBS_PROMPT: &'static str = "
-[INST]
-
 Extract the verbs from from the text below, labeled `Text`. This text is a command entered by the user, playing a text-based aventure game. Return the verbs as a JSON array.
 
 Text: `{}`
-[/INST]";
+";
 
 pub fn intro_prompt(cmd: &str) -> AiPrompt {
-    let prompt = INTRO_PROMPT.replace("{}", cmd);
-    AiPrompt::new_with_grammar(&prompt, ParsedCommands::to_grammar())
+    continuation_prompt(cmd).with_instructions(INTRO_PROMPT)
 }
 
 pub fn continuation_prompt(cmd: &str) -> AiPrompt {
-    let mut prompt = String::new();
-
-    prompt.push_str("[INST]");
-    prompt.push_str(&format!("Player Input: `{}`", cmd));
-
-    prompt.push_str("[/INST]");
-
+    let prompt = PLAYER_INPUT_PROMPT.replace("{}", cmd);
     AiPrompt::new_with_grammar(&prompt, ParsedCommands::to_grammar())
+        .with_json_schema(ParsedCommands::to_json_schema())
+        .with_kind(PromptKind::Parsing)
 }
 
 pub fn coherence_prompt() -> AiPrompt {
     AiPrompt::new_with_grammar(COHERENCE_PROMPT, ParsedCommands::to_grammar())
+        .with_json_schema(ParsedCommands::to_json_schema())
+        .with_kind(PromptKind::Parsing)
 }
 
 pub fn find_verbs_prompt(cmd: &str) -> AiPrompt {
     let prompt = FIND_VERBS_PROMPT.replace("{}", cmd);
     AiPrompt::new_with_grammar(&prompt, VerbsResponse::to_grammar())
+        .with_json_schema(VerbsResponse::to_json_schema())
+        .with_kind(PromptKind::Parsing)
 }
@@ -0,0 +1,16 @@
+use crate::ai::convo::{AiCreativity, AiPrompt};
+
+const SUMMARY_PROMPT: &'static str = r#"
+Summarize the conversation below in a few sentences. It is part of a text-based adventure game. Keep the details needed to continue the conversation: player commands, their results, names, and keys. Respond with the summary only.
+
+## Conversation
+
+{TRANSCRIPT}
+"#;
+
+pub fn summary_prompt(transcript: &str) -> AiPrompt {
+    let mut prompt = AiPrompt::new(&SUMMARY_PROMPT.replacen("{TRANSCRIPT}", transcript, 1));
+    prompt.max_tokens = 256;
+    prompt.creativity = AiCreativity::Predictable;
+    prompt
+}
@@ -1,57 +1,11 @@
 use crate::{
-    ai::convo::AiPrompt,
+    ai::{convo::AiPrompt, sampling::PromptKind},
     models::world::{
-        raw::{PersonSeed, SceneSeed},
+        raw::{ExitSeed, PersonDetails, PersonSeed, SceneSeed},
         scenes::{Exit, Scene, SceneStub},
     },
 };
 
-const SCENE_BNF: &'static str = r#"
-root ::= Scene
-Prop ::= "{"   ws   "\"name\":"   ws   string   ","   ws   "\"description\":"   ws   string   ","   ws   "\"features\":"   ws   stringlist   ","   ws   "\"possible_interactions\":"   ws   stringlist   "}"
-Proplist ::= "[]" | "["   ws   Prop   (","   ws   Prop)*   "]"
-Exit ::= "{"   ws   "\"name\":"   ws   string   ","   ws   "\"direction\":"   ws   string   ","   "\"region\":"   ws   string   "}"
-Exitlist ::= "[]" | "["   ws   Exit   (","   ws   Exit)*   "]"
-Item ::= "{"   ws   "\"name\":"   ws   string   ","   ws   "\"category\":"   ws   string   ","   ws   "\"rarity\":"   ws   string   "}"
-Itemlist ::= "[]" | "["   ws   Item   (","   ws   Item)*   "]"
-Person ::= "{"   ws   "\"name\":"   ws   string   ","   ws   "\"occupation\":"   ws   string   ","   ws   "\"race\":"   ws   string   "}"
-Personlist ::= "[]" | "["   ws   Person   (","   ws   Person)*   "]"
-Scene ::= "{"   ws "\"name\":"   ws   string   ","   ws   "\"region\":"   ws   string   ","   ws   "\"description\":"   ws   string   ","   ws   "\"people\":"   ws   Personlist   ","   ws   "\"items\":"   ws   Itemlist   ","   ws   "\"props\":"   ws   Proplist   "," "\"exits\":"    ws  Exitlist   "}"
-Scenelist ::= "[]" | "["   ws   Scene   (","   ws   Scene)*   "]"
-string ::= "\""   ([^"]*)   "\""
-boolean ::= "true" | "false"
-ws ::= [ \t\n]*
-number ::= [0-9]+   "."?   [0-9]*
-stringlist ::= "["   ws   "]" | "["   ws   string   (","   ws   string)*   ws   "]"
-numberlist ::= "["   ws   "]" | "["   ws   string   (","   ws   number)*   ws   "]"
-"#;
-
-const EXIT_SEED_BNF: &'static str = r#"
-root ::= ExitSeed
-ExitSeed ::= "{"   ws   "\"name\":"   ws   string   ","   ws   "\"direction\":"   ws   string   ","   ws   "\"region\":"   ws   string   "}"
-ExitSeedlist ::= "[]" | "["   ws   ExitSeed   (","   ws   ExitSeed)*   "]"
-string ::= "\""   ([^"]*)   "\""
-boolean ::= "true" | "false"
-ws ::= [ \t\n]*
-number ::= [0-9]+   "."?   [0-9]*
-stringlist ::= "["   ws   "]" | "["   ws   string   (","   ws   string)*   ws   "]"
-numberlist ::= "["   ws   "]" | "["   ws   string   (","   ws   number)*   ws   "]"
-"#;
-
-const PERSON_DETAILS_BNF: &'static str = r#"
-root ::= PersonDetails
-Item ::= "{"   ws   "\"name\":"   ws   string   ","   ws   "\"category\":"   ws   string   ","   ws   "\"rarity\":"   ws   string   "}"
-Itemlist ::= "[]" | "["   ws   Item   (","   ws   Item)*   "]"
-PersonDetails ::= "{"   ws   "\"description\":"   ws   string   ","   ws   "\"sex\":"   ws   string   ","   ws   "\"gender\":"   ws   string   ","   ws   "\"age\":"   ws   number   ","   ws   "\"residence\":"   ws   string   ","   ws   "\"items\":"   ws   Itemlist   ","   ws   "\"currentActivity\":"   ws   string   "}"
-PersonDetailslist ::= "[]" | "["   ws   PersonDetails   (","   ws   PersonDetails)*   "]"
-string ::= "\""   ([^"]*)   "\""
-boolean ::= "true" | "false"
-ws ::= [ \t\n]*
-number ::= [0-9]+   "."?   [0-9]*
-stringlist ::= "["   ws   "]" | "["   ws   string   (","   ws   string)*   ws   "]"
-numberlist ::= "["   ws   "]" | "["   ws   string   (","   ws   number)*   ws   "]"
-"#;
-
 const SCENE_INSTRUCTIONS: &'static str = r#"
 You are running a text-based adventure game. You must design a scene for the text-based adventure game that the user is playing. Your response must be in JSON.
 
@@ -93,19 +47,12 @@ Generate this data as a structured response.
 "#;
 
 const SCENE_CREATION_PROMPT: &'static str = r#"
-[INST]
-{SCENE_INSTRUCTIONS}
-
 The requested type of scene is: `{}`
 
 The requested amount of fantasticalness is: `{}`
-[/INST]
 "#;
 
 const SCENE_FROM_STUB_PROMPT: &'static str = r#"
-[INST]
-{SCENE_INSTRUCTIONS}
-
 ## Creation of THIS scene
 
 Create the scene and determine its fantasticalness (`low`, `medium`, or `high`) from the provided name and region.
@@ -137,11 +84,9 @@ Basic Connected Scene Information:
 ### Connected Scene Description
 
 {CONNECTED_SCENE_DESCRIPTION}
-[/INST]
 "#;
 
 const PERSON_CREATION_PROMPT: &'static str = r#"
-[INST]
 You are running a text-based adventure game. Your response must be in JSON.
 
 Fill in the details of the person below. This person is a character in a text-based adventure game. Use the person's basic information (name, race, occupation), along with information about the scene, to fill in details about this character. The character is in this scene. The following information needs to be generated:
@@ -150,7 +95,7 @@ Fill in the details of the person below. This person is a character in a text-ba
  - `sex`: The physical sex of the character. This must always be `male` or `female`.
  - `gender`: The self-identified gender of the character.
   - This is usually the same value as `sex`, but not always, as characters are, very rarely, trans.
-  - Valid values for `gender` are `male`, `female`, and `nonbinary`.
+  - Valid values for `gender` are `male`, `female`, and `non_binary`.
  - `description`: A long, detailed physical description of the character.
   - What they look like, the color of their hair, skin, eyes.
   - What clothes they are wearing.
@@ -173,7 +118,6 @@ Fill in the details of the person below. This person is a character in a text-ba
 ## Scene Information
 
 {SCENE_INFO}
-[/INST]
 "#;
 
 const SCENE_INFO_FOR_PERSON: &'static str = r#"
@@ -232,12 +176,14 @@ fn scene_info_for_person(scene: &SceneSeed) -> String {
 pub fn scene_creation_prompt(scene_type: &str, fantasticalness: &str) -> AiPrompt {
     AiPrompt::creative_with_grammar_and_size(
         &SCENE_CREATION_PROMPT
-            .replacen("{SCENE_INSTRUCTIONS}", SCENE_INSTRUCTIONS, 1)
             .replacen("{}", scene_type, 1)
             .replacen("{}", fantasticalness, 1),
-        SCENE_BNF,
+        SceneSeed::to_grammar(),
         1024,
     )
+    .with_json_schema(SceneSeed::to_json_schema())
+    .with_instructions(SCENE_INSTRUCTIONS)
+    .with_kind(PromptKind::SceneCreation)
 }
 
 pub fn fix_exit_prompt(scene: &Scene, invalid_exit: &Exit) -> AiPrompt {
@@ -255,9 +201,11 @@ pub fn fix_exit_prompt(scene: &Scene, invalid_exit: &Exit) -> AiPrompt {
             .replacen("{SCENE_NAME}", &scene.name, 1)
             .replacen("{SCENE_DESCRIPTION}", &scene.description, 1)
             .replacen("{OTHER_DIRECTIONS}", &other_directions, 1),
-        EXIT_SEED_BNF,
+        ExitSeed::to_grammar(),
         1024,
     )
+    .with_json_schema(ExitSeed::to_json_schema())
+    .with_kind(PromptKind::ExitFixing)
 }
 
 pub fn scene_from_stub_prompt(connected_scene: &Scene, stub: &SceneStub) -> AiPrompt {
@@ -272,7 +220,6 @@ pub fn scene_from_stub_prompt(connected_scene: &Scene, stub: &SceneStub) -> AiPr
 
     AiPrompt::creative_with_grammar_and_size(
         &SCENE_FROM_STUB_PROMPT
-            .replacen("{SCENE_INSTRUCTIONS}", SCENE_INSTRUCTIONS, 1)
             .replacen("{CONNECTED_SCENE_NAME}", &connected_scene.name, 1)
             .replacen("{CONNECTED_SCENE_REGION}", &connected_scene.region, 1)
             .replacen("{CONNECTED_SCENE_DIRECTION}", &connected_direction, 1)
@@ -285,9 +232,12 @@ pub fn scene_from_stub_prompt(connected_scene: &Scene, stub: &SceneStub) -> AiPr
             )
             .replacen("{SCENE_NAME}", &stub.name, 1)
             .replacen("{SCENE_REGION}", &stub.region, 1),
-        SCENE_BNF,
+        SceneSeed::to_grammar(),
         1024,
     )
+    .with_json_schema(SceneSeed::to_json_schema())
+    .with_instructions(SCENE_INSTRUCTIONS)
+    .with_kind(PromptKind::SceneCreation)
 }
 
 pub fn person_creation_prompt(scene: &SceneSeed, person: &PersonSeed) -> AiPrompt {
@@ -297,7 +247,9 @@ pub fn person_creation_prompt(scene: &SceneSeed, person: &PersonSeed) -> AiPromp
             .replacen("{RACE}", &person.race, 1)
             .replacen("{OCCUPATION}", &person.occupation, 1)
             .replacen("{SCENE_INFO}", &scene_info_for_person(scene), 1),
-        PERSON_DETAILS_BNF,
+        PersonDetails::to_grammar(),
         1024,
     )
+    .with_json_schema(PersonDetails::to_json_schema())
+    .with_kind(PromptKind::PersonCreation)
 }
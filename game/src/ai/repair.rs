@@ -0,0 +1,242 @@
+//! Best-effort repair of JSON that the LLM stopped generating in the
+//! middle of. The truncated document is cut back to the last complete
+//! value, and every open string, array, and object is closed.
+
+/// A point in the document where it can be cut off and still be
+/// closed into valid JSON.
+struct CutPoint {
+    len: usize,
+    closers: Vec<char>,
+}
+
+fn is_scalar_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '.')
+}
+
+fn is_complete_scalar(token: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(token).is_ok()
+}
+
+/// Close a truncated JSON document. If the document was cut off in
+/// the middle of a string value, the partial string is kept.
+/// Otherwise, anything after the last complete value (a dangling key,
+/// a trailing comma, half a number, an unfinished object in a list)
+/// is dropped. Returns the input as-is if there is nothing to
+/// salvage.
+pub fn repair_truncated_json(partial: &str) -> String {
+    let mut closers: Vec<char> = vec![];
+    let mut in_string = false;
+    let mut string_is_key = false;
+    let mut expect_key = false;
+    let mut escape_start: Option<usize> = None;
+    let mut unicode_remaining = 0;
+    let mut scalar_start: Option<usize> = None;
+    let mut cut: Option<CutPoint> = None;
+
+    macro_rules! mark_cut {
+        ($len:expr) => {
+            cut = Some(CutPoint {
+                len: $len,
+                closers: closers.clone(),
+            })
+        };
+    }
+
+    for (index, c) in partial.char_indices() {
+        let end = index + c.len_utf8();
+
+        if in_string {
+            if unicode_remaining > 0 {
+                unicode_remaining -= 1;
+                if unicode_remaining == 0 {
+                    escape_start = None;
+                }
+            } else if escape_start.is_some() {
+                if c == 'u' {
+                    unicode_remaining = 4;
+                } else {
+                    escape_start = None;
+                }
+            } else if c == '\\' {
+                escape_start = Some(index);
+            } else if c == '"' {
+                in_string = false;
+                if !string_is_key {
+                    mark_cut!(end);
+                }
+            }
+
+            continue;
+        }
+
+        if let Some(start) = scalar_start {
+            if is_scalar_char(c) {
+                continue;
+            }
+
+            scalar_start = None;
+            if is_complete_scalar(&partial[start..index]) {
+                mark_cut!(index);
+            }
+        }
+
+        match c {
+            '"' => {
+                in_string = true;
+                string_is_key = expect_key;
+            }
+            '{' => {
+                // An empty object in a list would most likely fail
+                // to deserialize, so it is better to drop it.
+                let in_list = closers.last() == Some(&']');
+                closers.push('}');
+                expect_key = true;
+                if !in_list {
+                    mark_cut!(end);
+                }
+            }
+            '[' => {
+                closers.push(']');
+                expect_key = false;
+                mark_cut!(end);
+            }
+            '}' | ']' => {
+                closers.pop();
+                expect_key = false;
+                mark_cut!(end);
+            }
+            ':' => expect_key = false,
+            ',' => expect_key = closers.last() == Some(&'}'),
+            c if is_scalar_char(c) => scalar_start = Some(index),
+            _ => (),
+        }
+    }
+
+    if let Some(start) = scalar_start {
+        if is_complete_scalar(&partial[start..]) {
+            mark_cut!(partial.len());
+        }
+    }
+
+    let (mut repaired, closers) = if in_string && !string_is_key {
+        // Keep the partial string value, minus any escape sequence
+        // that was cut off.
+        let len = escape_start.unwrap_or(partial.len());
+        (format!("{}\"", &partial[..len]), closers)
+    } else {
+        match cut {
+            Some(cut) => (partial[..cut.len].to_string(), cut.closers),
+            None => return partial.to_string(),
+        }
+    };
+
+    repaired.extend(closers.iter().rev());
+    repaired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_repairs(cases: &[(&str, &str)]) {
+        for (partial, expected) in cases {
+            let repaired = repair_truncated_json(partial);
+            assert_eq!(&repaired, expected, "repairing {:?}", partial);
+            assert!(
+                serde_json::from_str::<serde_json::Value>(&repaired).is_ok(),
+                "{:?} is not valid JSON",
+                repaired
+            );
+        }
+    }
+
+    #[test]
+    fn complete_documents_are_unchanged() {
+        assert_repairs(&[
+            (r#"{"a": 1}"#, r#"{"a": 1}"#),
+            (r#"[1, "two", null]"#, r#"[1, "two", null]"#),
+        ]);
+    }
+
+    #[test]
+    fn keeps_partial_string_values() {
+        assert_repairs(&[
+            (r#"{"name": "The Rus"#, r#"{"name": "The Rus"}"#),
+            (r#"{"a": ["one", "tw"#, r#"{"a": ["one", "tw"]}"#),
+            (r#"{"a": "say \"hi"#, r#"{"a": "say \"hi"}"#),
+        ]);
+    }
+
+    #[test]
+    fn drops_cut_off_escapes() {
+        assert_repairs(&[
+            (r#"{"a": "x\"#, r#"{"a": "x"}"#),
+            (r#"{"a": "x\u00"#, r#"{"a": "x"}"#),
+            (r#"{"a": "xé"#, r#"{"a": "xé"}"#),
+        ]);
+    }
+
+    #[test]
+    fn drops_dangling_keys() {
+        assert_repairs(&[
+            (r#"{"a": 1, "b"#, r#"{"a": 1}"#),
+            (r#"{"a": 1, "b""#, r#"{"a": 1}"#),
+            (r#"{"a": 1, "b":"#, r#"{"a": 1}"#),
+            (r#"{"a": 1, "b": "#, r#"{"a": 1}"#),
+            (r#"{"a": {"b"#, r#"{"a": {}}"#),
+        ]);
+    }
+
+    #[test]
+    fn closes_nested_arrays() {
+        assert_repairs(&[
+            (r#"{"a": [1, 2, 3"#, r#"{"a": [1, 2, 3]}"#),
+            (r#"{"a": [[1, 2], [3"#, r#"{"a": [[1, 2], [3]]}"#),
+            (
+                r#"{"a": [{"b": 1}, {"b": 2"#,
+                r#"{"a": [{"b": 1}, {"b": 2}]}"#,
+            ),
+        ]);
+    }
+
+    #[test]
+    fn drops_unfinished_objects_in_lists() {
+        assert_repairs(&[
+            (r#"{"a": [{"b": 1}, {"#, r#"{"a": [{"b": 1}]}"#),
+            (r#"{"a": [{"b": 1}, {"c"#, r#"{"a": [{"b": 1}]}"#),
+        ]);
+    }
+
+    #[test]
+    fn closes_nested_objects() {
+        assert_repairs(&[
+            (r#"{"a": {"b": {"c": 1"#, r#"{"a": {"b": {"c": 1}}}"#),
+            (r#"{"a": {"b": {"c": tr"#, r#"{"a": {"b": {}}}"#),
+            (r#"{"a": {"b": 1}, "c": {"#, r#"{"a": {"b": 1}, "c": {}}"#),
+        ]);
+    }
+
+    #[test]
+    fn drops_trailing_commas() {
+        assert_repairs(&[
+            (r#"{"a": 1,"#, r#"{"a": 1}"#),
+            (r#"{"a": [1, 2,"#, r#"{"a": [1, 2]}"#),
+            (r#"{"a": "x", "#, r#"{"a": "x"}"#),
+            (r#"{"a": {"b": true},"#, r#"{"a": {"b": true}}"#),
+        ]);
+    }
+
+    #[test]
+    fn drops_half_numbers() {
+        assert_repairs(&[
+            (r#"{"a": "x", "b": 1."#, r#"{"a": "x"}"#),
+            (r#"{"a": [1, -"#, r#"{"a": [1]}"#),
+        ]);
+    }
+
+    #[test]
+    fn returns_unsalvageable_input_as_is() {
+        assert_eq!(repair_truncated_json(""), "");
+        assert_eq!(repair_truncated_json("tr"), "tr");
+    }
+}
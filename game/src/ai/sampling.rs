@@ -0,0 +1,281 @@
+use super::convo::AiCreativity;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use strum::IntoStaticStr;
+
+/// KoboldCPP only accepts sampler seeds up to this value.
+const MAX_SAMPLER_SEED: u64 = 999_999;
+
+/// Derive the sampler seed of a generation from the world seed and
+/// what is being generated. Never 0, which backends take to mean a
+/// random seed.
+pub fn derive_seed(world_seed: u64, prompt: &str, grammar: Option<&str>) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(world_seed.to_le_bytes());
+    hasher.update(prompt.as_bytes());
+    hasher.update([0]);
+    hasher.update(grammar.unwrap_or_default().as_bytes());
+
+    let hash = hasher.finalize();
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&hash[..8]);
+    u64::from_le_bytes(bytes) % MAX_SAMPLER_SEED + 1
+}
+
+/// The seed for a retry of a generation with the given seed. Stays
+/// within the range KoboldCPP accepts. A seed of 0 is random anyway,
+/// and stays 0.
+pub fn retry_seed(seed: u64, retries: u32) -> u64 {
+    match (seed, retries) {
+        (0, _) | (_, 0) => seed,
+        _ => (seed % MAX_SAMPLER_SEED + u64::from(retries) - 1) % MAX_SAMPLER_SEED + 1,
+    }
+}
+
+/// The tasks the LLM is prompted for. Each can use its own sampler
+/// profile, on top of the one for the prompt's creativity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, IntoStaticStr)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum PromptKind {
+    Parsing,
+    Execution,
+    SceneCreation,
+    PersonCreation,
+    ExitFixing,
+}
+
+/// Sampler parameters for a generation. Unset parameters are left to
+/// the backend's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SamplerProfile {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<u64>,
+    pub top_a: Option<f64>,
+    pub min_p: Option<f64>,
+    pub typical: Option<f64>,
+    pub tfs: Option<f64>,
+    pub rep_pen: Option<f64>,
+    pub rep_pen_range: Option<u64>,
+    pub mirostat: Option<u8>,
+    pub mirostat_tau: Option<f64>,
+    pub mirostat_eta: Option<f64>,
+    pub sampler_order: Option<Vec<i64>>,
+    pub seed: Option<u64>,
+}
+
+impl SamplerProfile {
+    /// The sampler settings the game was originally tuned with, for
+    /// Mistral 7B Instruct.
+    fn tuned(temperature: f64) -> SamplerProfile {
+        SamplerProfile {
+            temperature: Some(temperature),
+            top_p: Some(0.92),
+            top_a: Some(0.0),
+            rep_pen: Some(1.1),
+            rep_pen_range: Some(320),
+            sampler_order: Some(vec![6, 0, 1, 3, 4, 2, 5]),
+            ..Default::default()
+        }
+    }
+
+    /// Take every parameter that is set in the other profile.
+    pub fn merge(mut self, other: &SamplerProfile) -> SamplerProfile {
+        macro_rules! take {
+            ($($field:ident),*) => {
+                $(if other.$field.is_some() {
+                    self.$field = other.$field.clone();
+                })*
+            };
+        }
+
+        take!(
+            temperature, top_p, top_k, top_a, min_p, typical, tfs, rep_pen, rep_pen_range,
+            mirostat, mirostat_tau, mirostat_eta, sampler_order, seed
+        );
+
+        self
+    }
+}
+
+/// Maps creativity levels and prompt kinds to sampler profiles.
+#[derive(Debug, Clone)]
+pub struct SamplerSettings {
+    predictable: SamplerProfile,
+    normal: SamplerProfile,
+    creative: SamplerProfile,
+    prompt_kinds: HashMap<PromptKind, SamplerProfile>,
+}
+
+impl Default for SamplerSettings {
+    fn default() -> Self {
+        SamplerSettings {
+            predictable: SamplerProfile::tuned(0.5),
+            normal: SamplerProfile::tuned(0.7),
+            creative: SamplerProfile::tuned(1.0),
+            prompt_kinds: HashMap::new(),
+        }
+    }
+}
+
+impl SamplerSettings {
+    /// Build the settings from named profiles. The profiles named
+    /// `predictable`, `normal`, and `creative` adjust the defaults for
+    /// those creativity levels. Prompt kinds are mapped to profiles by
+    /// name.
+    pub fn new(
+        mut profiles: HashMap<String, SamplerProfile>,
+        prompt_kinds: HashMap<PromptKind, String>,
+    ) -> Result<SamplerSettings> {
+        let defaults = SamplerSettings::default();
+
+        let prompt_kinds = prompt_kinds
+            .into_iter()
+            .map(|(kind, name)| {
+                profiles
+                    .get(&name)
+                    .cloned()
+                    .map(|profile| (kind, profile))
+                    .ok_or_else(|| anyhow!("unknown sampler profile for {:?}: {}", kind, name))
+            })
+            .collect::<Result<_>>()?;
+
+        let mut level = |name: &str, default: SamplerProfile| match profiles.remove(name) {
+            Some(profile) => default.merge(&profile),
+            None => default,
+        };
+
+        Ok(SamplerSettings {
+            predictable: level("predictable", defaults.predictable),
+            normal: level("normal", defaults.normal),
+            creative: level("creative", defaults.creative),
+            prompt_kinds,
+        })
+    }
+
+    /// The sampler profile for a prompt: the one for its creativity,
+    /// overridden by the one for its kind.
+    pub fn profile(&self, creativity: AiCreativity, kind: Option<PromptKind>) -> SamplerProfile {
+        let base = match creativity {
+            AiCreativity::Predictable => &self.predictable,
+            AiCreativity::Normal => &self.normal,
+            AiCreativity::Creative => &self.creative,
+        };
+
+        match kind.and_then(|kind| self.prompt_kinds.get(&kind)) {
+            Some(overrides) => base.clone().merge(overrides),
+            None => base.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(temperature: Option<f64>, top_k: Option<u64>) -> SamplerProfile {
+        SamplerProfile {
+            temperature,
+            top_k,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merging_takes_only_the_set_parameters() {
+        let base = SamplerProfile {
+            seed: Some(3),
+            ..profile(Some(0.7), None)
+        };
+
+        let merged = base.merge(&profile(None, Some(40)));
+        assert_eq!(
+            merged,
+            SamplerProfile {
+                seed: Some(3),
+                ..profile(Some(0.7), Some(40))
+            }
+        );
+    }
+
+    #[test]
+    fn creativity_levels_default_to_the_tuned_profiles() {
+        let settings = SamplerSettings::default();
+        let cases = [
+            (AiCreativity::Predictable, 0.5),
+            (AiCreativity::Normal, 0.7),
+            (AiCreativity::Creative, 1.0),
+        ];
+
+        for (creativity, temperature) in cases {
+            let profile = settings.profile(creativity, Some(PromptKind::Parsing));
+            assert_eq!(profile, SamplerProfile::tuned(temperature));
+        }
+    }
+
+    #[test]
+    fn named_profiles_adjust_levels_and_prompt_kinds() {
+        let profiles = HashMap::from([
+            ("creative".to_string(), profile(Some(1.2), None)),
+            ("precise".to_string(), profile(Some(0.1), Some(10))),
+        ]);
+        let prompt_kinds = HashMap::from([(PromptKind::Parsing, "precise".to_string())]);
+        let settings = SamplerSettings::new(profiles, prompt_kinds).unwrap();
+
+        let creative = settings.profile(AiCreativity::Creative, None);
+        assert_eq!(creative.temperature, Some(1.2));
+        assert_eq!(creative.top_p, Some(0.92));
+
+        // The prompt kind's profile is merged over the level's.
+        let parsing = settings.profile(AiCreativity::Creative, Some(PromptKind::Parsing));
+        assert_eq!(parsing.temperature, Some(0.1));
+        assert_eq!(parsing.top_k, Some(10));
+        assert_eq!(parsing.top_p, Some(0.92));
+
+        let execution = settings.profile(AiCreativity::Normal, Some(PromptKind::Execution));
+        assert_eq!(execution, SamplerProfile::tuned(0.7));
+    }
+
+    #[test]
+    fn unknown_profile_names_are_errors() {
+        let prompt_kinds = HashMap::from([(PromptKind::Execution, "missing".to_string())]);
+        let err = SamplerSettings::new(HashMap::new(), prompt_kinds).unwrap_err();
+        assert!(err.to_string().contains("missing"));
+    }
+
+    #[test]
+    fn profiles_deserialize_with_unset_parameters() {
+        let profile: SamplerProfile =
+            serde_json::from_str(r#"{"temperature": 0.3, "sampler_order": [6, 0]}"#).unwrap();
+
+        assert_eq!(profile.temperature, Some(0.3));
+        assert_eq!(profile.sampler_order, Some(vec![6, 0]));
+        assert_eq!(profile.top_p, None);
+    }
+
+    #[test]
+    fn derived_seeds_are_stable_and_valid() {
+        let seed = derive_seed(1234, "prompt", Some("grammar"));
+        assert_eq!(seed, derive_seed(1234, "prompt", Some("grammar")));
+        assert_ne!(seed, derive_seed(1235, "prompt", Some("grammar")));
+        assert!((1..=MAX_SAMPLER_SEED).contains(&seed));
+
+        // Seeds are stored with nothing but the world seed, so they
+        // must not change between builds.
+        assert_eq!(seed, 808146);
+    }
+
+    #[test]
+    fn retries_get_other_valid_seeds() {
+        assert_eq!(retry_seed(5, 0), 5);
+        assert_eq!(retry_seed(5, 1), 6);
+        assert_eq!(retry_seed(5, 2), 7);
+        assert_eq!(retry_seed(MAX_SAMPLER_SEED, 1), 1);
+        assert_eq!(retry_seed(u64::MAX, 3), u64::MAX % MAX_SAMPLER_SEED + 3);
+        assert_eq!(retry_seed(0, 2), 0);
+    }
+}
@@ -0,0 +1,255 @@
+use super::backend::TokenSink;
+
+/// Decode the escape sequence following a backslash in a JSON
+/// string. Returns None while the sequence is incomplete.
+fn decode_escape(escape: &str) -> Option<Option<char>> {
+    let mut chars = escape.chars();
+    let decoded = match chars.next()? {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'u' => {
+            let hex: String = chars.take(4).collect();
+            if hex.len() < 4 {
+                return None;
+            }
+
+            let code = u32::from_str_radix(&hex, 16).unwrap_or(0xFFFD);
+            Some(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER))
+        }
+        'r' | 'b' | 'f' => None,
+        other => Some(other),
+    };
+
+    Some(decoded)
+}
+
+/// Reads a JSON document while it is being generated, and passes the
+/// text of one top-level string field on to another sink as it
+/// arrives. Used to show the narration of a command to the player
+/// before the rest of the response has been generated.
+pub struct JsonFieldStream<S: TokenSink> {
+    field: String,
+    sink: S,
+
+    // One entry per open container: true for objects.
+    containers: Vec<bool>,
+    in_string: bool,
+    string_is_key: bool,
+    expect_key: bool,
+    escape: Option<String>,
+    key: String,
+    last_key: String,
+    in_field: bool,
+}
+
+impl<S: TokenSink> JsonFieldStream<S> {
+    pub fn new(field: &str, sink: S) -> JsonFieldStream<S> {
+        JsonFieldStream {
+            field: field.to_string(),
+            sink,
+            containers: vec![],
+            in_string: false,
+            string_is_key: false,
+            expect_key: false,
+            escape: None,
+            key: String::new(),
+            last_key: String::new(),
+            in_field: false,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+
+    fn string_char(&mut self, c: char, out: &mut String) {
+        if self.string_is_key {
+            self.key.push(c);
+        } else if self.in_field {
+            out.push(c);
+        }
+    }
+
+    fn push_char(&mut self, c: char, out: &mut String) {
+        if self.in_string {
+            if let Some(escape) = self.escape.as_mut() {
+                escape.push(c);
+                if let Some(decoded) = decode_escape(escape) {
+                    self.escape = None;
+                    if let Some(decoded) = decoded {
+                        self.string_char(decoded, out);
+                    }
+                }
+            } else if c == '\\' {
+                self.escape = Some(String::new());
+            } else if c == '"' {
+                self.in_string = false;
+                self.in_field = false;
+                if self.string_is_key && self.containers.len() == 1 {
+                    self.last_key = std::mem::take(&mut self.key);
+                }
+            } else {
+                self.string_char(c, out);
+            }
+
+            return;
+        }
+
+        match c {
+            '"' => {
+                self.in_string = true;
+                self.string_is_key = self.expect_key;
+                self.key.clear();
+                self.in_field = !self.string_is_key
+                    && self.containers.len() == 1
+                    && self.last_key == self.field;
+            }
+            '{' => {
+                self.containers.push(true);
+                self.expect_key = true;
+            }
+            '[' => {
+                self.containers.push(false);
+                self.expect_key = false;
+            }
+            '}' | ']' => {
+                self.containers.pop();
+                self.expect_key = false;
+            }
+            ':' => self.expect_key = false,
+            ',' => self.expect_key = self.containers.last() == Some(&true),
+            _ => (),
+        }
+    }
+}
+
+impl<S: TokenSink> TokenSink for JsonFieldStream<S> {
+    fn token(&mut self, token: &str) {
+        let mut out = String::new();
+        for c in token.chars() {
+            self.push_char(c, &mut out);
+        }
+
+        if !out.is_empty() {
+            self.sink.token(&out);
+        }
+    }
+
+    fn restart(&mut self) {
+        self.containers.clear();
+        self.in_string = false;
+        self.string_is_key = false;
+        self.expect_key = false;
+        self.escape = None;
+        self.key.clear();
+        self.last_key.clear();
+        self.in_field = false;
+        self.sink.restart();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Collected {
+        text: String,
+        restarts: usize,
+    }
+
+    impl TokenSink for Collected {
+        fn token(&mut self, token: &str) {
+            self.text.push_str(token);
+        }
+
+        fn restart(&mut self) {
+            self.text.clear();
+            self.restarts += 1;
+        }
+    }
+
+    fn stream_chunks(chunks: &[&str]) -> Collected {
+        let mut stream = JsonFieldStream::new("narration", Collected::default());
+        for chunk in chunks {
+            stream.token(chunk);
+        }
+
+        stream.into_inner()
+    }
+
+    /// Every way of splitting the document into two chunks.
+    fn stream_split(json: &str) -> Vec<String> {
+        json.char_indices()
+            .map(|(index, _)| {
+                let (first, second) = json.split_at(index);
+                stream_chunks(&[first, second]).text
+            })
+            .collect()
+    }
+
+    #[test]
+    fn streams_only_the_top_level_field() {
+        let json =
+            r#"{"valid": true, "narration": "You take the mug.", "event": {"narration": "no"}}"#;
+        assert_eq!(stream_chunks(&[json]).text, "You take the mug.");
+    }
+
+    #[test]
+    fn ignores_strings_that_look_like_the_key() {
+        let json = r#"{"items": ["narration", "x"], "reason": "narration", "narration": "y"}"#;
+        assert_eq!(stream_chunks(&[json]).text, "y");
+
+        let json = r#"{"narrationX": "no", "narratio": "no"}"#;
+        assert_eq!(stream_chunks(&[json]).text, "");
+    }
+
+    #[test]
+    fn decodes_escapes() {
+        let json = r#"{"narration": "a\"b\\c\nd\teéf\/g\rh"}"#;
+        assert_eq!(stream_chunks(&[json]).text, "a\"b\\c\nd\teéf/gh");
+    }
+
+    #[test]
+    fn escaped_quotes_do_not_end_keys() {
+        let json = r#"{"say \"narration\"": "no", "narration": "yes"}"#;
+        assert_eq!(stream_chunks(&[json]).text, "yes");
+    }
+
+    #[test]
+    fn handles_chunk_boundaries_anywhere() {
+        let json = r#"{"valid": true, "narration": "He said \"hié\".", "reason": null}"#;
+        for text in stream_split(json) {
+            assert_eq!(text, "He said \"hié\".");
+        }
+    }
+
+    #[test]
+    fn handles_chunk_boundaries_inside_keys() {
+        let text = stream_chunks(&[r#"{"narr"#, r#"ation": "hi"}"#]).text;
+        assert_eq!(text, "hi");
+
+        let text = stream_chunks(&[r#"{"narration"#, r#"X": "no", "b": "#, r#""no"}"#]).text;
+        assert_eq!(text, "");
+    }
+
+    #[test]
+    fn streams_one_character_at_a_time() {
+        let json = r#"{"narration": "abc\n"}"#;
+        let chunks: Vec<String> = json.chars().map(String::from).collect();
+        let chunks: Vec<&str> = chunks.iter().map(String::as_str).collect();
+        assert_eq!(stream_chunks(&chunks).text, "abc\n");
+    }
+
+    #[test]
+    fn restart_starts_over() {
+        let mut stream = JsonFieldStream::new("narration", Collected::default());
+        stream.token(r#"{"narration": "Hel\u00"#);
+        stream.restart();
+        stream.token(r#"{"narration": "Hello"}"#);
+
+        let collected = stream.into_inner();
+        assert_eq!(collected.text, "Hello");
+        assert_eq!(collected.restarts, 1);
+    }
+}
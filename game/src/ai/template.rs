@@ -0,0 +1,375 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Who a message in a conversation comes from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn system(content: &str) -> ChatMessage {
+        ChatMessage {
+            role: Role::System,
+            content: content.to_string(),
+        }
+    }
+
+    pub fn user(content: &str) -> ChatMessage {
+        ChatMessage {
+            role: Role::User,
+            content: content.to_string(),
+        }
+    }
+
+    pub fn assistant(content: &str) -> ChatMessage {
+        ChatMessage {
+            role: Role::Assistant,
+            content: content.to_string(),
+        }
+    }
+
+    /// Instructions are trimmed so that the prompt constants can be
+    /// written as indented raw strings. The model's own output is
+    /// left alone, because it may be continued later.
+    fn text(&self) -> &str {
+        match self.role {
+            Role::Assistant => &self.content,
+            _ => self.content.trim(),
+        }
+    }
+}
+
+/// The prompt format an instruct model was trained on. Prompts are
+/// written as role-tagged messages, and the template turns them into
+/// the raw text sent to text completion backends.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ChatTemplate {
+    /// `<s>[INST] ... [/INST]`, for Mistral 7B Instruct and Mixtral.
+    #[default]
+    Mistral,
+
+    /// `<|im_start|>role ... <|im_end|>`, used by many fine tunes.
+    ChatMl,
+
+    /// `<|start_header_id|>role<|end_header_id|> ... <|eot_id|>`
+    Llama3,
+
+    /// `### Instruction:` / `### Response:`
+    Alpaca,
+
+    /// Messages are concatenated with no special tokens.
+    Raw,
+}
+
+impl FromStr for ChatTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_ref() {
+            "mistral" => Ok(ChatTemplate::Mistral),
+            "chatml" => Ok(ChatTemplate::ChatMl),
+            "llama3" | "llama-3" => Ok(ChatTemplate::Llama3),
+            "alpaca" => Ok(ChatTemplate::Alpaca),
+            "raw" | "none" => Ok(ChatTemplate::Raw),
+            _ => Err(anyhow!("unknown chat template: {}", value)),
+        }
+    }
+}
+
+impl ChatTemplate {
+    /// Render the conversation into a prompt. If the last message is
+    /// from the assistant, it is left open so the model continues
+    /// it. Otherwise, the prompt ends with the start of a new
+    /// assistant response.
+    pub fn render(&self, messages: &[ChatMessage]) -> String {
+        match self {
+            ChatTemplate::Mistral => render_mistral(messages),
+            ChatTemplate::ChatMl => render_tagged(messages, CHATML),
+            ChatTemplate::Llama3 => render_tagged(messages, LLAMA3),
+            ChatTemplate::Alpaca => render_alpaca(messages),
+            ChatTemplate::Raw => render_raw(messages),
+        }
+    }
+
+    /// Sequences that mark the end of the model's turn.
+    pub fn stop_sequences(&self) -> &'static [&'static str] {
+        match self {
+            ChatTemplate::Mistral => &["<s>", "</s>"],
+            ChatTemplate::ChatMl => &["<|im_end|>", "<|im_start|>"],
+            ChatTemplate::Llama3 => &["<|eot_id|>", "<|end_of_text|>"],
+            ChatTemplate::Alpaca => &["### Instruction:"],
+            ChatTemplate::Raw => &[],
+        }
+    }
+}
+
+fn is_open(messages: &[ChatMessage], index: usize) -> bool {
+    index == messages.len() - 1 && messages[index].role == Role::Assistant
+}
+
+fn ends_with_assistant(messages: &[ChatMessage]) -> bool {
+    messages.last().map(|msg| msg.role) == Some(Role::Assistant)
+}
+
+// https://huggingface.co/mistralai/Mistral-7B-Instruct-v0.2
+// Only the very first instruction begins with <s>, and there is no
+// system role, so system messages are folded into the next
+// instruction.
+fn render_mistral(messages: &[ChatMessage]) -> String {
+    let mut prompt = String::from("<s>");
+    let mut instructions: Vec<&str> = vec![];
+
+    for (index, message) in messages.iter().enumerate() {
+        match message.role {
+            Role::System | Role::User => instructions.push(message.text()),
+            Role::Assistant => {
+                if !instructions.is_empty() {
+                    prompt.push_str(&format!("[INST] {} [/INST]", instructions.join("\n\n")));
+                    instructions.clear();
+                }
+
+                prompt.push_str(message.text());
+                if !is_open(messages, index) {
+                    prompt.push_str("</s>");
+                }
+            }
+        }
+    }
+
+    if !instructions.is_empty() {
+        prompt.push_str(&format!("[INST] {} [/INST]", instructions.join("\n\n")));
+    }
+
+    prompt
+}
+
+/// Special tokens for templates that wrap every message in a header
+/// and a terminator.
+struct TaggedFormat {
+    begin: &'static str,
+    header_start: &'static str,
+    header_end: &'static str,
+    message_end: &'static str,
+}
+
+const CHATML: TaggedFormat = TaggedFormat {
+    begin: "",
+    header_start: "<|im_start|>",
+    header_end: "\n",
+    message_end: "<|im_end|>\n",
+};
+
+const LLAMA3: TaggedFormat = TaggedFormat {
+    begin: "<|begin_of_text|>",
+    header_start: "<|start_header_id|>",
+    header_end: "<|end_header_id|>\n\n",
+    message_end: "<|eot_id|>",
+};
+
+fn render_tagged(messages: &[ChatMessage], format: TaggedFormat) -> String {
+    let mut prompt = String::from(format.begin);
+
+    for (index, message) in messages.iter().enumerate() {
+        prompt.push_str(format.header_start);
+        prompt.push_str(message.role.as_str());
+        prompt.push_str(format.header_end);
+        prompt.push_str(message.text());
+
+        if !is_open(messages, index) {
+            prompt.push_str(format.message_end);
+        }
+    }
+
+    if !ends_with_assistant(messages) {
+        prompt.push_str(format.header_start);
+        prompt.push_str(Role::Assistant.as_str());
+        prompt.push_str(format.header_end);
+    }
+
+    prompt
+}
+
+fn render_alpaca(messages: &[ChatMessage]) -> String {
+    let mut prompt = String::new();
+
+    for (index, message) in messages.iter().enumerate() {
+        match message.role {
+            Role::System => prompt.push_str(&format!("{}\n\n", message.text())),
+            Role::User => prompt.push_str(&format!("### Instruction:\n{}\n\n", message.text())),
+            Role::Assistant => {
+                prompt.push_str("### Response:\n");
+                prompt.push_str(message.text());
+                if !is_open(messages, index) {
+                    prompt.push_str("\n\n");
+                }
+            }
+        }
+    }
+
+    if !ends_with_assistant(messages) {
+        prompt.push_str("### Response:\n");
+    }
+
+    prompt
+}
+
+fn render_raw(messages: &[ChatMessage]) -> String {
+    let mut prompt = String::new();
+
+    for (index, message) in messages.iter().enumerate() {
+        prompt.push_str(message.text());
+        if !is_open(messages, index) {
+            prompt.push_str("\n\n");
+        }
+    }
+
+    prompt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A finished exchange, followed by a new instruction.
+    fn conversation() -> Vec<ChatMessage> {
+        vec![
+            ChatMessage::system("\n    Be brief.\n"),
+            ChatMessage::user("Look around."),
+            ChatMessage::assistant("A room."),
+            ChatMessage::user("Go north."),
+        ]
+    }
+
+    /// A response that stopped in the middle.
+    fn continuation() -> Vec<ChatMessage> {
+        vec![
+            ChatMessage::user("Look around."),
+            ChatMessage::assistant(r#"{"name": "#),
+        ]
+    }
+
+    fn assert_renders(template: ChatTemplate, conversation: &str, continuation: &str) {
+        assert_eq!(template.render(&self::conversation()), conversation);
+        assert_eq!(template.render(&self::continuation()), continuation);
+    }
+
+    #[test]
+    fn renders_mistral() {
+        assert_renders(
+            ChatTemplate::Mistral,
+            "<s>[INST] Be brief.\n\nLook around. [/INST]A room.</s>[INST] Go north. [/INST]",
+            r#"<s>[INST] Look around. [/INST]{"name": "#,
+        );
+    }
+
+    #[test]
+    fn renders_chatml() {
+        assert_renders(
+            ChatTemplate::ChatMl,
+            concat!(
+                "<|im_start|>system\nBe brief.<|im_end|>\n",
+                "<|im_start|>user\nLook around.<|im_end|>\n",
+                "<|im_start|>assistant\nA room.<|im_end|>\n",
+                "<|im_start|>user\nGo north.<|im_end|>\n",
+                "<|im_start|>assistant\n",
+            ),
+            concat!(
+                "<|im_start|>user\nLook around.<|im_end|>\n",
+                "<|im_start|>assistant\n{\"name\": ",
+            ),
+        );
+    }
+
+    #[test]
+    fn renders_llama3() {
+        assert_renders(
+            ChatTemplate::Llama3,
+            concat!(
+                "<|begin_of_text|>",
+                "<|start_header_id|>system<|end_header_id|>\n\nBe brief.<|eot_id|>",
+                "<|start_header_id|>user<|end_header_id|>\n\nLook around.<|eot_id|>",
+                "<|start_header_id|>assistant<|end_header_id|>\n\nA room.<|eot_id|>",
+                "<|start_header_id|>user<|end_header_id|>\n\nGo north.<|eot_id|>",
+                "<|start_header_id|>assistant<|end_header_id|>\n\n",
+            ),
+            concat!(
+                "<|begin_of_text|>",
+                "<|start_header_id|>user<|end_header_id|>\n\nLook around.<|eot_id|>",
+                "<|start_header_id|>assistant<|end_header_id|>\n\n",
+                r#"{"name": "#,
+            ),
+        );
+    }
+
+    #[test]
+    fn renders_alpaca() {
+        assert_renders(
+            ChatTemplate::Alpaca,
+            concat!(
+                "Be brief.\n\n",
+                "### Instruction:\nLook around.\n\n",
+                "### Response:\nA room.\n\n",
+                "### Instruction:\nGo north.\n\n",
+                "### Response:\n",
+            ),
+            concat!(
+                "### Instruction:\nLook around.\n\n",
+                "### Response:\n",
+                r#"{"name": "#,
+            ),
+        );
+    }
+
+    #[test]
+    fn renders_raw() {
+        assert_renders(
+            ChatTemplate::Raw,
+            "Be brief.\n\nLook around.\n\nA room.\n\nGo north.\n\n",
+            "Look around.\n\n{\"name\": ",
+        );
+    }
+
+    #[test]
+    fn keeps_assistant_whitespace() {
+        let messages = [ChatMessage::user("  Hi  "), ChatMessage::assistant("  {\n")];
+        assert_eq!(ChatTemplate::Raw.render(&messages), "Hi\n\n  {\n");
+    }
+
+    #[test]
+    fn parses_template_names() {
+        let cases = [
+            ("mistral", ChatTemplate::Mistral),
+            ("ChatML", ChatTemplate::ChatMl),
+            ("llama-3", ChatTemplate::Llama3),
+            ("alpaca", ChatTemplate::Alpaca),
+            ("none", ChatTemplate::Raw),
+        ];
+
+        for (name, template) in cases {
+            assert_eq!(name.parse::<ChatTemplate>().unwrap(), template);
+        }
+
+        assert!("vicuna".parse::<ChatTemplate>().is_err());
+    }
+}
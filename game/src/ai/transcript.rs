@@ -0,0 +1,184 @@
+//! Records every turn of every conversation with the LLM, so that a
+//! session can be read back later to find out why the model did what
+//! it did.
+
+use super::template::ChatMessage;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const TRANSCRIPT_FILE: &str = "transcript.jsonl";
+
+/// How an attempt at getting a response ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Succeeded,
+    Failed,
+
+    /// Stopped by the player. The responses end where the model was
+    /// when it was stopped.
+    Cancelled,
+}
+
+/// One attempt at getting a response to a prompt.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TranscriptEntry {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+
+    /// Which conversation the turn is in, e.g. parsing.
+    pub conversation: String,
+
+    /// The messages the prompt added to the conversation.
+    pub messages: Vec<ChatMessage>,
+    pub grammar: Option<String>,
+
+    /// What the LLM generated, including continuations.
+    pub responses: Vec<String>,
+    pub outcome: Outcome,
+
+    /// The JSON the response was parsed from, if parsing succeeded.
+    pub result: Option<Value>,
+    pub error: Option<String>,
+}
+
+impl TranscriptEntry {
+    pub fn new(conversation: &str, messages: &[ChatMessage], grammar: Option<&str>) -> Self {
+        TranscriptEntry {
+            timestamp: now(),
+            conversation: conversation.to_string(),
+            messages: messages.to_vec(),
+            grammar: grammar.map(String::from),
+            responses: vec![],
+            outcome: Outcome::Succeeded,
+            result: None,
+            error: None,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+/// Appends transcript entries to a log in a directory of its own for
+/// every game session.
+#[derive(Debug)]
+pub struct TranscriptLogger {
+    session_dir: PathBuf,
+    file: Mutex<File>,
+}
+
+impl TranscriptLogger {
+    /// Start the log of a new session under the given directory.
+    pub fn create(root: impl AsRef<Path>) -> Result<TranscriptLogger> {
+        let session_dir = root.as_ref().join(now().to_string());
+        std::fs::create_dir_all(&session_dir)?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(session_dir.join(TRANSCRIPT_FILE))?;
+
+        Ok(TranscriptLogger {
+            session_dir,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn session_dir(&self) -> &Path {
+        &self.session_dir
+    }
+
+    pub fn log(&self, entry: &TranscriptEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Find the directory of a session: either the path itself, or a
+/// session under the transcript directory. The latest session if none
+/// is given.
+pub fn find_session(root: &Path, session: Option<&str>) -> Result<PathBuf> {
+    if let Some(session) = session {
+        let path = Path::new(session);
+        return match path.is_dir() {
+            true => Ok(path.to_path_buf()),
+            false => Ok(root.join(session)),
+        };
+    }
+
+    // Session directories are named after when they started.
+    std::fs::read_dir(root)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u64>().ok())
+        .max()
+        .map(|latest| root.join(latest.to_string()))
+        .ok_or_else(|| anyhow!("no sessions in {}", root.display()))
+}
+
+/// Read every entry of a session's transcript.
+pub fn read_session(session_dir: &Path) -> Result<Vec<TranscriptEntry>> {
+    let path = session_dir.join(TRANSCRIPT_FILE);
+    let file = File::open(&path).map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+
+    BufReader::new(file)
+        .lines()
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// Print a session's transcript in a human readable form.
+pub fn print_session(session_dir: &Path) -> Result<()> {
+    for entry in read_session(session_dir)? {
+        println!(
+            "==== [{}] at {}, {:?} ====",
+            entry.conversation, entry.timestamp, entry.outcome
+        );
+        for message in &entry.messages {
+            println!("---- {:?} ----", message.role);
+            println!("{}", message.content);
+        }
+
+        if let Some(grammar) = &entry.grammar {
+            println!("---- grammar ----");
+            println!("{}", grammar);
+        }
+
+        for (index, response) in entry.responses.iter().enumerate() {
+            match index {
+                0 => println!("---- response ----"),
+                _ => println!("---- continuation {} ----", index),
+            }
+            println!("{}", response);
+        }
+
+        if let Some(result) = &entry.result {
+            println!("---- result ----");
+            println!("{}", serde_json::to_string_pretty(result)?);
+        }
+
+        if let Some(error) = &entry.error {
+            println!("---- error ----");
+            println!("{}", error);
+        }
+
+        println!();
+    }
+
+    Ok(())
+}
@@ -11,21 +11,21 @@ use crate::{
 use anyhow::{anyhow, Result as AnyhowResult};
 use futures::stream::{self, StreamExt};
 use futures::{future, TryFutureExt};
-use std::rc::Rc;
+use std::sync::Arc;
 use uuid::Uuid;
 
 type CoherenceResult = Result<AiCommand, EventCoherenceFailure>;
 
 pub struct CommandCoherence<'a> {
-    logic: Rc<AiLogic>,
-    db: Rc<Database>,
+    logic: Arc<AiLogic>,
+    db: Arc<Database>,
     stage: &'a Stage,
 }
 
 impl CommandCoherence<'_> {
     pub fn new<'a>(
-        logic: &Rc<AiLogic>,
-        db: &Rc<Database>,
+        logic: &Arc<AiLogic>,
+        db: &Arc<Database>,
         stage: &'a Stage,
     ) -> CommandCoherence<'a> {
         CommandCoherence {
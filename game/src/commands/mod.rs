@@ -1,5 +1,5 @@
 use crate::{
-    ai::logic::AiLogic,
+    ai::{backend::TokenSink, logic::AiLogic},
     db::Database,
     models::{
         commands::{
@@ -9,9 +9,10 @@ use crate::{
         },
         world::scenes::Stage,
     },
+    telemetry::METRICS_TARGET,
 };
 use anyhow::Result;
-use std::rc::Rc;
+use std::sync::Arc;
 
 /// Splits up a stream of results into successes and failures.
 macro_rules! partition {
@@ -72,12 +73,12 @@ fn translate(cmd: &str) -> Option<ParsedCommands> {
 }
 
 pub struct CommandExecutor {
-    logic: Rc<AiLogic>,
-    db: Rc<Database>,
+    logic: Arc<AiLogic>,
+    db: Arc<Database>,
 }
 
 impl CommandExecutor {
-    pub fn new(logic: Rc<AiLogic>, db: Rc<Database>) -> CommandExecutor {
+    pub fn new(logic: Arc<AiLogic>, db: Arc<Database>) -> CommandExecutor {
         CommandExecutor { logic, db }
     }
 
@@ -98,16 +99,23 @@ impl CommandExecutor {
         Ok(maybe_commands)
     }
 
-    pub async fn execute(&self, stage: &Stage, cmd: &str) -> Result<CommandExecution> {
+    /// Execute a command. The raw response of the LLM is handed to
+    /// the sink as it is generated.
+    pub async fn execute(
+        &self,
+        stage: &Stage,
+        cmd: &str,
+        sink: &mut dyn TokenSink,
+    ) -> Result<CommandExecution> {
         if let Some(builtin) = builtins::check_builtin_command(stage, cmd) {
             return Ok(CommandExecution::Builtin(builtin));
         }
 
         let pre_parsed = self.check_translation_and_cache(stage, cmd).await?;
         let raw_exec: RawCommandExecution = if let Some(pre_parsed_cmds) = pre_parsed {
-            self.logic.execute_parsed(stage, &pre_parsed_cmds).await?
+            self.logic.execute_parsed(stage, &pre_parsed_cmds, sink).await?
         } else {
-            let (cmds_to_cache, execution) = self.logic.execute(stage, cmd).await?;
+            let (cmds_to_cache, execution) = self.logic.execute(stage, cmd, sink).await?;
 
             if execution.valid && cmds_to_cache.commands.len() > 0 {
                 self.db
@@ -142,7 +150,15 @@ impl CommandExecutor {
             let fixer = coherence::CommandCoherence::new(&self.logic, &self.db, stage);
 
             // TODO should do something w/ partial failures.
-            fixer.fix_incoherent_event(coherence_failure).await
+            let fixed = fixer.fix_incoherent_event(coherence_failure).await;
+
+            tracing::info!(
+                target: METRICS_TARGET,
+                metric = "event_coherence_fix",
+                success = fixed.is_ok(),
+            );
+
+            fixed
         } else {
             Err(failure)
         }
@@ -1,7 +1,8 @@
 use crate::models::commands::{CachedParsedCommand, ParsedCommand, ParsedCommands};
 use crate::models::world::scenes::{Scene, Stage, StageOrStub};
+use crate::models::world::WorldInfo;
 use crate::models::{Content, ContentContainer, Entity, Insertable};
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use arangors::document::options::InsertOptions;
 use arangors::graph::{EdgeDefinition, Graph};
 use arangors::transaction::{TransactionCollections, TransactionSettings};
@@ -34,6 +35,7 @@ const ITEMS_COLLECTION: &'static str = "items";
 const PROPS_COLLECTION: &'static str = "props";
 const RACES_COLLECTION: &'static str = "races";
 const OCCUPATIONS_COLLECTION: &'static str = "occupations";
+const WORLD_COLLECTION: &'static str = "world_info";
 
 // Edge collections
 const GAME_WORLD_EDGES: &'static str = "game_world";
@@ -51,10 +53,14 @@ const DOC_COLLECTIONS: &'static [&str] = &[
     PROPS_COLLECTION,
     RACES_COLLECTION,
     OCCUPATIONS_COLLECTION,
+    WORLD_COLLECTION,
 ];
 
 const EDGE_COLLECTIONS: &'static [&str] = &[GAME_WORLD_EDGES, PERSON_ATTRS];
 
+// The single document in the world collection.
+const WORLD_INFO_KEY: &'static str = "world";
+
 // Change if we decide to use a different HTTP client.
 type ArangoHttp = ReqwestClient;
 type ActiveDatabase = ArangoDatabase<ArangoHttp>;
@@ -88,6 +94,20 @@ fn extract_document<T>(document: ArangoResult<Document<T>>) -> Result<Option<T>>
     }
 }
 
+/// Explain why listing the databases failed. It is the first request
+/// the game makes, so it is where a server that wants credentials
+/// shows up.
+fn access_error(err: ClientError) -> anyhow::Error {
+    match err {
+        ClientError::Arango(ref arango_err) if matches!(arango_err.code(), 401 | 403) => anyhow!(
+            "ArangoDB refused the request ({}). The game connects without credentials, \
+             so the server must run with --server.authentication false.",
+            arango_err.message()
+        ),
+        err => anyhow!("could not list the databases on ArangoDB ({})", err),
+    }
+}
+
 fn take_first<T>(mut vec: Vec<T>) -> Option<T> {
     if vec.get(0).is_none() {
         None
@@ -178,19 +198,46 @@ impl Database {
         Ok(db)
     }
 
+    /// Create the database, and everything in it, that is missing.
+    /// This is the first thing done with the connection, so its
+    /// errors say what to check on the server.
     async fn init(&self) -> Result<()> {
-        let dbs = self.conn.accessible_databases().await?;
+        let dbs = self
+            .conn
+            .accessible_databases()
+            .await
+            .map_err(access_error)?;
 
         if !dbs.contains_key(&self.world_name) {
-            self.conn.create_database(&self.world_name).await?;
+            self.conn
+                .create_database(&self.world_name)
+                .await
+                .with_context(|| {
+                    format!(
+                        "could not create database {}. The ArangoDB user needs permission \
+                         to create databases.",
+                        self.world_name
+                    )
+                })?;
         }
 
-        self.create_collections(CollectionType::Document, DOC_COLLECTIONS)
-            .await?;
-        self.create_collections(CollectionType::Edge, EDGE_COLLECTIONS)
-            .await?;
+        let collections = [
+            (CollectionType::Document, DOC_COLLECTIONS),
+            (CollectionType::Edge, EDGE_COLLECTIONS),
+        ];
 
-        self.create_graphs().await?;
+        for (coll_type, names) in collections {
+            self.create_collections(coll_type, names).await.with_context(|| {
+                format!("could not create the collections of database {}", self.world_name)
+            })?;
+        }
+
+        self.create_graphs().await.with_context(|| {
+            format!(
+                "could not create graph {} in database {}",
+                GAME_WORLD_GRAPH, self.world_name
+            )
+        })?;
 
         Ok(())
     }
@@ -457,4 +504,20 @@ impl Database {
         let results = self.db().await?.aql_query(aql).await?;
         Ok(take_first(results))
     }
+
+    /// Start a game session, creating the world info with the given
+    /// seed if this is a new world. The seed of an existing world is
+    /// never changed.
+    pub async fn start_session(&self, seed_if_new: u64) -> Result<WorldInfo> {
+        let aql = AqlQuery::builder()
+            .query(queries::START_SESSION)
+            .bind_var("@world_collection", WORLD_COLLECTION)
+            .bind_var("world_key", WORLD_INFO_KEY)
+            .bind_var("seed", seed_if_new.to_string())
+            .build();
+
+        let results = self.db().await?.aql_query(aql).await?;
+        let world = take_first(results).expect("did not get world info");
+        Ok(world)
+    }
 }
@@ -73,3 +73,11 @@ pub const LOAD_CACHED_COMMAND: &'static str = r#"
     FILTER cmd.raw == @raw_cmd && cmd.scene_key == @scene_key
     RETURN cmd
 "#;
+
+pub const START_SESSION: &'static str = r#"
+  UPSERT { _key: @world_key }
+    INSERT { _key: @world_key, seed: @seed, sessions: 1 }
+    UPDATE { sessions: OLD.sessions + 1 }
+  IN @@world_collection
+    RETURN NEW
+"#;
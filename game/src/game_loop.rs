@@ -1,4 +1,6 @@
-use crate::io::display;
+use crate::ai::backend::GenerationError;
+use crate::ai::stream::JsonFieldStream;
+use crate::io::{display, StreamingDisplay};
 use crate::models::commands::{
     AiCommand, BuiltinCommand, CommandExecution, ExecutionConversionResult, EventConversionFailure,
 };
@@ -6,18 +8,37 @@ use crate::state::GameState;
 use crate::{commands::CommandExecutor, db::Database};
 use anyhow::Result;
 use reedline::{DefaultPrompt, Reedline, Signal};
-use std::rc::Rc;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+/// Tell the player that their command failed, without ending the
+/// game.
+fn report_failure(err: &anyhow::Error) {
+    match err.downcast_ref::<GenerationError>() {
+        Some(GenerationError::Cancelled) => display!("Cancelled."),
+        Some(gen_err) => display!("The world fails to respond ({}). Try again.", gen_err),
+        None => display!("Something went wrong: {}", err),
+    }
+}
+
+/// Reedline handles Ctrl-C while reading input. While a command is
+/// running, it cancels the command instead.
+async fn cancel_on_ctrl_c(cancel: CancellationToken) {
+    if tokio::signal::ctrl_c().await.is_ok() {
+        cancel.cancel();
+    }
+}
 
 pub struct GameLoop {
     executor: CommandExecutor,
     state: GameState,
-    db: Rc<Database>,
+    db: Arc<Database>,
     editor: Reedline,
     prompt: DefaultPrompt,
 }
 
 impl GameLoop {
-    pub fn new(state: GameState, db: &Rc<Database>) -> GameLoop {
+    pub fn new(state: GameState, db: &Arc<Database>) -> GameLoop {
         let executor_db = db.clone();
         let loop_db = db.clone();
         let executor_logic = state.logic.clone();
@@ -31,7 +52,7 @@ impl GameLoop {
         }
     }
 
-    async fn handle_ai_command(&mut self, execution: AiCommand) -> Result<()> {
+    async fn handle_ai_command(&mut self, execution: AiCommand, streamed: bool) -> Result<()> {
         if !execution.valid {
             display!(
                 "You can't do that: {}",
@@ -41,7 +62,10 @@ impl GameLoop {
             return Ok(());
         }
 
-        display!("\n\n{}\n\n", execution.narration);
+        if !streamed {
+            display!("\n\n{}\n\n", execution.narration);
+        }
+
         if let Some(event) = execution.event {
             self.state.update(event).await?;
         }
@@ -58,14 +82,22 @@ impl GameLoop {
         Ok(())
     }
 
-    async fn handle_execution(&mut self, execution: Result<CommandExecution>) -> Result<()> {
+    /// Handle the result of a command. If the narration was already
+    /// streamed to the terminal, it is not displayed again.
+    async fn handle_execution(
+        &mut self,
+        execution: Result<CommandExecution>,
+        streamed: bool,
+    ) -> Result<()> {
         if let Ok(execution) = execution {
             match execution {
                 CommandExecution::Builtin(builtin) => self.handle_builtin(builtin).await?,
-                CommandExecution::AiCommand(exec) => self.handle_ai_command(exec).await?,
+                CommandExecution::AiCommand(exec) => {
+                    self.handle_ai_command(exec, streamed).await?
+                }
             };
         } else {
-            display!("{}", execution.unwrap_err());
+            report_failure(&execution.unwrap_err());
         }
 
         Ok(())
@@ -74,8 +106,10 @@ impl GameLoop {
     async fn handle_input(&mut self, cmd: &str) -> Result<()> {
         if !cmd.is_empty() {
             let mut stage = &self.state.current_scene;
-            let execution = self.executor.execute(&mut stage, cmd).await;
-            self.handle_execution(execution).await?;
+            let mut narration = JsonFieldStream::new("narration", StreamingDisplay::new());
+            let execution = self.executor.execute(&mut stage, cmd, &mut narration).await;
+            let streamed = narration.into_inner().finish();
+            self.handle_execution(execution, streamed).await?;
         }
 
         Ok(())
@@ -89,7 +123,14 @@ impl GameLoop {
             match sig {
                 Ok(Signal::Success(buffer)) => {
                     display!("We processed: {}", buffer);
-                    self.handle_input(&buffer).await?;
+                    let cancel = self.state.logic.new_cancellation_token();
+                    let ctrl_c = tokio::spawn(cancel_on_ctrl_c(cancel));
+                    let result = self.handle_input(&buffer).await;
+                    ctrl_c.abort();
+
+                    if let Err(err) = result {
+                        report_failure(&err);
+                    }
                 }
                 Ok(Signal::CtrlD) | Ok(Signal::CtrlC) => {
                     display!("\nAborted!");
@@ -1,3 +1,6 @@
+use crate::ai::backend::TokenSink;
+use std::io::Write;
+
 #[inline]
 pub(crate) fn display_text<S : AsRef<str>>(text: S) {
     let text = text.as_ref();
@@ -21,3 +24,107 @@ macro_rules! display {
 }
 
 pub(crate) use display;
+
+/// Prints text to the terminal as it is generated, wrapping lines at
+/// word boundaries like `display!` does.
+pub(crate) struct StreamingDisplay {
+    columns: usize,
+    column: usize,
+    word: String,
+    printed: bool,
+
+    /// Lines ended since the text started, so that it can be erased.
+    rows: u16,
+}
+
+impl StreamingDisplay {
+    pub fn new() -> StreamingDisplay {
+        let (columns, _) = crossterm::terminal::size().ok().unwrap_or((80, 25));
+
+        StreamingDisplay {
+            columns: columns.into(),
+            column: 0,
+            word: String::new(),
+            printed: false,
+            rows: 0,
+        }
+    }
+
+    fn print_word(&mut self) {
+        let len = self.word.chars().count();
+        if self.column > 0 && self.column + len > self.columns {
+            self.end_line();
+        }
+
+        print!("{}", self.word);
+        self.column += len;
+        self.word.clear();
+    }
+
+    fn end_line(&mut self) {
+        println!();
+        self.column = 0;
+        self.rows = self.rows.saturating_add(1);
+    }
+
+    fn write(&mut self, text: &str) {
+        if !self.printed {
+            println!("\n");
+            self.printed = true;
+        }
+
+        for c in text.chars() {
+            if c == '\n' {
+                self.print_word();
+                self.end_line();
+            } else if c.is_whitespace() {
+                self.print_word();
+                if self.column > 0 && self.column < self.columns {
+                    print!(" ");
+                    self.column += 1;
+                }
+            } else {
+                self.word.push(c);
+            }
+        }
+
+        let _ = std::io::stdout().flush();
+    }
+
+    /// End the streamed text. Returns whether anything was printed.
+    pub fn finish(mut self) -> bool {
+        if self.printed {
+            self.print_word();
+            println!("\n");
+        }
+
+        self.printed
+    }
+}
+
+impl TokenSink for StreamingDisplay {
+    fn token(&mut self, token: &str) {
+        self.write(token);
+    }
+
+    /// Erase the text printed so far, so that the new attempt does
+    /// not show up below the old one.
+    fn restart(&mut self) {
+        if !self.printed {
+            return;
+        }
+
+        let mut stdout = std::io::stdout();
+        let _ = crossterm::queue!(stdout, crossterm::cursor::MoveToColumn(0));
+        if self.rows > 0 {
+            let _ = crossterm::queue!(stdout, crossterm::cursor::MoveUp(self.rows));
+        }
+
+        let clear = crossterm::terminal::Clear(crossterm::terminal::ClearType::FromCursorDown);
+        let _ = crossterm::execute!(stdout, clear);
+
+        self.column = 0;
+        self.rows = 0;
+        self.word.clear();
+    }
+}
@@ -1,30 +1,32 @@
+use anyhow::anyhow;
 use async_trait::async_trait;
 use es::SSE;
 use eventsource_client as es;
 use futures::{Stream, StreamExt, TryStreamExt};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::num::NonZeroU64;
-use std::time::Duration;
 
-use crate::ai::convo::AiCreativity;
+use crate::ai::backend::{
+    BackendInfo, GenerationError, GenerationRequest, LlmBackend, TokenSink,
+};
+use crate::ai::context::estimate_tokens;
+use crate::ai::sampling::SamplerProfile;
 
 include!(concat!(env!("OUT_DIR"), "/codegen.rs"));
 
-fn creativity_to_temperature(creativity: AiCreativity) -> Option<f64> {
-    match creativity {
-        AiCreativity::Predictable => Some(0.5),
-        AiCreativity::Normal => Some(0.7),
-        AiCreativity::Creative => Some(1.0),
-    }
-}
+/// KoboldCPP's own default, used when the profile does not set one.
+const DEFAULT_SAMPLER_ORDER: [i64; 7] = [6, 0, 1, 3, 4, 2, 5];
 
 pub fn create_input(
     gen_key: String,
     prompt: &str,
     grammar: Option<String>,
     max_tokens: u64,
+    max_context_length: u64,
     retain_gramar_state: bool,
-    creativity: AiCreativity,
+    sampler: &SamplerProfile,
+    stop_sequences: &[&str],
 ) -> types::GenerationInput {
     types::GenerationInput {
         genkey: Some(gen_key),
@@ -32,31 +34,58 @@ pub fn create_input(
         grammar: grammar,
         grammar_retain_state: retain_gramar_state,
         use_default_badwordsids: false,
-        max_context_length: None,
+        max_context_length: NonZeroU64::new(max_context_length),
         max_length: NonZeroU64::new(max_tokens),
-        min_p: None,
-        mirostat: None,
-        mirostat_eta: None,
-        mirostat_tau: None,
-        rep_pen: Some(1.1),
-        temperature: creativity_to_temperature(creativity),
-        tfs: None,
-        top_a: Some(0.0),
-        top_p: Some(0.92),
-        typical: None,
-        rep_pen_range: Some(320),
-        top_k: None,
-        sampler_order: vec![6, 0, 1, 3, 4, 2, 5],
-        sampler_seed: None,
-        stop_sequence: vec!["<s>".to_string(), "</s>".to_string()],
+        min_p: sampler.min_p,
+        mirostat: sampler.mirostat.map(f64::from),
+        mirostat_eta: sampler.mirostat_eta,
+        mirostat_tau: sampler.mirostat_tau,
+        rep_pen: sampler.rep_pen,
+        temperature: sampler.temperature,
+        tfs: sampler.tfs,
+        top_a: sampler.top_a,
+        top_p: sampler.top_p,
+        typical: sampler.typical,
+        rep_pen_range: sampler.rep_pen_range,
+        top_k: sampler.top_k,
+        sampler_order: sampler
+            .sampler_order
+            .clone()
+            .unwrap_or(DEFAULT_SAMPLER_ORDER.to_vec()),
+        sampler_seed: sampler.seed.and_then(NonZeroU64::new),
+        stop_sequence: stop_sequences.iter().map(|stop| stop.to_string()).collect(),
     }
 }
 
-pub struct WrappedGenerationError(String);
+/// The first version of KoboldCPP with GBNF grammar sampling.
+const MIN_GRAMMAR_VERSION: (u32, u32) = (1, 44);
+
+#[derive(Serialize, Deserialize)]
+struct TokenCount {
+    value: usize,
+}
+
+#[derive(Deserialize)]
+struct ModelName {
+    result: String,
+}
 
-impl From<es::Error> for WrappedGenerationError {
-    fn from(value: es::Error) -> Self {
-        WrappedGenerationError(format!("{:?}", value))
+#[derive(Deserialize)]
+struct ContextLength {
+    value: u64,
+}
+
+#[derive(Deserialize)]
+struct KoboldVersion {
+    version: String,
+}
+
+/// Major and minor version, from e.g. `1.46.1`.
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.split('.').map(|part| part.parse::<u32>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(major)), Some(Ok(minor))) => Some((major, minor)),
+        _ => None,
     }
 }
 
@@ -84,6 +113,13 @@ pub trait SseGenerationExt {
         &self,
         input: types::GenerationInput,
     ) -> std::result::Result<String, es::Error>;
+
+    /// Generate text, handing every token to the sink as it arrives.
+    async fn sse_generate_streaming(
+        &self,
+        input: types::GenerationInput,
+        sink: &mut dyn TokenSink,
+    ) -> std::result::Result<String, es::Error>;
 }
 
 #[async_trait]
@@ -91,23 +127,28 @@ impl SseGenerationExt for Client {
     async fn sse_generate(
         &self,
         input: types::GenerationInput,
+    ) -> std::result::Result<String, es::Error> {
+        self.sse_generate_streaming(input, &mut |_: &str| ()).await
+    }
+
+    async fn sse_generate_streaming(
+        &self,
+        input: types::GenerationInput,
+        sink: &mut dyn TokenSink,
     ) -> std::result::Result<String, es::Error> {
         let params = serde_json::to_string(&input)?;
         let stream_url = format!("{}/extra/generate/stream", self.baseurl());
 
-        let reconnect_opts = es::ReconnectOptions::reconnect(true)
-            .retry_initial(false)
-            .delay(Duration::from_secs(1))
-            .backoff_factor(2)
-            .delay_max(Duration::from_secs(60))
-            .build();
-
         let client = es::ClientBuilder::for_url(&stream_url)?
             .header("accept", "application/json")?
             .header("Content-Type", "application/json")?
             .method("POST".to_string())
             .body(params)
-            .reconnect(reconnect_opts)
+            // Reconnecting would POST the prompt again and start a
+            // new generation, whose tokens would be appended to the
+            // ones already streamed. Failures are retried from the
+            // start by the conversation instead.
+            .reconnect(es::ReconnectOptions::reconnect(false).build())
             .build();
 
         let mut stream = create_response_stream(client);
@@ -116,7 +157,10 @@ impl SseGenerationExt for Client {
         loop {
             let maybe_token = stream.try_next().await;
             match maybe_token {
-                Ok(Some(token)) => response.push_str(&token),
+                Ok(Some(token)) => {
+                    sink.token(&token);
+                    response.push_str(&token);
+                }
                 Err(es::Error::Eof) => break,
                 Err(err) => return Err(err),
                 _ => (),
@@ -126,3 +170,103 @@ impl SseGenerationExt for Client {
         Ok(response)
     }
 }
+
+impl Client {
+    async fn get_json<T: DeserializeOwned>(&self, path: &str) -> anyhow::Result<T> {
+        let url = format!("{}{}", self.baseurl(), path);
+        let resp = self.client().get(&url).send().await?.error_for_status()?;
+        Ok(resp.json::<T>().await?)
+    }
+}
+
+#[async_trait]
+impl LlmBackend for Client {
+    async fn generate(&self, request: &GenerationRequest<'_>) -> anyhow::Result<String> {
+        self.generate_streaming(request, &mut |_: &str| ()).await
+    }
+
+    async fn generate_streaming(
+        &self,
+        request: &GenerationRequest<'_>,
+        sink: &mut dyn TokenSink,
+    ) -> anyhow::Result<String> {
+        let input = create_input(
+            request.gen_key.to_string(),
+            request.prompt,
+            request.grammar.map(String::from),
+            request.max_tokens,
+            request.max_context_length,
+            request.retain_grammar_state,
+            request.sampler,
+            request.stop_sequences,
+        );
+
+        let response = self
+            .sse_generate_streaming(input, sink)
+            .await
+            .map_err(GenerationError::from)?;
+
+        Ok(response)
+    }
+
+    async fn abort(&self, gen_key: &str) -> anyhow::Result<()> {
+        let url = format!("{}/extra/abort", self.baseurl());
+        let body = serde_json::json!({ "genkey": gen_key });
+
+        self.client()
+            .post(&url)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn count_tokens(&self, text: &str) -> anyhow::Result<usize> {
+        let url = format!("{}/extra/tokencount", self.baseurl());
+        let body = serde_json::json!({ "prompt": text });
+
+        let response = self.client().post(&url).json(&body).send().await;
+
+        // Older versions of KoboldCPP do not have the endpoint.
+        // Estimating is better than refusing to generate anything.
+        match response {
+            Ok(resp) if resp.status().is_success() => Ok(resp.json::<TokenCount>().await?.value),
+            _ => Ok(estimate_tokens(text)),
+        }
+    }
+
+    async fn probe(&self) -> anyhow::Result<BackendInfo> {
+        let model = self.get_json::<ModelName>("/v1/model").await.map_err(|err| {
+            anyhow!(
+                "could not reach KoboldCPP at {} ({}). Is it running? \
+                 The endpoint is set with connection.kobold_endpoint.",
+                self.baseurl(),
+                err
+            )
+        })?;
+
+        // Both of these are extensions that older versions lack.
+        let context = self.get_json::<ContextLength>("/extra/true_max_context_length");
+        let context = context.await.ok();
+        let version = self.get_json::<KoboldVersion>("/extra/version").await.ok();
+
+        // Without the version, there is no telling whether grammars
+        // work. The first generation will find out.
+        let grammar_support = match version {
+            Some(version) => parse_version(&version.version)
+                .map(|version| version >= MIN_GRAMMAR_VERSION),
+            None => {
+                tracing::warn!(endpoint = %self.baseurl(), "KoboldCPP did not report its version");
+                None
+            }
+        };
+
+        Ok(BackendInfo {
+            model: Some(model.result),
+            max_context_length: context.map(|context| context.value),
+            grammar_support,
+        })
+    }
+}
@@ -1,10 +1,22 @@
+use ai::backend::cache::{CacheSettings, CachingBackend};
+use ai::backend::replay::{RecordingBackend, ReplayBackend};
+use ai::backend::LlmBackend;
+use ai::context::{ContextBudget, TrimPolicy};
+use ai::convo::{ConversationSettings, RetryPolicy};
+use ai::generator::{BackendRoutes, ConversationBackend, ConversationKind};
 use ai::logic::AiLogic;
-use anyhow::Result;
+use ai::sampling::{PromptKind, SamplerProfile, SamplerSettings};
+use ai::template::ChatTemplate;
+use ai::transcript::{self, TranscriptLogger};
+use anyhow::{anyhow, Result};
 use config::Config;
 use game_loop::GameLoop;
 use models::world::scenes::{root_scene_id, Stage};
 use state::GameState;
-use std::{io::stdout, rc::Rc, str::FromStr, time::Duration};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use std::{collections::HashMap, io::stdout, str::FromStr, sync::Arc, time::Duration};
 
 use arangors::Connection;
 
@@ -18,16 +30,94 @@ mod io;
 mod kobold_api;
 
 mod models;
+mod openai_api;
 mod state;
+mod telemetry;
 
 use crate::{db::Database, models::world::scenes::StageOrStub};
-use kobold_api::Client;
+use openai_api::{GrammarSupport, OpenAiApi};
 
-struct GameConfig {
+/// Which LLM server the game talks to.
+enum BackendKind {
+    Kobold,
+    OpenAi,
+    /// Serve responses from a fixture file, without a model.
+    Replay,
+}
+
+impl FromStr for BackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_ref() {
+            "kobold" | "koboldcpp" => Ok(BackendKind::Kobold),
+            "openai" => Ok(BackendKind::OpenAi),
+            "replay" => Ok(BackendKind::Replay),
+            _ => Err(anyhow::anyhow!("unknown backend: {}", value)),
+        }
+    }
+}
+
+struct OpenAiConfig {
+    pub endpoint: String,
+    pub model: String,
+    pub api_key: Option<String>,
+    pub api: OpenAiApi,
+    pub grammar_support: GrammarSupport,
+}
+
+/// An LLM server, and how to talk to it.
+struct BackendConfig {
+    pub kind: BackendKind,
     pub kobold_endpoint: String,
+    pub openai: OpenAiConfig,
+
+    /// Overrides `ai.chat_template` for this backend.
+    pub chat_template: Option<ChatTemplate>,
+
+    /// Overrides `ai.context_budget` for this backend.
+    pub context_budget: Option<u64>,
+}
+
+struct GameConfig {
+    /// The backend of every conversation that is not routed to one of
+    /// the named backends.
+    pub backend: BackendConfig,
+    pub backends: HashMap<String, BackendConfig>,
+    pub conversation_backends: HashMap<ConversationKind, String>,
     pub arangodb_endpoint: String,
+    pub conversation: ConversationSettings,
+
+    /// The context budget from the config. If unset, the context
+    /// length of the loaded model is used.
+    pub context_budget: Option<u64>,
+
+    /// Fixture file read by the replay backend.
+    pub fixtures: String,
+
+    /// If set, every generation is also recorded to this fixture file.
+    pub record_fixtures: Option<String>,
+
+    /// On-disk cache of LLM responses, if enabled.
+    pub cache: Option<CacheSettings>,
+
+    /// JSON-lines file that generation metrics are appended to.
+    pub metrics_file: Option<String>,
+
+    /// Directory that a transcript of every session is written to.
+    pub transcript_dir: Option<String>,
+
+    /// Seed for a new world. A random one is used if unset.
+    pub world_seed: Option<u64>,
+
+    /// How many people and items of a scene are generated at once.
+    pub parallel_generations: usize,
 }
 
+/// Where `ai-game transcript` looks for sessions, if the config does
+/// not say.
+const DEFAULT_TRANSCRIPT_DIR: &str = "transcripts";
+
 // Needs to be moved somewhere else.
 async fn store_root_scene(db: &Database, state: &mut GameState) -> Result<Stage> {
     let mut created_scene: crate::models::ContentContainer = state
@@ -61,6 +151,48 @@ async fn load_root_scene(db: &Database, state: &mut GameState) -> Result<()> {
     Ok(())
 }
 
+/// Read the settings of a backend from a section of the config, e.g.
+/// `connection` or `backends.fast`.
+fn load_backend_config(settings: &Config, section: &str) -> Result<BackendConfig> {
+    let get = |key: &str| settings.get::<Option<String>>(&format!("{}.{}", section, key));
+
+    let kind = get("backend")?
+        .map(|backend| BackendKind::from_str(&backend))
+        .transpose()?
+        .unwrap_or(BackendKind::Kobold);
+
+    let kobold_endpoint = get("kobold_endpoint")?
+        .unwrap_or("http://127.0.0.1:5001/api".to_string());
+
+    let openai = OpenAiConfig {
+        endpoint: get("openai_endpoint")?.unwrap_or("http://127.0.0.1:8080/v1".to_string()),
+        model: get("openai_model")?.unwrap_or("default".to_string()),
+        api_key: get("openai_api_key")?,
+        api: get("openai_api")?
+            .map(|api| OpenAiApi::from_str(&api))
+            .transpose()?
+            .unwrap_or(OpenAiApi::Completions),
+        grammar_support: get("openai_grammar")?
+            .map(|support| GrammarSupport::from_str(&support))
+            .transpose()?
+            .unwrap_or(GrammarSupport::Gbnf),
+    };
+
+    let chat_template = get("chat_template")?
+        .map(|template| ChatTemplate::from_str(&template))
+        .transpose()?;
+
+    let context_budget = settings.get::<Option<u64>>(&format!("{}.context_budget", section))?;
+
+    Ok(BackendConfig {
+        kind,
+        kobold_endpoint,
+        openai,
+        chat_template,
+        context_budget,
+    })
+}
+
 fn load_config() -> Result<GameConfig> {
     let settings = Config::builder()
         .add_source(config::File::with_name("config.toml"))
@@ -68,40 +200,361 @@ fn load_config() -> Result<GameConfig> {
         .build()
         .unwrap();
 
-    let kobold_endpoint = settings
-        .get::<Option<String>>("connection.kobold_endpoint")?
-        .unwrap_or("http://127.0.0.1:5001/api".to_string());
-
     let arangodb_endpoint = settings
         .get::<Option<String>>("connection.arangodb_endpoint")?
         .unwrap_or("http://localhost:8529".to_string());
 
+    let backend = load_backend_config(&settings, "connection")?;
+
+    let backend_names = settings
+        .get::<Option<HashMap<String, config::Value>>>("backends")?
+        .unwrap_or_default();
+
+    let backends = backend_names
+        .into_keys()
+        .map(|name| {
+            let backend = load_backend_config(&settings, &format!("backends.{}", name))?;
+            Ok((name, backend))
+        })
+        .collect::<Result<HashMap<_, _>>>()?;
+
+    let conversation_backends = settings
+        .get::<Option<HashMap<ConversationKind, String>>>("ai.conversation_backends")?
+        .unwrap_or_default();
+
+    for (kind, name) in &conversation_backends {
+        if !backends.contains_key(name) {
+            return Err(anyhow!("unknown backend for {:?} conversation: {}", kind, name));
+        }
+    }
+
+    let fixtures = settings
+        .get::<Option<String>>("connection.fixtures")?
+        .unwrap_or("fixtures.json".to_string());
+
+    let record_fixtures = settings.get::<Option<String>>("connection.record_fixtures")?;
+
+    let chat_template = settings
+        .get::<Option<String>>("ai.chat_template")?
+        .map(|template| ChatTemplate::from_str(&template))
+        .transpose()?
+        .unwrap_or_default();
+
+    let default_budget = ContextBudget::default();
+    let context_budget = settings.get::<Option<u64>>("ai.context_budget")?;
+    let context = ContextBudget {
+        max_tokens: context_budget.unwrap_or(default_budget.max_tokens),
+        policy: settings
+            .get::<Option<String>>("ai.context_policy")?
+            .map(|policy| TrimPolicy::from_str(&policy))
+            .transpose()?
+            .unwrap_or(default_budget.policy),
+    };
+
+    let sampler_profiles = settings
+        .get::<Option<HashMap<String, SamplerProfile>>>("samplers")?
+        .unwrap_or_default();
+
+    let prompt_samplers = settings
+        .get::<Option<HashMap<PromptKind, String>>>("ai.prompt_samplers")?
+        .unwrap_or_default();
+
+    let default_retry = RetryPolicy::default();
+    let delay = |key: &str, default: Duration| -> Result<Duration> {
+        let seconds = settings.get::<Option<f64>>(&format!("ai.retry.{}", key))?;
+        Ok(seconds.map_or(default, Duration::from_secs_f64))
+    };
+
+    let retry = RetryPolicy {
+        max_attempts: settings
+            .get::<Option<u32>>("ai.retry.max_attempts")?
+            .unwrap_or(default_retry.max_attempts)
+            .max(1),
+        initial_delay: delay("initial_delay", default_retry.initial_delay)?,
+        backoff_factor: settings
+            .get::<Option<u32>>("ai.retry.backoff_factor")?
+            .unwrap_or(default_retry.backoff_factor),
+        max_delay: delay("max_delay", default_retry.max_delay)?,
+    };
+
+    let conversation = ConversationSettings {
+        template: chat_template,
+        context,
+        samplers: SamplerSettings::new(sampler_profiles, prompt_samplers)?,
+        retry,
+        world_seed: None,
+        transcript: None,
+    };
+
+    let cache = match settings.get::<Option<bool>>("cache.enabled")? {
+        Some(true) => Some(CacheSettings {
+            path: settings
+                .get::<Option<String>>("cache.path")?
+                .unwrap_or("llm_cache".to_string())
+                .into(),
+            bypass: settings.get::<Option<bool>>("cache.bypass")?.unwrap_or(false),
+            max_age: settings
+                .get::<Option<u64>>("cache.max_age")?
+                .map(Duration::from_secs),
+        }),
+        _ => None,
+    };
+
+    let metrics_file = settings.get::<Option<String>>("telemetry.metrics_file")?;
+    let transcript_dir = settings.get::<Option<String>>("telemetry.transcript_dir")?;
+    let world_seed = settings.get::<Option<u64>>("world.seed")?;
+    let parallel_generations = settings
+        .get::<Option<usize>>("ai.parallel_generations")?
+        .unwrap_or(1);
+
     Ok(GameConfig {
+        backend,
+        backends,
+        conversation_backends,
         arangodb_endpoint,
-        kobold_endpoint,
+        conversation,
+        context_budget,
+        fixtures,
+        record_fixtures,
+        cache,
+        metrics_file,
+        transcript_dir,
+        world_seed,
+        parallel_generations,
     })
 }
 
+/// Check that an LLM backend is up and able to run the game. Returns
+/// the context budget for conversations with it: the configured one,
+/// fitted to the loaded model.
+async fn probe_backend(
+    backend: &dyn LlmBackend,
+    config: &BackendConfig,
+    budget: Option<u64>,
+) -> Result<Option<u64>> {
+    let info = backend.probe().await?;
+
+    if let Some(model) = &info.model {
+        println!("  Model: {}", model);
+    }
+
+    match (info.grammar_support, &config.kind) {
+        (Some(false), BackendKind::Kobold) => {
+            return Err(anyhow!(
+                "this version of KoboldCPP ({}) does not support grammar sampling, \
+                 which the game needs. Please upgrade KoboldCPP.",
+                config.kobold_endpoint
+            ));
+        }
+        (Some(false), _) => println!(
+            "  Warning: grammars are disabled, so the model may not generate valid JSON."
+        ),
+        (None, BackendKind::Kobold) => println!(
+            "  Warning: could not tell the KoboldCPP version. Grammar sampling needs 1.44 \
+             or later."
+        ),
+        _ => (),
+    }
+
+    let Some(detected) = info.max_context_length else {
+        return Ok(budget);
+    };
+
+    let budget = match budget {
+        Some(budget) if budget > detected => {
+            println!(
+                "  Warning: context budget {} is larger than the model's context length.",
+                budget
+            );
+            detected
+        }
+        Some(budget) => budget,
+        None => detected,
+    };
+
+    println!("  Context length: {} (budget: {})", detected, budget);
+    Ok(Some(budget))
+}
+
+fn describe_backend(config: &GameConfig, backend: &BackendConfig) -> String {
+    match backend.kind {
+        BackendKind::Kobold => format!("Kobold API: {}", backend.kobold_endpoint),
+        BackendKind::OpenAi => format!("OpenAI API: {}", backend.openai.endpoint),
+        BackendKind::Replay => format!("Replaying fixtures: {}", config.fixtures),
+    }
+}
+
+/// Identifies a backend in the response cache. Backends that could
+/// answer the same prompt differently get different names.
+fn cache_name(config: &GameConfig, backend: &BackendConfig) -> String {
+    match backend.kind {
+        BackendKind::Kobold => format!("kobold {}", backend.kobold_endpoint),
+        BackendKind::OpenAi => format!(
+            "openai {} {} {:?}",
+            backend.openai.endpoint, backend.openai.model, backend.openai.api
+        ),
+        BackendKind::Replay => format!("replay {}", config.fixtures),
+    }
+}
+
+/// Connect to a backend, and work out the settings of conversations
+/// with it.
+async fn setup_backend(
+    config: &GameConfig,
+    backend_config: &BackendConfig,
+    conversation: &ConversationSettings,
+) -> Result<ConversationBackend> {
+    let backend = create_backend(config, backend_config)?;
+
+    let budget = backend_config.context_budget.or(config.context_budget);
+    let budget = probe_backend(backend.as_ref(), backend_config, budget).await?;
+
+    let mut settings = conversation.clone();
+    if let Some(template) = backend_config.chat_template {
+        settings.template = template;
+    }
+
+    if let Some(budget) = budget {
+        settings.context.max_tokens = budget;
+    }
+
+    Ok(ConversationBackend { backend, settings })
+}
+
+/// Set up the default backend, and every named backend that a
+/// conversation is routed to.
+async fn setup_routes(
+    config: &GameConfig,
+    conversation: &ConversationSettings,
+) -> Result<BackendRoutes> {
+    println!("{}", describe_backend(config, &config.backend));
+    let default = setup_backend(config, &config.backend, conversation).await?;
+    let mut routes = BackendRoutes::new(default);
+
+    let mut named: HashMap<&str, ConversationBackend> = HashMap::new();
+    for (kind, name) in &config.conversation_backends {
+        if !named.contains_key(name.as_str()) {
+            let backend_config = &config.backends[name];
+            println!("[{}] {}", name, describe_backend(config, backend_config));
+            let backend = setup_backend(config, backend_config, conversation).await?;
+            named.insert(name.as_str(), backend);
+        }
+
+        routes.route(*kind, named[name.as_str()].clone());
+    }
+
+    Ok(routes)
+}
+
+async fn connect_database(config: &GameConfig) -> Result<Database> {
+    let conn = Connection::establish_without_auth(&config.arangodb_endpoint)
+        .await
+        .map_err(|err| {
+            anyhow!(
+                "could not connect to ArangoDB at {} ({}). Is it running? \
+                 The endpoint is set with connection.arangodb_endpoint.",
+                config.arangodb_endpoint,
+                err
+            )
+        })?;
+
+    Database::new(conn, "test_world").await
+}
+
+/// Pretty-print the transcript of a session, by default the latest.
+fn print_transcript(config: &GameConfig, session: Option<&str>) -> Result<()> {
+    let root = config
+        .transcript_dir
+        .as_deref()
+        .unwrap_or(DEFAULT_TRANSCRIPT_DIR);
+
+    let session_dir = transcript::find_session(Path::new(root), session)?;
+    transcript::print_session(&session_dir)
+}
+
+fn random_seed() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+fn create_backend(config: &GameConfig, backend: &BackendConfig) -> Result<Arc<dyn LlmBackend>> {
+    let cache_name = cache_name(config, backend);
+    let backend: Box<dyn LlmBackend> = match backend.kind {
+        BackendKind::Kobold => {
+            let base_client = reqwest::ClientBuilder::new()
+                .connect_timeout(Duration::from_secs(180))
+                .pool_idle_timeout(Duration::from_secs(180))
+                .timeout(Duration::from_secs(180))
+                .build()?;
+
+            Box::new(kobold_api::Client::new_with_client(
+                &backend.kobold_endpoint,
+                base_client,
+            ))
+        }
+        BackendKind::OpenAi => Box::new(openai_api::Client::new(
+            &backend.openai.endpoint,
+            &backend.openai.model,
+            backend.openai.api_key.clone(),
+            backend.openai.api,
+            backend.openai.grammar_support,
+        )),
+        BackendKind::Replay => Box::new(ReplayBackend::from_file(&config.fixtures)?),
+    };
+
+    let backend: Box<dyn LlmBackend> = match &config.cache {
+        Some(cache) => Box::new(CachingBackend::new(backend, &cache_name, cache.clone())?),
+        None => backend,
+    };
+
+    let backend: Box<dyn LlmBackend> = match &config.record_fixtures {
+        Some(path) => Box::new(RecordingBackend::new(backend, path)?),
+        None => backend,
+    };
+
+    Ok(Arc::from(backend))
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let config = load_config()?;
-    println!("Kobold API: {}", config.kobold_endpoint);
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("transcript") => return print_transcript(&config, args.get(1).map(String::as_str)),
+        Some(command) => return Err(anyhow!("unknown command: {}", command)),
+        None => (),
+    }
+
+    telemetry::init(config.metrics_file.as_deref())?;
+
     println!("ArangoDB: {}", config.arangodb_endpoint);
+    let db = Arc::new(connect_database(&config).await?);
+
+    // Everything generated from here on, including conversation
+    // keys, is derived from the world seed.
+    let world = db
+        .start_session(config.world_seed.unwrap_or_else(random_seed))
+        .await?;
+
+    models::seed_keys(world.seed, world.session());
+    let mut conversation = config.conversation.clone();
+    conversation.world_seed = Some(world.seed);
+
+    if let Some(dir) = &config.transcript_dir {
+        conversation.transcript = Some(Arc::new(TranscriptLogger::create(dir)?));
+    }
+
+    let routes = setup_routes(&config, &conversation).await?;
+    println!();
+
+    println!("World seed: {} (session {})", world.seed, world.session());
+    if let Some(transcript) = &conversation.transcript {
+        println!("Transcript: {}", transcript.session_dir().display());
+    }
     println!();
 
-    let base_client = reqwest::ClientBuilder::new()
-        .connect_timeout(Duration::from_secs(180))
-        .pool_idle_timeout(Duration::from_secs(180))
-        .timeout(Duration::from_secs(180))
-        .build()?;
-
-    let conn = Connection::establish_without_auth(config.arangodb_endpoint).await?;
-    let client = Rc::new(Client::new_with_client(
-        &config.kobold_endpoint,
-        base_client,
-    ));
-    let db = Rc::new(Database::new(conn, "test_world").await?);
-    let logic = Rc::new(AiLogic::new(client, &db));
+    let logic = AiLogic::new(&routes, &db).with_parallel_generations(config.parallel_generations);
+    let logic = Arc::new(logic);
 
     let mut state = GameState {
         logic,
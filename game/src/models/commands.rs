@@ -1,6 +1,7 @@
 use std::fmt::Display;
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
 use strum::{EnumString, EnumVariantNames};
 use thiserror::Error;
 use gbnf::prelude::*;
@@ -56,13 +57,14 @@ pub struct VerbAndTargetEntry {
     pub target: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Gbnf)]
 #[serde(rename_all = "camelCase")]
 pub struct RawCommandExecution {
     pub valid: bool,
     pub reason: Option<String>,
     pub narration: String,
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[gbnf(omittable)]
     pub event: Option<RawCommandEvent>,
 }
 
@@ -82,9 +84,50 @@ impl RawCommandExecution {
 pub struct RawCommandEvent {
     pub event_name: String,
     pub applies_to: String,
+    #[serde(deserialize_with = "string_or_number")]
     pub parameter: String,
 }
 
+/// Some events have numeric parameters, which the grammar makes the
+/// LLM generate as JSON numbers. They are parsed along with the rest
+/// of the event, so they are read as strings here.
+fn string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    match Value::deserialize(deserializer)? {
+        Value::String(value) => Ok(value),
+        Value::Null => Ok(String::new()),
+        value => Ok(value.to_string()),
+    }
+}
+
+/// The grammar of a raw command event: the shape of a
+/// `RawCommandEvent`, but with event names restricted to the events
+/// of `CommandEvent`, and parameters of the right type. The response
+/// is still read as a `RawCommandEvent`, which is lenient enough for
+/// backends that cannot enforce grammars.
+#[allow(dead_code)]
+#[derive(Deserialize, Gbnf)]
+#[serde(tag = "eventName", rename_all = "snake_case", rename_all_fields = "camelCase")]
+enum RawCommandEventGrammar {
+    Narration { applies_to: String, parameter: String },
+    LookAtEntity { applies_to: String, parameter: String },
+    ChangeScene { applies_to: String, parameter: String },
+    TakeDamage {
+        applies_to: String,
+        #[gbnf(range = 0..=1000)]
+        parameter: u32,
+    },
+    Stand { applies_to: String, parameter: String },
+    Sit { applies_to: String, parameter: String },
+    Prone { applies_to: String, parameter: String },
+    Crouch { applies_to: String, parameter: String },
+}
+
+impl AsGbnf for RawCommandEvent {
+    fn to_gbnf() -> GbnfFieldType {
+        RawCommandEventGrammar::to_gbnf()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, EnumString, EnumVariantNames)]
 #[strum(serialize_all = "snake_case")]
 #[serde(rename_all = "snake_case")]
@@ -253,3 +296,52 @@ impl EventCoherenceFailure {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use strum::VariantNames;
+
+    #[test]
+    fn event_grammar_allows_every_recognized_event() {
+        let alternatives = match RawCommandEventGrammar::to_gbnf() {
+            GbnfFieldType::Union(union) => union.alternatives,
+            _ => panic!("event grammar is not a union"),
+        };
+
+        let mut grammar_events: Vec<String> = alternatives
+            .into_iter()
+            .map(|alternative| {
+                let tag = alternative.as_complex().fields.remove(0);
+                assert_eq!(tag.field_name, "eventName");
+                match tag.field_type {
+                    GbnfFieldType::Limited(limited) => limited.values.join(""),
+                    _ => panic!("event name is not a literal"),
+                }
+            })
+            .collect();
+
+        let mut events: Vec<String> = CommandEvent::VARIANTS
+            .iter()
+            .filter(|&&name| name != "unrecognized")
+            .map(|name| name.to_string())
+            .collect();
+
+        grammar_events.sort();
+        events.sort();
+        assert_eq!(grammar_events, events);
+    }
+
+    #[test]
+    fn command_grammars_match_their_types() {
+        gbnf::check_shape::<ParsedCommands>(ParsedCommands::to_grammar()).unwrap();
+        gbnf::check_shape::<VerbsResponse>(VerbsResponse::to_grammar()).unwrap();
+        gbnf::check_shape::<RawCommandExecution>(RawCommandExecution::to_grammar()).unwrap();
+    }
+
+    #[test]
+    fn command_grammars_do_not_match_other_types() {
+        let mismatch = gbnf::check_shape::<VerbsResponse>(ParsedCommands::to_grammar());
+        assert!(matches!(mismatch, Err(gbnf::ShapeError::Mismatch { .. })));
+    }
+}
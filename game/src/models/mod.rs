@@ -2,6 +2,8 @@ use self::world::items::Item;
 use self::world::people::Person;
 use self::world::scenes::{Scene, SceneStub};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
 use uuid::Uuid;
 
 // Has to come before any module declarations!
@@ -43,8 +45,50 @@ pub mod coherence;
 pub mod commands;
 pub mod world;
 
+/// Where keys come from once the world seed is known. Keys are hashed
+/// from the seed, the session, and a counter, so the same seed
+/// produces the same keys in the same order.
+struct KeySeed {
+    seed: u64,
+    session: u64,
+    counter: u64,
+}
+
+impl KeySeed {
+    fn next_uuid(&mut self) -> Uuid {
+        let mut hasher = Sha256::new();
+        hasher.update(self.seed.to_le_bytes());
+        hasher.update(self.session.to_le_bytes());
+        hasher.update(self.counter.to_le_bytes());
+        self.counter += 1;
+
+        let hash = hasher.finalize();
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&hash[..16]);
+        uuid::Builder::from_random_bytes(bytes).into_uuid()
+    }
+}
+
+static KEY_SEED: Mutex<Option<KeySeed>> = Mutex::new(None);
+
+/// Generate all keys from now on from the world seed. Each session of
+/// the world gets its own sequence of keys, so keys created in later
+/// sessions do not collide with the ones created before.
+pub fn seed_keys(seed: u64, session: u64) {
+    let mut key_seed = KEY_SEED.lock().unwrap();
+    *key_seed = Some(KeySeed {
+        seed,
+        session,
+        counter: 0,
+    });
+}
+
 pub fn new_uuid_string() -> String {
-    let uuid = Uuid::now_v7();
+    let uuid = match KEY_SEED.lock().unwrap().as_mut() {
+        Some(key_seed) => key_seed.next_uuid(),
+        None => Uuid::now_v7(),
+    };
+
     let mut uuid_str = Uuid::encode_buffer();
     let uuid_str = uuid.hyphenated().encode_lower(&mut uuid_str);
     uuid_str.to_owned()
@@ -245,3 +289,53 @@ impl Insertable for Entity {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_seed(seed: u64, session: u64) -> KeySeed {
+        KeySeed {
+            seed,
+            session,
+            counter: 0,
+        }
+    }
+
+    fn uuids(mut key_seed: KeySeed, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| key_seed.next_uuid().hyphenated().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_keys() {
+        let keys = uuids(key_seed(42, 1), 3);
+        assert_eq!(keys, uuids(key_seed(42, 1), 3));
+
+        // Every key in the sequence is new.
+        assert_ne!(keys[0], keys[1]);
+        assert_ne!(keys[1], keys[2]);
+    }
+
+    #[test]
+    fn seeds_and_sessions_get_their_own_keys() {
+        let keys = uuids(key_seed(42, 1), 3);
+        assert!(uuids(key_seed(43, 1), 3)
+            .iter()
+            .all(|key| !keys.contains(key)));
+        assert!(uuids(key_seed(42, 2), 3)
+            .iter()
+            .all(|key| !keys.contains(key)));
+    }
+
+    #[test]
+    fn keys_are_stable_across_builds() {
+        let key = key_seed(42, 1).next_uuid();
+        assert_eq!(key.get_version_num(), 4);
+        assert_eq!(
+            key.hyphenated().to_string(),
+            "d8c6127c-1cb3-4895-b428-e124f4d054fb"
+        );
+    }
+}
@@ -1,11 +1,13 @@
 use crate::models::new_uuid_string;
+use gbnf::prelude::*;
+use gbnf_derive::Gbnf;
 use serde::{Deserialize, Serialize};
 use strum::{Display, EnumString, EnumVariantNames};
 use tabled::Tabled;
 
 use super::super::Insertable;
 
-#[derive(Serialize, Deserialize, Debug, EnumString, EnumVariantNames, Clone, Display)]
+#[derive(Serialize, Deserialize, Debug, EnumString, EnumVariantNames, Clone, Display, Gbnf)]
 #[strum(serialize_all = "snake_case")]
 #[serde(rename_all = "snake_case")]
 pub enum Category {
@@ -3,3 +3,42 @@ pub mod raw;
 pub mod items;
 pub mod people;
 pub mod scenes;
+
+use serde::{Deserialize, Serialize};
+
+/// Information about the world as a whole, stored once per world.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorldInfo {
+    /// Drives the sampler seeds and keys of everything generated for
+    /// the world.
+    #[serde(with = "seed_string")]
+    pub seed: u64,
+
+    /// How many times the world has been loaded, including the time
+    /// it was created.
+    pub sessions: u64,
+}
+
+impl WorldInfo {
+    /// The current session, counting from 0 for the session that
+    /// created the world.
+    pub fn session(&self) -> u64 {
+        self.sessions.saturating_sub(1)
+    }
+}
+
+/// ArangoDB stores numbers as doubles, which cannot hold every u64, so
+/// the seed is stored as a string.
+mod seed_string {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(seed: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&seed.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
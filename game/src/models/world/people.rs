@@ -2,11 +2,13 @@ use crate::models::new_uuid_string;
 use tabled::Tabled;
 
 use super::super::Insertable;
+use gbnf::prelude::*;
+use gbnf_derive::Gbnf;
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use strum::{EnumString, EnumVariantNames};
 
-#[derive(Serialize, Deserialize, Debug, EnumString, EnumVariantNames, Clone)]
+#[derive(Serialize, Deserialize, Debug, EnumString, EnumVariantNames, Clone, Gbnf)]
 #[serde(rename_all = "snake_case")]
 #[strum(serialize_all = "snake_case")]
 pub enum Sex {
@@ -23,7 +25,7 @@ impl Display for Sex {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, EnumString, EnumVariantNames, Clone)]
+#[derive(Serialize, Deserialize, Debug, EnumString, EnumVariantNames, Clone, Gbnf)]
 #[serde(rename_all = "snake_case")]
 #[strum(serialize_all = "snake_case")]
 pub enum Gender {
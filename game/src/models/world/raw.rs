@@ -2,8 +2,13 @@
 /// needs to be filled in with extra information to be fully complete.
 /// Raw information does not have db IDs, or most of the other info an
 /// entity might want.
+use gbnf::prelude::*;
+use gbnf_derive::Gbnf;
 use serde::{Deserialize, Serialize};
 
+use super::items::Category;
+use super::people::{Gender, Sex};
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct World {
     pub name: String,
@@ -12,8 +17,9 @@ pub struct World {
 /// Contains everything needed to generate a DB-backed scene. The info
 /// here is a seed for the full creation, which spiders out like a
 /// tree structure.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Gbnf)]
 pub struct SceneSeed {
+    #[gbnf(max_len = 100)]
     pub name: String,
     pub region: String,
     pub description: String,
@@ -23,39 +29,46 @@ pub struct SceneSeed {
     pub exits: Vec<ExitSeed>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Gbnf)]
 pub struct ExitSeed {
+    #[gbnf(max_len = 100)]
     pub name: String,
     pub region: String,
     pub direction: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Gbnf)]
 pub struct PersonSeed {
+    #[gbnf(max_len = 100)]
     pub name: String,
     pub occupation: String,
     pub race: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Gbnf)]
 #[serde(rename_all = "camelCase")]
 pub struct PersonDetails {
     pub description: String,
-    pub sex: String,
-    pub gender: String,
+    pub sex: Sex,
+    pub gender: Gender,
+
+    // Long-lived races can be centuries old.
+    #[gbnf(range = 0..=1000)]
     pub age: u32,
+
     pub residence: String,
     pub items: Vec<ItemSeed>,
     pub current_activity: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Gbnf)]
 pub struct ItemSeed {
+    #[gbnf(max_len = 100)]
     pub name: String,
-    pub category: String,
+    pub category: Category,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Gbnf)]
 #[serde(rename_all = "camelCase")]
 pub struct ItemDetails {
     pub description: String,
@@ -66,10 +79,23 @@ pub struct ItemDetails {
     pub secret_attributes: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Gbnf)]
 pub struct PropSeed {
+    #[gbnf(max_len = 100)]
     pub name: String,
     pub description: String,
     pub features: Vec<String>,
     pub possible_interactions: Vec<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn world_grammars_match_their_types() {
+        gbnf::check_shape::<SceneSeed>(SceneSeed::to_grammar()).unwrap();
+        gbnf::check_shape::<ExitSeed>(ExitSeed::to_grammar()).unwrap();
+        gbnf::check_shape::<PersonDetails>(PersonDetails::to_grammar()).unwrap();
+    }
+}
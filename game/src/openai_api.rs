@@ -0,0 +1,720 @@
+use anyhow::anyhow;
+use async_trait::async_trait;
+use es::SSE;
+use eventsource_client as es;
+use futures::{Stream, StreamExt, TryStreamExt};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::str::FromStr;
+
+use crate::ai::backend::{
+    BackendInfo, GenerationError, GenerationRequest, LlmBackend, TokenSink,
+};
+
+/// Sent by the server as the final event of a streamed response.
+const STREAM_DONE: &str = "[DONE]";
+
+/// Which of the OpenAI endpoints to generate text with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpenAiApi {
+    /// `/chat/completions`. The server applies the model's chat
+    /// template to the messages itself, so the configured template
+    /// is not used.
+    Chat,
+
+    /// `/completions`. The prompt is sent to the model as-is.
+    Completions,
+}
+
+impl FromStr for OpenAiApi {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_ref() {
+            "chat" => Ok(OpenAiApi::Chat),
+            "completions" | "completion" => Ok(OpenAiApi::Completions),
+            _ => Err(anyhow::anyhow!("unknown OpenAI API type: {}", value)),
+        }
+    }
+}
+
+/// How (or if) the server accepts a GBNF grammar or a JSON Schema to
+/// constrain the output. The OpenAI API has no standard way of doing
+/// this, so every server does it a little differently.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GrammarSupport {
+    /// `grammar` parameter: llama.cpp server and its derivatives.
+    Gbnf,
+
+    /// `guided_grammar` parameter: vLLM.
+    GuidedGrammar,
+
+    /// `json_schema` parameter: llama.cpp server.
+    JsonSchema,
+
+    /// `guided_json` parameter: vLLM.
+    GuidedJson,
+
+    /// `response_format` of type `json_schema`: OpenAI's structured
+    /// outputs, also understood by LM Studio and Ollama.
+    ResponseFormat,
+
+    /// The server cannot be constrained. Grammars are dropped.
+    None,
+}
+
+/// Every parameter that one of the above adds to the request.
+const CONSTRAINT_PARAMS: [&str; 5] = [
+    "grammar",
+    "guided_grammar",
+    "json_schema",
+    "guided_json",
+    "response_format",
+];
+
+impl FromStr for GrammarSupport {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_ref() {
+            "gbnf" | "grammar" => Ok(GrammarSupport::Gbnf),
+            "guided" | "guided_grammar" => Ok(GrammarSupport::GuidedGrammar),
+            "json_schema" | "schema" => Ok(GrammarSupport::JsonSchema),
+            "guided_json" => Ok(GrammarSupport::GuidedJson),
+            "response_format" => Ok(GrammarSupport::ResponseFormat),
+            "none" => Ok(GrammarSupport::None),
+            _ => Err(anyhow::anyhow!("unknown grammar support type: {}", value)),
+        }
+    }
+}
+
+/// Client for any server that speaks the OpenAI completions API:
+/// vLLM, llama.cpp server, LM Studio, Ollama, etc.
+pub struct Client {
+    endpoint: String,
+    model: String,
+    api_key: Option<String>,
+    api: OpenAiApi,
+    grammar_support: GrammarSupport,
+}
+
+impl Client {
+    /// The endpoint is the base URL of the API, including the
+    /// version, e.g. `http://127.0.0.1:8080/v1`.
+    pub fn new(
+        endpoint: &str,
+        model: &str,
+        api_key: Option<String>,
+        api: OpenAiApi,
+        grammar_support: GrammarSupport,
+    ) -> Client {
+        Client {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            model: model.to_string(),
+            api_key,
+            api,
+            grammar_support,
+        }
+    }
+
+    fn url(&self) -> String {
+        match self.api {
+            OpenAiApi::Chat => format!("{}/chat/completions", self.endpoint),
+            OpenAiApi::Completions => format!("{}/completions", self.endpoint),
+        }
+    }
+
+    fn create_body(&self, request: &GenerationRequest<'_>) -> Value {
+        let mut body = json!({
+            "model": self.model,
+            "max_tokens": request.max_tokens,
+            "stream": true,
+        });
+
+        // Only the samplers that the common servers agree on. The
+        // rest have different names (or do not exist) on each one.
+        let sampler = request.sampler;
+        let samplers = [
+            ("temperature", sampler.temperature.map(|v| json!(v))),
+            ("top_p", sampler.top_p.map(|v| json!(v))),
+            ("top_k", sampler.top_k.map(|v| json!(v))),
+            ("min_p", sampler.min_p.map(|v| json!(v))),
+            ("seed", sampler.seed.map(|v| json!(v))),
+        ];
+
+        for (name, value) in samplers {
+            if let Some(value) = value {
+                body[name] = value;
+            }
+        }
+
+        match self.api {
+            OpenAiApi::Chat => {
+                let messages: Vec<_> = request
+                    .messages
+                    .iter()
+                    .map(|msg| json!({ "role": msg.role.as_str(), "content": msg.content }))
+                    .collect();
+
+                body["messages"] = json!(messages);
+            }
+            OpenAiApi::Completions => {
+                body["prompt"] = json!(request.prompt);
+                body["stop"] = json!(request.stop_sequences);
+            }
+        }
+
+        // Grammar state cannot be retained across requests on these
+        // servers. Starting the grammar over in the middle of a JSON
+        // response would force the model to begin a new document, so
+        // continuations are left unconstrained instead.
+        if !request.retain_grammar_state {
+            self.add_constraint(&mut body, request);
+        }
+
+        body
+    }
+
+    /// Constrain the output with the grammar or the JSON Schema of
+    /// the request, in the way the server understands.
+    fn add_constraint(&self, body: &mut Value, request: &GenerationRequest<'_>) {
+        let grammar = request.grammar.map(|grammar| json!(grammar));
+
+        // The schema is generated, so it is always valid JSON. It is
+        // sent as an object, not as a string.
+        let schema = request
+            .json_schema
+            .and_then(|schema| serde_json::from_str::<Value>(schema).ok());
+
+        let (param, value) = match self.grammar_support {
+            GrammarSupport::Gbnf => ("grammar", grammar),
+            GrammarSupport::GuidedGrammar => ("guided_grammar", grammar),
+            GrammarSupport::JsonSchema => ("json_schema", schema),
+            GrammarSupport::GuidedJson => ("guided_json", schema),
+            GrammarSupport::ResponseFormat => (
+                "response_format",
+                schema.map(|schema| {
+                    json!({
+                        "type": "json_schema",
+                        "json_schema": { "name": "response", "schema": schema },
+                    })
+                }),
+            ),
+            GrammarSupport::None => return,
+        };
+
+        if let Some(value) = value {
+            body[param] = value;
+        }
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, url: &str) -> anyhow::Result<T> {
+        let mut request = reqwest::Client::new().get(url);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        let resp = request.send().await?.error_for_status()?;
+        Ok(resp.json::<T>().await?)
+    }
+
+    /// The context length, as reported by the llama.cpp server. It
+    /// lives outside of the versioned API.
+    async fn llama_cpp_context_length(&self) -> Option<u64> {
+        let root = self.endpoint.trim_end_matches("/v1");
+        let url = format!("{}/props", root);
+        let props = self.get_json::<ServerProps>(&url).await.ok()?;
+        Some(props.default_generation_settings.n_ctx)
+    }
+
+    async fn stream_generate(
+        &self,
+        body: Value,
+        sink: &mut dyn TokenSink,
+    ) -> std::result::Result<String, es::Error> {
+        let params = serde_json::to_string(&body)?;
+
+        let mut builder = es::ClientBuilder::for_url(&self.url())?
+            .header("accept", "text/event-stream")?
+            .header("Content-Type", "application/json")?;
+
+        if let Some(api_key) = &self.api_key {
+            builder = builder.header("Authorization", &format!("Bearer {}", api_key))?;
+        }
+
+        let client = builder
+            .method("POST".to_string())
+            .body(params)
+            .reconnect(es::ReconnectOptions::reconnect(false).build())
+            .build();
+
+        let mut stream = create_response_stream(client, self.api);
+        let mut response = String::new();
+
+        loop {
+            let maybe_token = stream.try_next().await;
+            match maybe_token {
+                Ok(Some(StreamedToken::Token(token))) => {
+                    sink.token(&token);
+                    response.push_str(&token);
+                }
+                Ok(Some(StreamedToken::Done)) | Ok(None) => break,
+                Err(es::Error::Eof) => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(response)
+    }
+}
+
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<ModelEntry>,
+}
+
+#[derive(Deserialize)]
+struct ModelEntry {
+    id: String,
+
+    // Only vLLM reports the context length here.
+    #[serde(default)]
+    max_model_len: Option<u64>,
+}
+
+/// `/props` of the llama.cpp server.
+#[derive(Deserialize)]
+struct ServerProps {
+    default_generation_settings: ServerGenerationSettings,
+}
+
+#[derive(Deserialize)]
+struct ServerGenerationSettings {
+    n_ctx: u64,
+}
+
+#[derive(Deserialize)]
+struct StreamChunk {
+    choices: Vec<StreamChoice>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    // Set by the completions API.
+    #[serde(default)]
+    text: Option<String>,
+
+    // Set by the chat completions API.
+    #[serde(default)]
+    delta: Option<StreamDelta>,
+}
+
+#[derive(Deserialize)]
+struct StreamDelta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+enum StreamedToken {
+    Token(String),
+    Done,
+}
+
+fn extract_token(data: &str, api: OpenAiApi) -> Result<StreamedToken, serde_json::Error> {
+    if data.trim() == STREAM_DONE {
+        return Ok(StreamedToken::Done);
+    }
+
+    let chunk: StreamChunk = serde_json::from_str(data)?;
+    let token = chunk
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| match api {
+            OpenAiApi::Chat => choice.delta.and_then(|delta| delta.content),
+            OpenAiApi::Completions => choice.text,
+        })
+        .unwrap_or_default();
+
+    Ok(StreamedToken::Token(token))
+}
+
+fn create_response_stream(
+    client: impl es::Client,
+    api: OpenAiApi,
+) -> impl Stream<Item = Result<StreamedToken, es::Error>> {
+    client.stream().map(move |sse| {
+        sse.and_then(|event| match event {
+            SSE::Event(ev) => {
+                extract_token(&ev.data, api).map_err(|err| es::Error::Unexpected(Box::new(err)))
+            }
+            SSE::Comment(_) => Ok(StreamedToken::Token("".to_string())),
+        })
+    })
+}
+
+#[async_trait]
+impl LlmBackend for Client {
+    async fn generate(&self, request: &GenerationRequest<'_>) -> anyhow::Result<String> {
+        self.generate_streaming(request, &mut |_: &str| ()).await
+    }
+
+    async fn generate_streaming(
+        &self,
+        request: &GenerationRequest<'_>,
+        sink: &mut dyn TokenSink,
+    ) -> anyhow::Result<String> {
+        let body = self.create_body(request);
+        let sent_grammar = CONSTRAINT_PARAMS.iter().any(|param| body.get(param).is_some());
+
+        let response = self.stream_generate(body, sink).await.map_err(|err| match err {
+            // Servers that do not know the grammar parameter tend to
+            // reject the whole request as invalid. Any other status,
+            // e.g. a rate limit or a server error, has nothing to do
+            // with the grammar.
+            es::Error::UnexpectedResponse(status)
+                if sent_grammar && matches!(status.as_u16(), 400 | 422) =>
+            {
+                GenerationError::GrammarUnsupported(format!(
+                    "{:?} (check the openai_grammar setting)",
+                    err
+                ))
+            }
+            err => GenerationError::from(err),
+        })?;
+
+        Ok(response)
+    }
+
+    /// The chat API can only send the response so far back as a
+    /// finished assistant turn, which the model answers with a new
+    /// response instead of carrying on with it.
+    fn can_continue(&self) -> bool {
+        self.api == OpenAiApi::Completions
+    }
+
+    async fn probe(&self) -> anyhow::Result<BackendInfo> {
+        let url = format!("{}/models", self.endpoint);
+        let models = self.get_json::<ModelList>(&url).await.map_err(|err| {
+            anyhow!(
+                "could not reach the OpenAI API at {} ({}). Is the server running? \
+                 The endpoint is set with connection.openai_endpoint.",
+                self.endpoint,
+                err
+            )
+        })?;
+
+        // Servers that only serve one model tend to ignore the model
+        // name, so the configured one may not be in the list.
+        let entry = models
+            .data
+            .iter()
+            .find(|entry| entry.id == self.model)
+            .or(models.data.first());
+
+        let max_context_length = match entry.and_then(|entry| entry.max_model_len) {
+            Some(length) => Some(length),
+            None => self.llama_cpp_context_length().await,
+        };
+
+        // There is no way to ask the server whether it understands
+        // the grammar parameter, short of generating something.
+        let grammar_support = match self.grammar_support {
+            GrammarSupport::None => Some(false),
+            _ => None,
+        };
+
+        Ok(BackendInfo {
+            model: entry.map(|entry| entry.id.clone()),
+            max_context_length,
+            grammar_support,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::sampling::SamplerProfile;
+    use crate::ai::template::ChatMessage;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinHandle;
+
+    const GRAMMAR: &str = r#"root ::= "{}""#;
+    const SCHEMA: &str = r#"{"type":"object"}"#;
+
+    /// The body of an HTTP request, read up to its content length.
+    async fn read_body(socket: &mut TcpStream) -> String {
+        let mut data = vec![];
+        let mut buf = [0; 4096];
+
+        loop {
+            let read = socket.read(&mut buf).await.unwrap();
+            data.extend_from_slice(&buf[..read]);
+
+            let text = String::from_utf8_lossy(&data).to_string();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text[..end]
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+
+                if data.len() >= end + 4 + length || read == 0 {
+                    return text[end + 4..].to_string();
+                }
+            }
+
+            if read == 0 {
+                return text;
+            }
+        }
+    }
+
+    /// A server that answers a single request, and hands back the
+    /// body of the request it got.
+    async fn serve_once(
+        status: &str,
+        content_type: &str,
+        body: &str,
+    ) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/v1", listener.local_addr().unwrap());
+
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        );
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let request = read_body(&mut socket).await;
+            socket.write_all(response.as_bytes()).await.unwrap();
+            socket.shutdown().await.unwrap();
+            request
+        });
+
+        (endpoint, server)
+    }
+
+    /// An event stream of the given chunks, ending with `[DONE]`.
+    fn event_stream(chunks: &[Value]) -> String {
+        chunks
+            .iter()
+            .map(|chunk| format!("data: {}\n\n", chunk))
+            .chain(std::iter::once(format!("data: {}\n\n", STREAM_DONE)))
+            .collect()
+    }
+
+    fn request<'a>(
+        messages: &'a [ChatMessage],
+        sampler: &'a SamplerProfile,
+    ) -> GenerationRequest<'a> {
+        GenerationRequest {
+            gen_key: "test",
+            prompt: "Describe a room.",
+            messages,
+            stop_sequences: &["</s>"],
+            grammar: Some(GRAMMAR),
+            json_schema: Some(SCHEMA),
+            max_tokens: 32,
+            max_context_length: 4096,
+            sampler,
+            retain_grammar_state: false,
+            attempt: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_completion_tokens() {
+        let stream = event_stream(&[
+            json!({ "choices": [{ "text": "{\"name\"" }] }),
+            json!({ "choices": [{ "text": ": \"Bob\"}" }] }),
+        ]);
+
+        let (endpoint, server) = serve_once("200 OK", "text/event-stream", &stream).await;
+        let client = Client::new(
+            &endpoint,
+            "model",
+            None,
+            OpenAiApi::Completions,
+            GrammarSupport::Gbnf,
+        );
+
+        let messages = [ChatMessage::user("Describe a room.")];
+        let sampler = SamplerProfile {
+            temperature: Some(0.5),
+            seed: Some(7),
+            ..Default::default()
+        };
+
+        let mut tokens = vec![];
+        let mut sink = |token: &str| tokens.push(token.to_string());
+        let response = client
+            .generate_streaming(&request(&messages, &sampler), &mut sink)
+            .await
+            .unwrap();
+
+        assert_eq!(response, r#"{"name": "Bob"}"#);
+        assert_eq!(tokens, vec![r#"{"name""#, r#": "Bob"}"#]);
+
+        let body: Value = serde_json::from_str(&server.await.unwrap()).unwrap();
+        assert_eq!(body["model"], "model");
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["prompt"], "Describe a room.");
+        assert_eq!(body["stop"], json!(["</s>"]));
+        assert_eq!(body["grammar"], GRAMMAR);
+        assert_eq!(body["temperature"], 0.5);
+        assert_eq!(body["seed"], 7);
+        assert!(body.get("messages").is_none());
+    }
+
+    #[tokio::test]
+    async fn streams_chat_deltas_with_a_response_format() {
+        let stream = event_stream(&[
+            json!({ "choices": [{ "delta": { "role": "assistant" } }] }),
+            json!({ "choices": [{ "delta": { "content": "{}" } }] }),
+        ]);
+
+        let (endpoint, server) = serve_once("200 OK", "text/event-stream", &stream).await;
+        let support = GrammarSupport::ResponseFormat;
+        let client = Client::new(&endpoint, "model", None, OpenAiApi::Chat, support);
+
+        let messages = [ChatMessage::system("Be brief."), ChatMessage::user("Hi")];
+        let sampler = SamplerProfile::default();
+        let response = client
+            .generate(&request(&messages, &sampler))
+            .await
+            .unwrap();
+
+        assert_eq!(response, "{}");
+
+        let body: Value = serde_json::from_str(&server.await.unwrap()).unwrap();
+        assert_eq!(
+            body["messages"],
+            json!([
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "Hi" },
+            ])
+        );
+        assert_eq!(
+            body["response_format"]["json_schema"]["schema"],
+            json!({ "type": "object" })
+        );
+        assert!(body.get("grammar").is_none());
+        assert!(body.get("prompt").is_none());
+    }
+
+    #[tokio::test]
+    async fn continuations_are_not_constrained() {
+        let stream = event_stream(&[json!({ "choices": [{ "text": "}" }] })]);
+        let (endpoint, server) = serve_once("200 OK", "text/event-stream", &stream).await;
+        let client = Client::new(
+            &endpoint,
+            "model",
+            None,
+            OpenAiApi::Completions,
+            GrammarSupport::Gbnf,
+        );
+
+        let messages: [ChatMessage; 0] = [];
+        let sampler = SamplerProfile::default();
+        let request = GenerationRequest {
+            retain_grammar_state: true,
+            ..request(&messages, &sampler)
+        };
+
+        assert_eq!(client.generate(&request).await.unwrap(), "}");
+
+        let body: Value = serde_json::from_str(&server.await.unwrap()).unwrap();
+        assert!(body.get("grammar").is_none());
+    }
+
+    #[tokio::test]
+    async fn rejected_grammars_are_reported() {
+        let (endpoint, _server) = serve_once(
+            "400 Bad Request",
+            "application/json",
+            r#"{"error": "grammar"}"#,
+        )
+        .await;
+        let client = Client::new(
+            &endpoint,
+            "model",
+            None,
+            OpenAiApi::Completions,
+            GrammarSupport::Gbnf,
+        );
+
+        let messages: [ChatMessage; 0] = [];
+        let sampler = SamplerProfile::default();
+        let err = client
+            .generate(&request(&messages, &sampler))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<GenerationError>(),
+            Some(GenerationError::GrammarUnsupported(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_transient_with_a_grammar() {
+        for status in ["429 Too Many Requests", "503 Service Unavailable"] {
+            let (endpoint, _server) =
+                serve_once(status, "application/json", r#"{"error": "busy"}"#).await;
+            let client = Client::new(
+                &endpoint,
+                "model",
+                None,
+                OpenAiApi::Completions,
+                GrammarSupport::Gbnf,
+            );
+
+            let messages: [ChatMessage; 0] = [];
+            let sampler = SamplerProfile::default();
+            let err = client
+                .generate(&request(&messages, &sampler))
+                .await
+                .unwrap_err();
+
+            let err = err.downcast_ref::<GenerationError>();
+            assert!(
+                matches!(err, Some(GenerationError::Transport(_))),
+                "{}",
+                status
+            );
+            assert!(err.unwrap().is_transient());
+        }
+    }
+
+    #[tokio::test]
+    async fn probes_the_model_list() {
+        let models = json!({ "data": [
+            { "id": "other" },
+            { "id": "model", "max_model_len": 8192 },
+        ]});
+
+        let (endpoint, _server) =
+            serve_once("200 OK", "application/json", &models.to_string()).await;
+        let client = Client::new(
+            &endpoint,
+            "model",
+            None,
+            OpenAiApi::Chat,
+            GrammarSupport::None,
+        );
+        let info = client.probe().await.unwrap();
+
+        assert_eq!(info.model.as_deref(), Some("model"));
+        assert_eq!(info.max_context_length, Some(8192));
+        assert_eq!(info.grammar_support, Some(false));
+    }
+}
@@ -10,18 +10,18 @@ use crate::{
     },
 };
 use anyhow::Result;
-use std::rc::Rc;
+use std::sync::Arc;
 
 pub struct GameState {
     pub start_prompt: String,
-    pub logic: Rc<AiLogic>,
-    pub db: Rc<Database>,
+    pub logic: Arc<AiLogic>,
+    pub db: Arc<Database>,
     pub current_scene: Stage,
 }
 
 impl GameState {
     pub async fn update(&mut self, event: CommandEvent) -> Result<()> {
-        println!("handling event: {:?}", event);
+        tracing::debug!(event = ?event, "handling event");
         match event {
             CommandEvent::ChangeScene { scene_key } => self.change_scene(&scene_key).await?,
             CommandEvent::Narration(narration) => println!("\n\n{}\n\n", narration),
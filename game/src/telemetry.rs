@@ -0,0 +1,54 @@
+use anyhow::Result;
+use std::fs::OpenOptions;
+use std::sync::Mutex;
+use tracing::Subscriber;
+use tracing_subscriber::filter::{filter_fn, EnvFilter};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Target of the tracing events that are written to the metrics
+/// file. Every event has a `metric` field naming what it measures.
+pub const METRICS_TARGET: &str = "ai_game::metrics";
+
+/// Set up tracing. Events are logged to stderr, filtered by the
+/// `RUST_LOG` environment variable (warnings and errors if it is not
+/// set). If a metrics file is given, every metrics event is also
+/// appended to it as a line of JSON, along with the spans it happened
+/// in (e.g. which step of command parsing an LLM call was for).
+pub fn init(metrics_file: Option<&str>) -> Result<()> {
+    let log_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn"));
+    let log_layer = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_filter(log_filter);
+
+    let metrics_layer = match metrics_file {
+        Some(path) => Some(metrics_layer(path)?),
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(log_layer)
+        .with(metrics_layer)
+        .try_init()?;
+
+    Ok(())
+}
+
+fn metrics_layer<S>(path: &str) -> Result<impl Layer<S>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    // Spans must be let through, or they would be missing from the
+    // span list of the events.
+    let metrics_only = filter_fn(|meta| meta.is_span() || meta.target() == METRICS_TARGET);
+
+    Ok(tracing_subscriber::fmt::layer()
+        .json()
+        .with_writer(Mutex::new(file))
+        .with_current_span(false)
+        .with_span_list(true)
+        .with_filter(metrics_only))
+}
@@ -0,0 +1,438 @@
+//! Primitives with limits on their values, set with field attributes
+//! of the derive: a maximum length or a pattern for strings, and a
+//! range for whole numbers.
+
+use sha2::{Digest, Sha256};
+
+use crate::parser::GbnfExpr;
+use crate::pattern::{bounded, pattern_to_gbnf};
+use crate::{AsGrammar, GbnfPrimitive, GbnfRule};
+
+/// A limit on the values of a primitive.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GbnfConstraint {
+    /// A string of at most this many characters.
+    MaxLength(usize),
+
+    /// A string that matches the regular expression as a whole.
+    Pattern(String),
+
+    /// A whole number within the bounds, which are inclusive. A
+    /// missing bound leaves that side open.
+    Range { min: Option<i64>, max: Option<i64> },
+}
+
+/// Types whose values can be limited with `#[gbnf(range = ...)]`.
+/// The derive checks this for field types it cannot tell from their
+/// name, e.g. type aliases.
+#[diagnostic::on_unimplemented(message = "`{Self}` is not an integer, so it cannot have a range")]
+pub trait GbnfRangeConstrainable {
+    /// Whether the type has no negative values.
+    const UNSIGNED: bool;
+}
+
+/// Types whose values can be limited with `#[gbnf(max_len = ...)]`
+/// or `#[gbnf(pattern = "...")]`. Checked like `GbnfRangeConstrainable`.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` is not a string, so it cannot have a maximum length or a pattern"
+)]
+pub trait GbnfStringConstrainable {}
+
+macro_rules! range_constrainable {
+    ($unsigned:expr, $($type:ty),*) => {
+        $(impl GbnfRangeConstrainable for $type {
+            const UNSIGNED: bool = $unsigned;
+        })*
+    };
+}
+
+range_constrainable!(false, i8, i16, i32, i64, isize);
+range_constrainable!(true, u8, u16, u32, u64, usize);
+
+impl<T: GbnfRangeConstrainable> GbnfRangeConstrainable for Option<T> {
+    const UNSIGNED: bool = T::UNSIGNED;
+}
+
+impl GbnfStringConstrainable for String {}
+impl GbnfStringConstrainable for char {}
+impl<T: GbnfStringConstrainable> GbnfStringConstrainable for Option<T> {}
+
+/// A primitive, and the constraint on its values.
+#[derive(Debug)]
+pub struct GbnfConstrained {
+    pub primitive: GbnfPrimitive,
+    pub constraint: GbnfConstraint,
+}
+
+impl GbnfConstrained {
+    /// Panics if the constraint does not apply to the primitive, e.g.
+    /// a maximum length on a number.
+    pub fn new(primitive: GbnfPrimitive, constraint: GbnfConstraint) -> GbnfConstrained {
+        let applies = match constraint {
+            GbnfConstraint::MaxLength(_) | GbnfConstraint::Pattern(_) => {
+                matches!(primitive, GbnfPrimitive::String)
+            }
+            GbnfConstraint::Range { .. } => {
+                matches!(primitive, GbnfPrimitive::Integer | GbnfPrimitive::Unsigned)
+            }
+        };
+
+        if !applies {
+            panic!("{:?} cannot be constrained by {:?}", primitive, constraint);
+        }
+
+        let constrained = GbnfConstrained {
+            primitive,
+            constraint,
+        };
+
+        if let GbnfConstraint::Range { min, max } = constrained.constraint {
+            if let (Some(min), Some(max)) = constrained.bounds(min, max) {
+                if min > max {
+                    panic!("no {:?} is within {}..={}", constrained.primitive, min, max);
+                }
+            }
+        }
+
+        constrained
+    }
+
+    /// The bounds of a range, with unsigned numbers never going
+    /// below zero.
+    fn bounds(&self, min: Option<i64>, max: Option<i64>) -> (Option<i128>, Option<i128>) {
+        let min = match self.primitive {
+            GbnfPrimitive::Unsigned => Some(min.unwrap_or(0).max(0)),
+            _ => min,
+        };
+
+        (min.map(i128::from), max.map(i128::from))
+    }
+
+    fn expr(&self) -> GbnfExpr {
+        let quote = || GbnfExpr::Literal("\"".to_string());
+
+        match &self.constraint {
+            GbnfConstraint::MaxLength(length) => GbnfExpr::Sequence(vec![
+                quote(),
+                bounded(
+                    GbnfExpr::RuleRef("char".to_string()),
+                    0,
+                    Some(*length as u32),
+                ),
+                quote(),
+            ]),
+            GbnfConstraint::Pattern(pattern) => GbnfExpr::Sequence(vec![
+                quote(),
+                pattern_to_gbnf(pattern).expect("patterns are checked by the derive"),
+                quote(),
+            ]),
+            GbnfConstraint::Range { min, max } => {
+                let (min, max) = self.bounds(*min, *max);
+                integer_range(min, max)
+            }
+        }
+    }
+}
+
+impl AsGrammar for GbnfConstrained {
+    fn rules(&self) -> Vec<GbnfRule> {
+        let mut rules = GbnfRule::single(self.token(), self.expr().to_string());
+        if let GbnfConstraint::MaxLength(_) = self.constraint {
+            rules.push(GbnfPrimitive::char_rule());
+        }
+
+        rules
+    }
+
+    fn token(&self) -> String {
+        let name = self.primitive.token();
+
+        match &self.constraint {
+            GbnfConstraint::MaxLength(length) => format!("{}Max{}", name, length),
+            GbnfConstraint::Pattern(pattern) => {
+                // The rule name ends up in cache keys and fixtures, so
+                // it must not change between builds.
+                let hash = Sha256::digest(pattern.as_bytes());
+                let hash: String = hash[..8]
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                format!("{}Pattern{}", name, hash)
+            }
+            GbnfConstraint::Range { min, max } => {
+                let bound = |bound: i128| match bound < 0 {
+                    true => format!("Neg{}", -bound),
+                    false => bound.to_string(),
+                };
+
+                let (min, max) = self.bounds(*min, *max);
+                let from = min.map(|min| format!("From{}", bound(min)));
+                let to = max.map(|max| format!("To{}", bound(max)));
+
+                format!(
+                    "{}{}{}",
+                    name,
+                    from.unwrap_or_default(),
+                    to.unwrap_or_default()
+                )
+            }
+        }
+    }
+}
+
+fn digit_class(start: u32, end: u32) -> GbnfExpr {
+    let digit = |digit: u32| char::from_digit(digit, 10).expect("digits are below ten");
+
+    GbnfExpr::CharClass {
+        negated: false,
+        ranges: vec![(digit(start), digit(end))],
+    }
+}
+
+fn sequence(mut items: Vec<GbnfExpr>) -> GbnfExpr {
+    match items.len() {
+        1 => items.remove(0),
+        _ => GbnfExpr::Sequence(items),
+    }
+}
+
+fn alternation(mut alternatives: Vec<GbnfExpr>) -> GbnfExpr {
+    match alternatives.len() {
+        1 => alternatives.remove(0),
+        _ => GbnfExpr::Alternation(alternatives),
+    }
+}
+
+/// The numbers from `start` to `stop`, which have the same number of
+/// digits, and only differ in the digits after the first one that
+/// differs, where `start` has zeros and `stop` nines. Equal digits
+/// become a literal, and the others a class.
+fn same_length_range(start: u128, stop: u128) -> GbnfExpr {
+    let mut items: Vec<GbnfExpr> = vec![];
+
+    for (a, b) in start.to_string().chars().zip(stop.to_string().chars()) {
+        match (a == b, items.last_mut()) {
+            (true, Some(GbnfExpr::Literal(text))) => text.push(a),
+            (true, _) => items.push(GbnfExpr::Literal(a.to_string())),
+            (false, _) => items.push(digit_class(
+                a.to_digit(10).unwrap_or(0),
+                b.to_digit(10).unwrap_or(9),
+            )),
+        }
+    }
+
+    sequence(items)
+}
+
+/// Splits `min..=max` into ranges that `same_length_range` can match,
+/// e.g. `5..=150` into `5..=9`, `10..=99`, `100..=149`, and `150`.
+fn split_range(min: u128, max: u128) -> Vec<(u128, u128)> {
+    // `n` with its last `count` digits all nines or all zeros.
+    let nines = |n: u128, count: u32| n - n % 10u128.pow(count) + 10u128.pow(count) - 1;
+    let zeros = |n: u128, count: u32| n - n % 10u128.pow(count);
+
+    let mut stops = vec![max];
+
+    let mut count = 1;
+    while nines(min, count) <= max {
+        stops.push(nines(min, count));
+        count += 1;
+    }
+
+    let mut count = 1;
+    while zeros(max + 1, count) > min + 1 {
+        stops.push(zeros(max + 1, count) - 1);
+        count += 1;
+    }
+
+    stops.sort_unstable();
+    stops.dedup();
+
+    let mut start = min;
+    stops
+        .into_iter()
+        .map(|stop| {
+            let range = (start, stop);
+            start = stop + 1;
+            range
+        })
+        .collect()
+}
+
+/// Every number from `min` on, written without leading zeros. Without
+/// a maximum, numbers with more digits than `min` are all allowed.
+fn natural_range(min: u128, max: Option<u128>) -> GbnfExpr {
+    let digits = min.to_string().len() as u32;
+    let bounded_max = max.unwrap_or(10u128.pow(digits) - 1);
+
+    let mut alternatives: Vec<GbnfExpr> = split_range(min, bounded_max)
+        .into_iter()
+        .map(|(start, stop)| same_length_range(start, stop))
+        .collect();
+
+    if max.is_none() {
+        alternatives.push(GbnfExpr::Sequence(vec![
+            digit_class(1, 9),
+            bounded(digit_class(0, 9), digits, None),
+        ]));
+    }
+
+    alternation(alternatives)
+}
+
+/// Every whole number within the bounds.
+fn integer_range(min: Option<i128>, max: Option<i128>) -> GbnfExpr {
+    let negative = |expr: GbnfExpr| GbnfExpr::Sequence(vec![GbnfExpr::Literal("-".into()), expr]);
+    let magnitude = |n: i128| n.unsigned_abs();
+
+    let mut alternatives = vec![];
+
+    // Negative numbers, as the range of their magnitudes.
+    if min.is_none_or(|min| min < 0) {
+        let smallest = max.map_or(1, |max| magnitude(max.min(-1)));
+        alternatives.push(negative(natural_range(smallest, min.map(magnitude))));
+    }
+
+    // Zero and positive numbers.
+    if max.is_none_or(|max| max >= 0) {
+        let smallest = magnitude(min.unwrap_or(0).max(0));
+        alternatives.push(natural_range(smallest, max.map(magnitude)));
+    }
+
+    alternation(alternatives)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GbnfRecognizer, ParsedGrammar, Recognition};
+
+    fn recognizer(expr: GbnfExpr) -> GbnfRecognizer {
+        let grammar = ParsedGrammar::parse(&format!("root ::= {}", expr)).unwrap();
+        GbnfRecognizer::new(&grammar).unwrap()
+    }
+
+    #[test]
+    fn splits_ranges_by_digit_count() {
+        type Range = (u128, u128);
+
+        let cases: [(Range, &[Range]); 7] = [
+            ((0, 0), &[(0, 0)]),
+            ((9, 10), &[(9, 9), (10, 10)]),
+            ((5, 150), &[(5, 9), (10, 99), (100, 149), (150, 150)]),
+            ((0, 1000), &[(0, 9), (10, 99), (100, 999), (1000, 1000)]),
+            ((123, 129), &[(123, 129)]),
+            ((15, 42), &[(15, 19), (20, 39), (40, 42)]),
+            ((1, 9), &[(1, 9)]),
+        ];
+
+        for ((min, max), expected) in cases {
+            assert_eq!(split_range(min, max), expected, "{}..={}", min, max);
+        }
+    }
+
+    #[test]
+    fn matches_exactly_the_numbers_in_range() {
+        let cases = [
+            (Some(0), Some(0)),
+            (Some(9), Some(10)),
+            (Some(5), Some(150)),
+            (Some(0), Some(1000)),
+            (Some(-15), Some(-3)),
+            (Some(-5), Some(5)),
+            (Some(-120), Some(-100)),
+            (None, Some(10)),
+            (None, Some(-7)),
+            (Some(3), None),
+            (Some(-12), None),
+            (None, None),
+        ];
+
+        for (min, max) in cases {
+            let recognizer = recognizer(integer_range(min, max));
+            for n in -1100..=1100 {
+                let in_range = min.is_none_or(|min| min <= n) && max.is_none_or(|max| n <= max);
+                let matched = recognizer.recognize(&n.to_string()) == Recognition::Complete;
+                assert_eq!(matched, in_range, "{} in {:?}..={:?}", n, min, max);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_leading_zeros() {
+        let recognizer = recognizer(integer_range(Some(0), Some(150)));
+        for text in ["00", "05", "007", "-0"] {
+            assert_ne!(
+                recognizer.recognize(text),
+                Recognition::Complete,
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn renders_ranges() {
+        assert_eq!(integer_range(Some(0), Some(0)).to_string(), r#""0""#);
+        assert_eq!(
+            integer_range(Some(9), Some(10)).to_string(),
+            r#""9" | "10""#
+        );
+        assert_eq!(
+            integer_range(Some(5), Some(150)).to_string(),
+            r#"[5-9] | [1-9]   [0-9] | "1"   [0-4]   [0-9] | "150""#
+        );
+    }
+
+    #[test]
+    fn unsigned_ranges_start_at_zero() {
+        let unsigned = GbnfConstrained::new(
+            GbnfPrimitive::Unsigned,
+            GbnfConstraint::Range {
+                min: Some(-10),
+                max: Some(20),
+            },
+        );
+
+        assert_eq!(unsigned.token(), "unsignedFrom0To20");
+        assert_eq!(
+            recognizer(unsigned.expr()).recognize("-1"),
+            Recognition::Diverged { position: 0 }
+        );
+    }
+
+    #[test]
+    fn names_rules_after_their_constraint() {
+        let constrained = |primitive, constraint| GbnfConstrained::new(primitive, constraint);
+
+        let cases = [
+            (
+                constrained(GbnfPrimitive::String, GbnfConstraint::MaxLength(100)),
+                "stringMax100",
+            ),
+            (
+                constrained(
+                    GbnfPrimitive::Integer,
+                    GbnfConstraint::Range {
+                        min: Some(-5),
+                        max: None,
+                    },
+                ),
+                "integerFromNeg5",
+            ),
+            // Pattern names are a hash, which must be the same on
+            // every build and platform.
+            (
+                constrained(
+                    GbnfPrimitive::String,
+                    GbnfConstraint::Pattern("[A-Z][a-z]+".to_string()),
+                ),
+                "stringPattern0e7a36b3db224d26",
+            ),
+        ];
+
+        for (constrained, name) in cases {
+            assert_eq!(constrained.token(), name);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! JSON Schema for the same types the grammar is generated from, for
+//! servers that constrain their output with a schema instead of GBNF.
+//! Everything is inlined: the types that can be derived are never
+//! recursive, and not every server resolves references.
+
+use serde_json::{json, Map, Value};
+
+use crate::pattern::anchored_pattern;
+use crate::{
+    GbnfComplex, GbnfConstrained, GbnfConstraint, GbnfField, GbnfFieldType, GbnfLimited,
+    GbnfPrimitive, GbnfUnion,
+};
+
+/// Converts GBNF definitions into the equivalent JSON Schema.
+pub trait AsJsonSchema {
+    fn json_schema(&self) -> Value;
+}
+
+/// Either the schema, or null.
+fn nullable(schema: Value) -> Value {
+    json!({ "anyOf": [schema, { "type": "null" }] })
+}
+
+fn list(schema: Value) -> Value {
+    json!({ "type": "array", "items": schema })
+}
+
+impl AsJsonSchema for GbnfPrimitive {
+    fn json_schema(&self) -> Value {
+        match self {
+            Self::String => json!({ "type": "string" }),
+            Self::Boolean => json!({ "type": "boolean" }),
+            Self::Number => json!({ "type": "number" }),
+            Self::Integer => json!({ "type": "integer" }),
+            Self::Unsigned => json!({ "type": "integer", "minimum": 0 }),
+        }
+    }
+}
+
+impl AsJsonSchema for GbnfConstrained {
+    fn json_schema(&self) -> Value {
+        let mut schema = self.primitive.json_schema();
+
+        match &self.constraint {
+            GbnfConstraint::MaxLength(length) => schema["maxLength"] = json!(length),
+            GbnfConstraint::Pattern(pattern) => {
+                schema["pattern"] = json!(anchored_pattern(pattern))
+            }
+            GbnfConstraint::Range { min, max } => {
+                if let Some(min) = min {
+                    // Unsigned numbers already have a minimum of zero.
+                    if schema["minimum"].as_i64().is_none_or(|zero| *min > zero) {
+                        schema["minimum"] = json!(min);
+                    }
+                }
+
+                if let Some(max) = max {
+                    schema["maximum"] = json!(max);
+                }
+            }
+        }
+
+        schema
+    }
+}
+
+impl AsJsonSchema for GbnfLimited {
+    fn json_schema(&self) -> Value {
+        // Only string values are quoted in the grammar. The others
+        // are already JSON literals.
+        let values: Vec<Value> = self
+            .values
+            .iter()
+            .map(|value| match self.primitive {
+                GbnfPrimitive::String => Value::String(value.clone()),
+                _ => serde_json::from_str(value).unwrap_or_else(|_| json!(value)),
+            })
+            .collect();
+
+        json!({ "enum": values })
+    }
+}
+
+impl AsJsonSchema for GbnfUnion {
+    fn json_schema(&self) -> Value {
+        let alternatives: Vec<Value> = self
+            .alternatives
+            .iter()
+            .map(|alternative| alternative.json_schema())
+            .collect();
+
+        json!({ "anyOf": alternatives })
+    }
+}
+
+impl AsJsonSchema for GbnfFieldType {
+    fn json_schema(&self) -> Value {
+        match self {
+            GbnfFieldType::Primitive(f) => f.json_schema(),
+            GbnfFieldType::OptionalPrimitive(f) => nullable(f.json_schema()),
+            GbnfFieldType::PrimitiveList(f) => list(f.json_schema()),
+            GbnfFieldType::Complex(f) => f.json_schema(),
+            GbnfFieldType::OptionalComplex(f) => nullable(f.json_schema()),
+            GbnfFieldType::ComplexList(f) => list(f.json_schema()),
+            GbnfFieldType::Limited(f) => f.json_schema(),
+            GbnfFieldType::OptionalLimited(f) => nullable(f.json_schema()),
+            GbnfFieldType::LimitedList(f) => list(f.json_schema()),
+            GbnfFieldType::Union(f) => f.json_schema(),
+            GbnfFieldType::OptionalUnion(f) => nullable(f.json_schema()),
+            GbnfFieldType::UnionList(f) => list(f.json_schema()),
+            GbnfFieldType::Constrained(f) => f.json_schema(),
+            GbnfFieldType::OptionalConstrained(f) => nullable(f.json_schema()),
+            GbnfFieldType::ConstrainedList(f) => list(f.json_schema()),
+        }
+    }
+}
+
+impl AsJsonSchema for GbnfField {
+    fn json_schema(&self) -> Value {
+        self.field_type.json_schema()
+    }
+}
+
+impl AsJsonSchema for GbnfComplex {
+    /// An object with exactly the fields of the type. Omittable
+    /// fields are the only ones that are not required.
+    fn json_schema(&self) -> Value {
+        let properties: Map<String, Value> = self
+            .fields
+            .iter()
+            .map(|field| (field.field_name.clone(), field.json_schema()))
+            .collect();
+
+        let required: Vec<&str> = self
+            .fields
+            .iter()
+            .filter(|field| !field.omittable)
+            .map(|field| field.field_name.as_str())
+            .collect();
+
+        json!({
+            "type": "object",
+            "properties": properties,
+            "required": required,
+            "additionalProperties": false,
+        })
+    }
+}
+
+impl GbnfComplex {
+    /// The schema of the type as a JSON document, to send along with
+    /// a request in place of the grammar.
+    pub fn to_json_schema(&self) -> String {
+        self.json_schema().to_string()
+    }
+}
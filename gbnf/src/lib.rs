@@ -2,25 +2,63 @@ extern crate proc_macro;
 
 use itertools::Itertools;
 use serde::de::DeserializeOwned;
+use std::cmp::Ordering;
+use std::iter;
+
+mod constraint;
+mod json_schema;
+mod lint;
+mod parser;
+mod pattern;
+mod recognizer;
+mod shape;
+
+pub use constraint::{
+    GbnfConstrained, GbnfConstraint, GbnfRangeConstrainable, GbnfStringConstrainable,
+};
+pub use json_schema::AsJsonSchema;
+pub use lint::LintIssue;
+pub use parser::{GbnfExpr, ParseError, ParsedGrammar, ParsedRule};
+pub use pattern::{pattern_to_gbnf, PatternError};
+pub use recognizer::{GbnfRecognizer, Recognition};
+pub use shape::{check_shape, ShapeError};
 
 pub mod prelude {
     pub use crate::gbnf_field;
     pub use crate::gbnf_field_type;
     pub use crate::AsGbnf;
     pub use crate::AsGrammar;
+    pub use crate::AsJsonSchema;
     pub use crate::GbnfComplex;
+    pub use crate::GbnfConstrained;
+    pub use crate::GbnfConstraint;
     pub use crate::GbnfField;
     pub use crate::GbnfFieldType;
+    pub use crate::GbnfLimited;
     pub use crate::GbnfPrimitive;
     pub use crate::GbnfRule;
     pub use crate::GbnfToken;
+    pub use crate::GbnfUnion;
 }
 
 // TODOs for this implementation:
 //  1. Move primitive definitions (string, bool, etc) to the bottom of generated grammar.
-//  2. Implement support for limited values.
-//  3. Generate static strings for the gramma rules where possible.
-//  4. Properly support optional types (right now they map to non-optional values).
+//  2. Generate static strings for the gramma rules where possible.
+
+/// A GBNF literal that matches the text exactly.
+fn literal(text: &str) -> String {
+    let escaped: String = text
+        .chars()
+        .map(|c| parser::escape_char(c, &['"']))
+        .collect();
+
+    format!(r#""{}""#, escaped)
+}
+
+/// The text as a JSON string, quoted and escaped.
+fn json_string(text: &str) -> String {
+    serde_json::Value::String(text.to_string()).to_string()
+}
 
 // Converts GBNF defintions (through the types below) into the grammar
 // rules.
@@ -58,20 +96,26 @@ macro_rules! gbnf_field {
         GbnfField {
             field_name: $field_name.to_string(),
             field_type: gbnf_field_type!($field_type),
+            omittable: false,
         }
     };
 }
 
 // Implemented field type mappings for common rust types.
-define_field_type!(i16, GbnfFieldType::Primitive(GbnfPrimitive::Number));
-define_field_type!(u16, GbnfFieldType::Primitive(GbnfPrimitive::Number));
-define_field_type!(i32, GbnfFieldType::Primitive(GbnfPrimitive::Number));
-define_field_type!(u32, GbnfFieldType::Primitive(GbnfPrimitive::Number));
-define_field_type!(i64, GbnfFieldType::Primitive(GbnfPrimitive::Number));
-define_field_type!(u64, GbnfFieldType::Primitive(GbnfPrimitive::Number));
+define_field_type!(i8, GbnfFieldType::Primitive(GbnfPrimitive::Integer));
+define_field_type!(i16, GbnfFieldType::Primitive(GbnfPrimitive::Integer));
+define_field_type!(i32, GbnfFieldType::Primitive(GbnfPrimitive::Integer));
+define_field_type!(i64, GbnfFieldType::Primitive(GbnfPrimitive::Integer));
+define_field_type!(isize, GbnfFieldType::Primitive(GbnfPrimitive::Integer));
+
+define_field_type!(u8, GbnfFieldType::Primitive(GbnfPrimitive::Unsigned));
+define_field_type!(u16, GbnfFieldType::Primitive(GbnfPrimitive::Unsigned));
+define_field_type!(u32, GbnfFieldType::Primitive(GbnfPrimitive::Unsigned));
+define_field_type!(u64, GbnfFieldType::Primitive(GbnfPrimitive::Unsigned));
+define_field_type!(usize, GbnfFieldType::Primitive(GbnfPrimitive::Unsigned));
+
 define_field_type!(f32, GbnfFieldType::Primitive(GbnfPrimitive::Number));
 define_field_type!(f64, GbnfFieldType::Primitive(GbnfPrimitive::Number));
-define_field_type!(usize, GbnfFieldType::Primitive(GbnfPrimitive::Number));
 
 define_field_type!(bool, GbnfFieldType::Primitive(GbnfPrimitive::Boolean));
 
@@ -90,8 +134,16 @@ where
             OptionalPrimitive(primitive_type) => PrimitiveList(primitive_type),
             Complex(complex_type) => ComplexList(complex_type),
             OptionalComplex(complex_type) => ComplexList(complex_type),
-            Limited(_) => panic!("limited values are not yet supported"),
-            ComplexList(_) | PrimitiveList(_) => panic!("nested lists not supported"),
+            Limited(limited) => LimitedList(limited),
+            OptionalLimited(limited) => LimitedList(limited),
+            Union(union) => UnionList(union),
+            OptionalUnion(union) => UnionList(union),
+            Constrained(constrained) => ConstrainedList(constrained),
+            OptionalConstrained(constrained) => ConstrainedList(constrained),
+            ComplexList(_) | PrimitiveList(_) | LimitedList(_) | UnionList(_)
+            | ConstrainedList(_) => {
+                panic!("nested lists not supported")
+            }
         }
     }
 }
@@ -107,8 +159,16 @@ where
             OptionalPrimitive(primitive_type) => PrimitiveList(primitive_type),
             Complex(complex_type) => ComplexList(complex_type),
             OptionalComplex(complex_type) => ComplexList(complex_type),
-            Limited(_) => panic!("limited values not yet supported"),
-            ComplexList(_) | PrimitiveList(_) => panic!("nested lists not supported"),
+            Limited(limited) => LimitedList(limited),
+            OptionalLimited(limited) => LimitedList(limited),
+            Union(union) => UnionList(union),
+            OptionalUnion(union) => UnionList(union),
+            Constrained(constrained) => ConstrainedList(constrained),
+            OptionalConstrained(constrained) => ConstrainedList(constrained),
+            ComplexList(_) | PrimitiveList(_) | LimitedList(_) | UnionList(_)
+            | ConstrainedList(_) => {
+                panic!("nested lists not supported")
+            }
         }
     }
 }
@@ -122,8 +182,16 @@ where
         match <T as AsGbnf>::to_gbnf() {
             Primitive(primitive_type) => OptionalPrimitive(primitive_type),
             Complex(complex_type) => OptionalComplex(complex_type),
-            OptionalPrimitive(_) | OptionalComplex(_) => panic!("nested options are not allowed"),
-            Limited(_) => panic!("limited values not yet supported"),
+            Limited(limited) => OptionalLimited(limited),
+            Union(union) => OptionalUnion(union),
+            Constrained(constrained) => OptionalConstrained(constrained),
+            OptionalPrimitive(_)
+            | OptionalComplex(_)
+            | OptionalLimited(_)
+            | OptionalUnion(_)
+            | OptionalConstrained(_) => {
+                panic!("nested options are not allowed")
+            }
             _ => panic!("optional type cannot be a list"),
         }
     }
@@ -178,13 +246,36 @@ impl AsGrammar for GbnfToken {
 pub enum GbnfPrimitive {
     String,
     Boolean,
+
+    /// Any JSON number, including fractions and exponents.
     Number,
+
+    /// A whole number, which can be negative.
+    Integer,
+
+    /// A whole number that is never negative.
+    Unsigned,
 }
 
 impl GbnfPrimitive {
-    pub(self) const STRING: &'static str = r#""\""   ([^"]*)   "\"""#;
+    pub(self) const STRING: &'static str = r#""\""   char*   "\"""#;
     pub(self) const BOOLEAN: &'static str = r#""true" | "false""#;
-    pub(self) const NUMBER: &'static str = r#"[0-9]+   "."?   [0-9]*"#;
+    pub(self) const NUMBER: &'static str =
+        r#""-"?   ("0" | [1-9] [0-9]*)   ("."   [0-9]+)?   ([eE]   [-+]?   [0-9]+)?"#;
+    pub(self) const INTEGER: &'static str = r#""-"?   ("0" | [1-9] [0-9]*)"#;
+    pub(self) const UNSIGNED: &'static str = r#""0" | [1-9] [0-9]*"#;
+
+    /// A single character of a string: anything but a quote, a
+    /// backslash, or a control character, unless it is escaped.
+    pub(self) const CHAR: &'static str = concat!(
+        r#"[^"\\\x7F\x00-\x1F] | "\\"   "#,
+        r#"(["\\/bfnrt] | "u"   [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F])"#
+    );
+
+    /// The rule for a single character of a string.
+    pub(crate) fn char_rule() -> GbnfRule {
+        GbnfRule::new("char".to_string(), Self::CHAR.to_string())
+    }
 }
 
 impl AsGrammar for GbnfPrimitive {
@@ -193,10 +284,17 @@ impl AsGrammar for GbnfPrimitive {
         let rule_text = match self {
             Self::Boolean => Self::BOOLEAN,
             Self::Number => Self::NUMBER,
+            Self::Integer => Self::INTEGER,
+            Self::Unsigned => Self::UNSIGNED,
             Self::String => Self::STRING,
         };
 
-        GbnfRule::single(self.token(), rule_text.to_string())
+        let mut rules = GbnfRule::single(self.token(), rule_text.to_string());
+        if let Self::String = self {
+            rules.push(Self::char_rule());
+        }
+
+        rules
     }
 
     /// Output the token name of the GBNF rule (to refer to in other
@@ -205,11 +303,47 @@ impl AsGrammar for GbnfPrimitive {
         String::from(match self {
             Self::Boolean => "boolean",
             Self::Number => "number",
+            Self::Integer => "integer",
+            Self::Unsigned => "unsigned",
             Self::String => "string",
         })
     }
 }
 
+/// A value that can only be one of a fixed set, e.g. a unit-only
+/// enum. The values are rendered as literals of the primitive type.
+#[derive(Debug)]
+pub struct GbnfLimited {
+    pub name: String,
+    pub primitive: GbnfPrimitive,
+    pub values: Vec<String>,
+}
+
+impl GbnfLimited {
+    fn literal(&self, value: &str) -> String {
+        match self.primitive {
+            GbnfPrimitive::String => literal(&json_string(value)),
+            _ => literal(value),
+        }
+    }
+}
+
+impl AsGrammar for GbnfLimited {
+    fn rules(&self) -> Vec<GbnfRule> {
+        let alternatives = self
+            .values
+            .iter()
+            .map(|value| self.literal(value))
+            .join(" | ");
+
+        GbnfRule::single(self.token(), alternatives)
+    }
+
+    fn token(&self) -> String {
+        self.name.clone()
+    }
+}
+
 /// Categorize all types of fields that the generated grammar can
 /// handle.
 #[derive(Debug)]
@@ -234,7 +368,32 @@ pub enum GbnfFieldType {
 
     /// A single property field, but with limited values allowed,
     /// constrained by the primitive type.
-    Limited(GbnfPrimitive),
+    Limited(GbnfLimited),
+
+    /// Can be one of the limited values or null.
+    OptionalLimited(GbnfLimited),
+
+    /// A list/vec of limited values.
+    LimitedList(GbnfLimited),
+
+    /// One of several types, e.g. the variants of an enum with data.
+    Union(GbnfUnion),
+
+    /// Can be one of the types or null.
+    OptionalUnion(GbnfUnion),
+
+    /// A list/vec of unions.
+    UnionList(GbnfUnion),
+
+    /// A primitive with a constraint on its values, e.g. a maximum
+    /// length.
+    Constrained(GbnfConstrained),
+
+    /// Can be a constrained value or null.
+    OptionalConstrained(GbnfConstrained),
+
+    /// A list/vec of constrained values.
+    ConstrainedList(GbnfConstrained),
 }
 
 impl GbnfFieldType {
@@ -244,21 +403,42 @@ impl GbnfFieldType {
             _ => panic!("Not a GBNF complex type"),
         }
     }
-}
 
-/// Connect a property name and a field type to generate a GBNF rule.
-#[derive(Debug)]
-pub struct GbnfField {
-    pub field_name: String,
-    pub field_type: GbnfFieldType,
-}
+    /// Constrain the values of a primitive type, or of each item of
+    /// a list of them.
+    pub fn constrained(self, constraint: GbnfConstraint) -> Self {
+        use GbnfFieldType::*;
+        match self {
+            Primitive(primitive) => Constrained(GbnfConstrained::new(primitive, constraint)),
+            OptionalPrimitive(primitive) => {
+                OptionalConstrained(GbnfConstrained::new(primitive, constraint))
+            }
+            PrimitiveList(primitive) => {
+                ConstrainedList(GbnfConstrained::new(primitive, constraint))
+            }
+            _ => panic!("only primitive types can be constrained"),
+        }
+    }
+
+    fn is_optional(&self) -> bool {
+        matches!(
+            self,
+            GbnfFieldType::OptionalPrimitive(_)
+                | GbnfFieldType::OptionalComplex(_)
+                | GbnfFieldType::OptionalLimited(_)
+                | GbnfFieldType::OptionalUnion(_)
+                | GbnfFieldType::OptionalConstrained(_)
+        )
+    }
 
-impl GbnfField {
     fn list_rule(field_type: &(impl AsGrammar + ?Sized)) -> String {
-        r#""[]" | "["   {SPACE}   {TYPE_NAME}   (","   {SPACE}   {TYPE_NAME})*   "]""#
-            .replace("{LIST_NAME}", "")
-            .replace("{SPACE}", &GbnfToken::Space.token())
-            .replace("{TYPE_NAME}", &field_type.token())
+        concat!(
+            r#""["   {SPACE}   "]" | "["   {SPACE}   {TYPE_NAME}   "#,
+            r#"({SPACE}   ","   {SPACE}   {TYPE_NAME})*   {SPACE}   "]""#
+        )
+        .replace("{LIST_NAME}", "")
+        .replace("{SPACE}", &GbnfToken::Space.token())
+        .replace("{TYPE_NAME}", &field_type.token())
     }
 
     fn list_rules<T: AsGrammar>(&self, f: &T) -> Vec<GbnfRule> {
@@ -271,9 +451,9 @@ impl GbnfField {
     }
 }
 
-impl AsGrammar for GbnfField {
+impl AsGrammar for GbnfFieldType {
     fn token(&self) -> String {
-        match &self.field_type {
+        match self {
             GbnfFieldType::Primitive(f) => f.token(),
             GbnfFieldType::OptionalPrimitive(f) => f.token(),
             GbnfFieldType::PrimitiveList(f) => format!("{}List", f.token()),
@@ -281,14 +461,19 @@ impl AsGrammar for GbnfField {
             GbnfFieldType::OptionalComplex(f) => f.token(),
             GbnfFieldType::ComplexList(f) => format!("{}List", f.token()),
             GbnfFieldType::Limited(f) => f.token(),
-            _ => "".to_string(),
+            GbnfFieldType::OptionalLimited(f) => f.token(),
+            GbnfFieldType::LimitedList(f) => format!("{}List", f.token()),
+            GbnfFieldType::Union(f) => f.token(),
+            GbnfFieldType::OptionalUnion(f) => f.token(),
+            GbnfFieldType::UnionList(f) => format!("{}List", f.token()),
+            GbnfFieldType::Constrained(f) => f.token(),
+            GbnfFieldType::OptionalConstrained(f) => f.token(),
+            GbnfFieldType::ConstrainedList(f) => format!("{}List", f.token()),
         }
     }
 
-    // TODO need to implement optional rules, which probably involves
-    // wrapping the primitive rule in parens, and then ORing to null.
     fn rules(&self) -> Vec<GbnfRule> {
-        match &self.field_type {
+        match self {
             GbnfFieldType::Complex(f) => f.rules(),
             GbnfFieldType::OptionalComplex(f) => f.rules(),
             GbnfFieldType::ComplexList(f) => self.list_rules(f),
@@ -296,10 +481,100 @@ impl AsGrammar for GbnfField {
             GbnfFieldType::OptionalPrimitive(f) => f.rules(),
             GbnfFieldType::PrimitiveList(f) => self.list_rules(f),
             GbnfFieldType::Limited(f) => f.rules(),
+            GbnfFieldType::OptionalLimited(f) => f.rules(),
+            GbnfFieldType::LimitedList(f) => self.list_rules(f),
+            GbnfFieldType::Union(f) => f.rules(),
+            GbnfFieldType::OptionalUnion(f) => f.rules(),
+            GbnfFieldType::UnionList(f) => self.list_rules(f),
+            GbnfFieldType::Constrained(f) => f.rules(),
+            GbnfFieldType::OptionalConstrained(f) => f.rules(),
+            GbnfFieldType::ConstrainedList(f) => self.list_rules(f),
         }
     }
 }
 
+/// One of several types. Each alternative should be distinguishable
+/// from the others, e.g. by a tag field, as serde does for enums.
+#[derive(Debug)]
+pub struct GbnfUnion {
+    pub name: String,
+    pub alternatives: Vec<GbnfFieldType>,
+}
+
+impl AsGrammar for GbnfUnion {
+    fn rules(&self) -> Vec<GbnfRule> {
+        let alternatives = self
+            .alternatives
+            .iter()
+            .map(|alternative| alternative.token())
+            .join(" | ");
+
+        let mut rules = GbnfRule::single(self.token(), alternatives);
+        for alternative in &self.alternatives {
+            rules.append(&mut alternative.rules());
+        }
+
+        rules
+    }
+
+    fn token(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/// Connect a property name and a field type to generate a GBNF rule.
+#[derive(Debug)]
+pub struct GbnfField {
+    pub field_name: String,
+    pub field_type: GbnfFieldType,
+
+    /// The field can be left out of the object entirely. It should
+    /// be an `Option`, or have a serde default.
+    pub omittable: bool,
+}
+
+impl GbnfField {
+    pub fn omittable(mut self) -> Self {
+        self.omittable = true;
+        self
+    }
+
+    pub fn constrained(mut self, constraint: GbnfConstraint) -> Self {
+        self.field_type = self.field_type.constrained(constraint);
+        self
+    }
+
+    /// What the value of the field can be. Optional types can also
+    /// be null.
+    fn value(&self) -> String {
+        match self.field_type.is_optional() {
+            true => format!(r#"({} | "null")"#, self.token()),
+            false => self.token(),
+        }
+    }
+
+    /// The field's name and value, as it appears in the object.
+    fn key_value(&self) -> String {
+        format!(
+            r#"{}   {}   {}  {}"#,
+            GbnfToken::Space.token(),
+            literal(&format!("{}:", json_string(&self.field_name))),
+            GbnfToken::Space.token(),
+            self.value(),
+        )
+    }
+}
+
+impl AsGrammar for GbnfField {
+    fn token(&self) -> String {
+        self.field_type.token()
+    }
+
+    fn rules(&self) -> Vec<GbnfRule> {
+        self.field_type.rules()
+    }
+}
+
 /// The complex type is a direct mapping from a supported Rust struct,
 /// and also used to generate the root of a GBNF grammar.
 #[derive(Debug)]
@@ -309,15 +584,58 @@ pub struct GbnfComplex {
 }
 
 impl GbnfComplex {
+    /// The fields of the object, separated by commas. An omittable
+    /// field is left out along with its comma, which is the comma
+    /// before it, unless no required field comes before it. Each
+    /// field starts with whitespace, and so can each comma, so that
+    /// pretty-printed JSON is allowed.
+    fn fields_text(&self) -> String {
+        let first_required = self.fields.iter().position(|field| !field.omittable);
+        let space = GbnfToken::Space.token();
+
+        match first_required {
+            Some(first) => self
+                .fields
+                .iter()
+                .enumerate()
+                .map(|(index, field)| {
+                    let key_value = field.key_value();
+                    match (index.cmp(&first), field.omittable) {
+                        (Ordering::Less, _) => format!(r#"({}   {}   ",")?"#, key_value, space),
+                        (Ordering::Equal, _) => key_value,
+                        (Ordering::Greater, false) => format!(r#"{}   ","   {}"#, space, key_value),
+                        (Ordering::Greater, true) => {
+                            format!(r#"({}   ","   {})?"#, space, key_value)
+                        }
+                    }
+                })
+                .join("   "),
+
+            // Every field can be left out, so any of them can be the
+            // first one, followed by any of the ones after it.
+            None if !self.fields.is_empty() => {
+                let alternatives = (0..self.fields.len())
+                    .map(|first| {
+                        iter::once(self.fields[first].key_value())
+                            .chain(self.fields[first + 1..].iter().map(|field| {
+                                format!(r#"({}   ","   {})?"#, space, field.key_value())
+                            }))
+                            .join("   ")
+                    })
+                    .join(" | ");
+
+                format!("({})?", alternatives)
+            }
+
+            None => String::new(),
+        }
+    }
+
     pub fn to_grammar(&self) -> String {
         let mut rules = vec![GbnfRule::new("root".to_string(), self.name.clone())];
 
         rules.append(&mut self.rules());
 
-        for field in &self.fields {
-            rules.append(&mut field.rules());
-        }
-
         rules
             .into_iter()
             .unique()
@@ -330,32 +648,20 @@ impl AsGrammar for GbnfComplex {
     fn rules(&self) -> Vec<GbnfRule> {
         // This will output the full set of rules for the complex type.
         // Deduplication handled later.
-        let mut rule = String::new();
-
-        rule.push_str(r#""{"  "#);
-
-        let field_rules_text = self
-            .fields
-            .iter()
-            .map(|field| {
-                let mut text = String::new();
-                text.push_str(&GbnfToken::Space.token());
-                text.push_str("   ");
-                text.push_str(&format!(
-                    r#""\"{}\":"   {}  {}"#,
-                    field.field_name,
-                    GbnfToken::Space.token(),
-                    field.token(),
-                ));
-                text
-            })
-            .join(r#"   ","   "#);
-
-        rule.push_str(&field_rules_text);
-        rule.push_str(r#"   "}""#);
+        let rule = format!(
+            r#""{{"  {}   {}   "}}""#,
+            self.fields_text(),
+            GbnfToken::Space.token()
+        );
 
         let mut rules = GbnfRule::single(self.token(), rule);
         rules.append(&mut GbnfToken::Space.rules());
+
+        // Nested complex types bring the rules of their own fields.
+        for field in &self.fields {
+            rules.append(&mut field.rules());
+        }
+
         rules
     }
 
@@ -0,0 +1,379 @@
+//! Finds mistakes in a parsed grammar that the LLM backend would
+//! either reject when loading it, or silently accept.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::parser::{GbnfExpr, ParsedGrammar};
+
+/// Generation always starts at this rule.
+const ROOT: &str = "root";
+
+/// A problem with a grammar.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LintIssue {
+    /// There is no `root` rule to start generating from.
+    MissingRoot,
+
+    /// The rule is defined more than once.
+    DuplicateRule { rule: String },
+
+    /// The rule is referred to, but never defined.
+    UndefinedRule { rule: String, referenced_by: String },
+
+    /// No other rule refers to the rule.
+    UnusedRule { rule: String },
+
+    /// The rule is referred to, but only by rules that cannot be
+    /// reached from `root`.
+    UnreachableRule { rule: String },
+
+    /// The rule can refer to itself before it matches anything,
+    /// which the backends cannot generate from.
+    LeftRecursion { rule: String },
+}
+
+impl fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LintIssue::MissingRoot => write!(f, "there is no root rule"),
+            LintIssue::DuplicateRule { rule } => write!(f, "{} is defined more than once", rule),
+            LintIssue::UndefinedRule {
+                rule,
+                referenced_by,
+            } => write!(f, "{} is used by {}, but not defined", rule, referenced_by),
+            LintIssue::UnusedRule { rule } => write!(f, "{} is never used", rule),
+            LintIssue::UnreachableRule { rule } => {
+                write!(f, "{} cannot be reached from root", rule)
+            }
+            LintIssue::LeftRecursion { rule } => write!(f, "{} is left recursive", rule),
+        }
+    }
+}
+
+impl std::error::Error for LintIssue {}
+
+/// Every rule the expression refers to, in order of appearance.
+fn references<'a>(expr: &'a GbnfExpr, refs: &mut Vec<&'a str>) {
+    match expr {
+        GbnfExpr::RuleRef(name) => refs.push(name),
+        GbnfExpr::Sequence(exprs) | GbnfExpr::Alternation(exprs) => {
+            for expr in exprs {
+                references(expr, refs);
+            }
+        }
+        GbnfExpr::Repeat { expr, .. } => references(expr, refs),
+        GbnfExpr::Literal(_) | GbnfExpr::CharClass { .. } | GbnfExpr::AnyChar => (),
+    }
+}
+
+/// Whether the expression can match without consuming anything.
+fn is_nullable(expr: &GbnfExpr, nullable: &HashSet<&str>) -> bool {
+    match expr {
+        GbnfExpr::Literal(text) => text.is_empty(),
+        GbnfExpr::CharClass { .. } | GbnfExpr::AnyChar => false,
+        GbnfExpr::RuleRef(name) => nullable.contains(name.as_str()),
+        GbnfExpr::Sequence(exprs) => exprs.iter().all(|expr| is_nullable(expr, nullable)),
+        GbnfExpr::Alternation(exprs) => exprs.iter().any(|expr| is_nullable(expr, nullable)),
+        GbnfExpr::Repeat { expr, min, .. } => *min == 0 || is_nullable(expr, nullable),
+    }
+}
+
+/// The rules the expression can start with, before it has matched
+/// anything.
+fn left_references<'a>(expr: &'a GbnfExpr, nullable: &HashSet<&str>, refs: &mut Vec<&'a str>) {
+    match expr {
+        GbnfExpr::RuleRef(name) => refs.push(name),
+        GbnfExpr::Sequence(exprs) => {
+            for expr in exprs {
+                left_references(expr, nullable, refs);
+                if !is_nullable(expr, nullable) {
+                    break;
+                }
+            }
+        }
+        GbnfExpr::Alternation(exprs) => {
+            for expr in exprs {
+                left_references(expr, nullable, refs);
+            }
+        }
+        GbnfExpr::Repeat { expr, .. } => left_references(expr, nullable, refs),
+        GbnfExpr::Literal(_) | GbnfExpr::CharClass { .. } | GbnfExpr::AnyChar => (),
+    }
+}
+
+impl ParsedGrammar {
+    /// Every problem with the grammar, in the order of the rules.
+    pub fn lint(&self) -> Vec<LintIssue> {
+        let mut issues = vec![];
+        let mut definitions: HashMap<&str, &GbnfExpr> = HashMap::new();
+
+        for rule in &self.rules {
+            match definitions.contains_key(rule.name.as_str()) {
+                true => issues.push(LintIssue::DuplicateRule {
+                    rule: rule.name.clone(),
+                }),
+                false => {
+                    definitions.insert(&rule.name, &rule.expr);
+                }
+            }
+        }
+
+        let mut referenced_by_others = HashSet::new();
+        for rule in &self.rules {
+            let mut refs = vec![];
+            references(&rule.expr, &mut refs);
+
+            for name in refs {
+                if name != rule.name {
+                    referenced_by_others.insert(name);
+                }
+
+                let undefined = LintIssue::UndefinedRule {
+                    rule: name.to_string(),
+                    referenced_by: rule.name.clone(),
+                };
+
+                if !definitions.contains_key(name) && !issues.contains(&undefined) {
+                    issues.push(undefined);
+                }
+            }
+        }
+
+        if !definitions.contains_key(ROOT) {
+            issues.push(LintIssue::MissingRoot);
+        } else {
+            let reachable = self.reachable(&definitions);
+            for rule in &self.rules {
+                let name = rule.name.as_str();
+                if name == ROOT || reachable.contains(name) {
+                    continue;
+                }
+
+                let issue = match referenced_by_others.contains(name) {
+                    true => LintIssue::UnreachableRule {
+                        rule: rule.name.clone(),
+                    },
+                    false => LintIssue::UnusedRule {
+                        rule: rule.name.clone(),
+                    },
+                };
+
+                if !issues.contains(&issue) {
+                    issues.push(issue);
+                }
+            }
+        }
+
+        let nullable = Self::nullable(&definitions);
+        for rule in &self.rules {
+            let issue = LintIssue::LeftRecursion {
+                rule: rule.name.clone(),
+            };
+
+            if Self::is_left_recursive(&rule.name, &definitions, &nullable)
+                && !issues.contains(&issue)
+            {
+                issues.push(issue);
+            }
+        }
+
+        issues
+    }
+
+    /// The rules that generation from `root` can get to.
+    fn reachable<'a>(&self, definitions: &HashMap<&'a str, &'a GbnfExpr>) -> HashSet<&'a str> {
+        let mut reachable = HashSet::from([ROOT]);
+        let mut pending = vec![ROOT];
+
+        while let Some(name) = pending.pop() {
+            let mut refs = vec![];
+            if let Some(expr) = definitions.get(name) {
+                references(expr, &mut refs);
+            }
+
+            for name in refs {
+                if reachable.insert(name) {
+                    pending.push(name);
+                }
+            }
+        }
+
+        reachable
+    }
+
+    /// The rules that can match without consuming anything. Whether
+    /// one rule is depends on the rules it refers to, so this repeats
+    /// until nothing changes.
+    fn nullable<'a>(definitions: &HashMap<&'a str, &'a GbnfExpr>) -> HashSet<&'a str> {
+        let mut nullable = HashSet::new();
+
+        loop {
+            let found: Vec<&str> = definitions
+                .iter()
+                .filter(|(name, expr)| !nullable.contains(*name) && is_nullable(expr, &nullable))
+                .map(|(name, _)| *name)
+                .collect();
+
+            if found.is_empty() {
+                return nullable;
+            }
+
+            nullable.extend(found);
+        }
+    }
+
+    /// Whether the rule can get back to itself through the rules it
+    /// starts with.
+    fn is_left_recursive(
+        rule: &str,
+        definitions: &HashMap<&str, &GbnfExpr>,
+        nullable: &HashSet<&str>,
+    ) -> bool {
+        let mut visited = HashSet::new();
+        let mut pending = vec![rule];
+
+        while let Some(name) = pending.pop() {
+            let mut refs = vec![];
+            if let Some(expr) = definitions.get(name) {
+                left_references(expr, nullable, &mut refs);
+            }
+
+            for name in refs {
+                if name == rule {
+                    return true;
+                }
+
+                if visited.insert(name) {
+                    pending.push(name);
+                }
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lint(text: &str) -> Vec<LintIssue> {
+        ParsedGrammar::parse(text).unwrap().lint()
+    }
+
+    fn rule(name: &str) -> String {
+        name.to_string()
+    }
+
+    #[test]
+    fn accepts_a_clean_grammar() {
+        let grammar =
+            "root ::= \"[\" (item (\",\" item)*)? \"]\"\nitem ::= \"x\" | \"[\" item \"]\"";
+        assert_eq!(lint(grammar), vec![]);
+    }
+
+    #[test]
+    fn finds_a_missing_root() {
+        assert_eq!(lint("start ::= \"x\""), vec![LintIssue::MissingRoot]);
+    }
+
+    #[test]
+    fn finds_duplicate_rules() {
+        assert_eq!(
+            lint("root ::= a\na ::= \"x\"\na ::= \"y\""),
+            vec![LintIssue::DuplicateRule { rule: rule("a") }]
+        );
+    }
+
+    #[test]
+    fn finds_undefined_rules_once_per_rule_using_them() {
+        assert_eq!(
+            lint("root ::= a a b\nb ::= a"),
+            vec![
+                LintIssue::UndefinedRule {
+                    rule: rule("a"),
+                    referenced_by: rule("root"),
+                },
+                LintIssue::UndefinedRule {
+                    rule: rule("a"),
+                    referenced_by: rule("b"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_unused_rules() {
+        // Referring to itself does not make a rule used.
+        assert_eq!(
+            lint("root ::= \"x\"\nunused ::= \"y\" unused?"),
+            vec![LintIssue::UnusedRule {
+                rule: rule("unused"),
+            }]
+        );
+    }
+
+    #[test]
+    fn finds_unreachable_rules() {
+        assert_eq!(
+            lint("root ::= \"x\"\nunused ::= \"y\" orphan\norphan ::= \"z\""),
+            vec![
+                LintIssue::UnusedRule {
+                    rule: rule("unused"),
+                },
+                LintIssue::UnreachableRule {
+                    rule: rule("orphan"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_left_recursion() {
+        assert_eq!(
+            lint("root ::= root \"x\" | \"y\""),
+            vec![LintIssue::LeftRecursion { rule: rule("root") }]
+        );
+
+        // Through another rule, and past something that can be empty.
+        assert_eq!(
+            lint("root ::= ws item\nws ::= \" \"*\nitem ::= \"(\" | root"),
+            vec![
+                LintIssue::LeftRecursion { rule: rule("root") },
+                LintIssue::LeftRecursion { rule: rule("item") },
+            ]
+        );
+    }
+
+    #[test]
+    fn describes_issues() {
+        let cases = [
+            (LintIssue::MissingRoot, "there is no root rule"),
+            (
+                LintIssue::DuplicateRule { rule: rule("a") },
+                "a is defined more than once",
+            ),
+            (
+                LintIssue::UndefinedRule {
+                    rule: rule("a"),
+                    referenced_by: rule("root"),
+                },
+                "a is used by root, but not defined",
+            ),
+            (LintIssue::UnusedRule { rule: rule("a") }, "a is never used"),
+            (
+                LintIssue::UnreachableRule { rule: rule("a") },
+                "a cannot be reached from root",
+            ),
+            (
+                LintIssue::LeftRecursion { rule: rule("a") },
+                "a is left recursive",
+            ),
+        ];
+
+        for (issue, description) in cases {
+            assert_eq!(issue.to_string(), description);
+        }
+    }
+}
@@ -0,0 +1,687 @@
+//! Parses GBNF text, as llama.cpp and KoboldCPP accept it, into a
+//! syntax tree, so that a grammar can be checked before it is sent to
+//! the LLM.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// An expression on the right hand side of a rule.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GbnfExpr {
+    /// A literal string, with its escapes resolved.
+    Literal(String),
+
+    /// A character class, e.g. `[^"]`. Single characters are ranges
+    /// that start and end with the same character.
+    CharClass {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+
+    /// Any single character: `.`
+    AnyChar,
+
+    /// Another rule, by name.
+    RuleRef(String),
+
+    /// Expressions that follow each other. Empty matches nothing.
+    Sequence(Vec<GbnfExpr>),
+
+    /// Any one of the expressions: `a | b`.
+    Alternation(Vec<GbnfExpr>),
+
+    /// `?`, `*`, `+`, or `{min,max}`. No maximum means unbounded.
+    Repeat {
+        expr: Box<GbnfExpr>,
+        min: u32,
+        max: Option<u32>,
+    },
+}
+
+/// A character, escaped for a literal or a character class.
+pub(crate) fn escape_char(c: char, special: &[char]) -> String {
+    match c {
+        '\n' => "\\n".to_string(),
+        '\t' => "\\t".to_string(),
+        '\r' => "\\r".to_string(),
+        '\\' => "\\\\".to_string(),
+        c if special.contains(&c) => format!("\\{}", c),
+        c if c.is_control() => format!("\\x{:02X}", c as u32),
+        c => c.to_string(),
+    }
+}
+
+impl GbnfExpr {
+    /// Whether the expression needs parentheses to be repeated or
+    /// put in a sequence.
+    fn is_compound(&self) -> bool {
+        matches!(
+            self,
+            GbnfExpr::Sequence(_) | GbnfExpr::Alternation(_) | GbnfExpr::Repeat { .. }
+        )
+    }
+}
+
+/// Renders the expression as GBNF text.
+impl fmt::Display for GbnfExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GbnfExpr::Literal(text) => {
+                let text: String = text.chars().map(|c| escape_char(c, &['"'])).collect();
+                write!(f, "\"{}\"", text)
+            }
+            GbnfExpr::CharClass { negated, ranges } => {
+                write!(f, "[{}", if *negated { "^" } else { "" })?;
+                for (index, &(start, end)) in ranges.iter().enumerate() {
+                    // Dashes, and carets at the start, would be read
+                    // as part of the class syntax.
+                    let class_char = |c: char| match c {
+                        '-' => "\\x2D".to_string(),
+                        '^' if index == 0 && !negated => "\\x5E".to_string(),
+                        c => escape_char(c, &['[', ']']),
+                    };
+
+                    match start == end {
+                        true => write!(f, "{}", class_char(start))?,
+                        false => write!(f, "{}-{}", class_char(start), class_char(end))?,
+                    }
+                }
+                write!(f, "]")
+            }
+            GbnfExpr::AnyChar => write!(f, "."),
+            GbnfExpr::RuleRef(name) => write!(f, "{}", name),
+            GbnfExpr::Sequence(exprs) if exprs.is_empty() => write!(f, "\"\""),
+            GbnfExpr::Sequence(exprs) => {
+                for (index, expr) in exprs.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, "   ")?;
+                    }
+
+                    match expr {
+                        GbnfExpr::Alternation(_) => write!(f, "({})", expr)?,
+                        expr => write!(f, "{}", expr)?,
+                    }
+                }
+                Ok(())
+            }
+            GbnfExpr::Alternation(exprs) => {
+                for (index, expr) in exprs.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, " | ")?;
+                    }
+                    write!(f, "{}", expr)?;
+                }
+                Ok(())
+            }
+            GbnfExpr::Repeat { expr, min, max } => {
+                match expr.is_compound() {
+                    true => write!(f, "({})", expr)?,
+                    false => write!(f, "{}", expr)?,
+                }
+
+                match (min, max) {
+                    (0, Some(1)) => write!(f, "?"),
+                    (0, None) => write!(f, "*"),
+                    (1, None) => write!(f, "+"),
+                    (min, None) => write!(f, "{{{},}}", min),
+                    (min, Some(max)) if min == max => write!(f, "{{{}}}", min),
+                    (min, Some(max)) => write!(f, "{{{},{}}}", min, max),
+                }
+            }
+        }
+    }
+}
+
+/// A single `name ::= expression` definition.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedRule {
+    pub name: String,
+    pub expr: GbnfExpr,
+
+    /// The line the rule starts on, counting from 1.
+    pub line: usize,
+}
+
+/// A grammar as written, in the order the rules were defined.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedGrammar {
+    pub rules: Vec<ParsedRule>,
+}
+
+impl ParsedGrammar {
+    pub fn parse(text: &str) -> Result<ParsedGrammar, ParseError> {
+        Parser::new(text).grammar()
+    }
+
+    /// The first definition of a rule.
+    pub fn rule(&self, name: &str) -> Option<&ParsedRule> {
+        self.rules.iter().find(|rule| rule.name == name)
+    }
+}
+
+impl FromStr for ParsedGrammar {
+    type Err = ParseError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        ParsedGrammar::parse(text)
+    }
+}
+
+/// Where and why the grammar could not be parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+fn repeat(expr: GbnfExpr, (min, max): (u32, Option<u32>)) -> GbnfExpr {
+    GbnfExpr::Repeat {
+        expr: Box::new(expr),
+        min,
+        max,
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(text: &str) -> Parser {
+        Parser {
+            chars: text.chars().collect(),
+            pos: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn line(&self) -> usize {
+        self.chars[..self.pos]
+            .iter()
+            .filter(|&&c| c == '\n')
+            .count()
+            + 1
+    }
+
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        let line_start = self.chars[..self.pos]
+            .iter()
+            .rposition(|&c| c == '\n')
+            .map_or(0, |newline| newline + 1);
+
+        ParseError {
+            line: self.line(),
+            column: self.pos - line_start + 1,
+            message: message.into(),
+        }
+    }
+
+    /// Skip spaces and comments. A rule ends at the end of its line,
+    /// so newlines are only skipped where the rule must go on: inside
+    /// parentheses, and after `::=` or `|`.
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\r' => self.pos += 1,
+                '\n' if newlines => self.pos += 1,
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), ParseError> {
+        for (offset, c) in expected.chars().enumerate() {
+            if self.peek_at(offset) != Some(c) {
+                return Err(self.error(format!("expected `{}`", expected)));
+            }
+        }
+
+        self.pos += expected.chars().count();
+        Ok(())
+    }
+
+    fn grammar(mut self) -> Result<ParsedGrammar, ParseError> {
+        let mut rules = vec![];
+
+        loop {
+            self.skip_space(true);
+            match self.peek() {
+                Some(_) => rules.push(self.rule()?),
+                None => break,
+            }
+        }
+
+        Ok(ParsedGrammar { rules })
+    }
+
+    fn rule(&mut self) -> Result<ParsedRule, ParseError> {
+        let line = self.line();
+        let name = self.name()?;
+
+        self.skip_space(false);
+        self.expect("::=")?;
+        self.skip_space(true);
+
+        let expr = self.alternation(false)?;
+
+        match self.peek() {
+            None | Some('\n') => Ok(ParsedRule { name, expr, line }),
+            Some(c) => Err(self.error(format!("unexpected `{}`", c))),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, ParseError> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_name_char) {
+            self.pos += 1;
+        }
+
+        match start == self.pos {
+            true => Err(self.error("expected a rule name")),
+            false => Ok(self.chars[start..self.pos].iter().collect()),
+        }
+    }
+
+    fn alternation(&mut self, nested: bool) -> Result<GbnfExpr, ParseError> {
+        let mut alternatives = vec![self.sequence(nested)?];
+
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            self.skip_space(true);
+            alternatives.push(self.sequence(nested)?);
+        }
+
+        match alternatives.len() {
+            1 => Ok(alternatives.remove(0)),
+            _ => Ok(GbnfExpr::Alternation(alternatives)),
+        }
+    }
+
+    fn sequence(&mut self, nested: bool) -> Result<GbnfExpr, ParseError> {
+        let mut items = vec![];
+
+        loop {
+            let item = match self.peek() {
+                Some('"') => self.literal()?,
+                Some('[') => self.char_class()?,
+                Some('.') => {
+                    self.pos += 1;
+                    GbnfExpr::AnyChar
+                }
+                Some('(') => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let group = self.alternation(true)?;
+                    self.expect(")")?;
+                    group
+                }
+                Some(c) if is_name_char(c) => GbnfExpr::RuleRef(self.name()?),
+                _ => break,
+            };
+
+            items.push(self.repetition(item)?);
+            self.skip_space(nested);
+        }
+
+        match items.len() {
+            1 => Ok(items.remove(0)),
+            _ => Ok(GbnfExpr::Sequence(items)),
+        }
+    }
+
+    fn repetition(&mut self, expr: GbnfExpr) -> Result<GbnfExpr, ParseError> {
+        let bounds = match self.peek() {
+            Some('?') => (0, Some(1)),
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('{') => return Ok(repeat(expr, self.bounds()?)),
+            _ => return Ok(expr),
+        };
+
+        self.pos += 1;
+        Ok(repeat(expr, bounds))
+    }
+
+    /// `{n}`, `{min,}`, `{,max}`, or `{min,max}`.
+    fn bounds(&mut self) -> Result<(u32, Option<u32>), ParseError> {
+        self.pos += 1;
+        self.skip_space(false);
+        let min = self.integer()?;
+        self.skip_space(false);
+
+        let bounds = match self.peek() {
+            Some(',') => {
+                self.pos += 1;
+                self.skip_space(false);
+                let max = self.integer()?;
+                self.skip_space(false);
+                (min.unwrap_or(0), max)
+            }
+            _ => match min {
+                Some(count) => (count, Some(count)),
+                None => return Err(self.error("expected a number")),
+            },
+        };
+
+        self.expect("}")?;
+        Ok(bounds)
+    }
+
+    fn integer(&mut self) -> Result<Option<u32>, ParseError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+
+        if start == self.pos {
+            return Ok(None);
+        }
+
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        match digits.parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => Err(self.error(format!("{} is too large", digits))),
+        }
+    }
+
+    fn literal(&mut self) -> Result<GbnfExpr, ParseError> {
+        self.pos += 1;
+        let mut text = String::new();
+
+        loop {
+            match self.peek() {
+                None | Some('\n') => return Err(self.error("unterminated string")),
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(GbnfExpr::Literal(text));
+                }
+                Some('\\') => text.push(self.escape()?),
+                Some(c) => {
+                    self.pos += 1;
+                    text.push(c);
+                }
+            }
+        }
+    }
+
+    fn char_class(&mut self) -> Result<GbnfExpr, ParseError> {
+        self.pos += 1;
+
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+
+        let mut ranges = vec![];
+        while let Some(start) = self.class_char()? {
+            // A dash right before the end is just a dash.
+            let end = match (self.peek(), self.peek_at(1)) {
+                (Some('-'), Some(next)) if next != ']' => {
+                    self.pos += 1;
+                    self.class_char()?
+                        .ok_or_else(|| self.error("expected the end of the range"))?
+                }
+                _ => start,
+            };
+
+            ranges.push((start, end));
+        }
+
+        Ok(GbnfExpr::CharClass { negated, ranges })
+    }
+
+    /// The next character of a character class. None at its end.
+    fn class_char(&mut self) -> Result<Option<char>, ParseError> {
+        match self.peek() {
+            None | Some('\n') => Err(self.error("unterminated character class")),
+            Some(']') => {
+                self.pos += 1;
+                Ok(None)
+            }
+            Some('\\') => self.escape().map(Some),
+            Some(c) => {
+                self.pos += 1;
+                Ok(Some(c))
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, ParseError> {
+        self.pos += 1;
+        let c = self
+            .peek()
+            .ok_or_else(|| self.error("unterminated escape"))?;
+        self.pos += 1;
+
+        match c {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            'x' => self.hex(2),
+            'u' => self.hex(4),
+            'U' => self.hex(8),
+            '\\' | '"' | '[' | ']' => Ok(c),
+            _ => Err(self.error(format!("unknown escape `\\{}`", c))),
+        }
+    }
+
+    fn hex(&mut self, digits: usize) -> Result<char, ParseError> {
+        let end = (self.pos + digits).min(self.chars.len());
+        let text: String = self.chars[self.pos..end].iter().collect();
+
+        let c = u32::from_str_radix(&text, 16)
+            .ok()
+            .filter(|_| text.len() == digits)
+            .and_then(char::from_u32)
+            .ok_or_else(|| self.error(format!("invalid escape `{}`", text)))?;
+
+        self.pos = end;
+        Ok(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_expr(text: &str) -> GbnfExpr {
+        let grammar = ParsedGrammar::parse(&format!("root ::= {}", text)).unwrap();
+        grammar.rules[0].expr.clone()
+    }
+
+    fn literal(text: &str) -> GbnfExpr {
+        GbnfExpr::Literal(text.to_string())
+    }
+
+    fn rule_ref(name: &str) -> GbnfExpr {
+        GbnfExpr::RuleRef(name.to_string())
+    }
+
+    #[test]
+    fn parses_rules_in_order() {
+        let grammar =
+            ParsedGrammar::parse("root ::= item\n\n# a comment\nitem ::= \"x\"\n").unwrap();
+
+        let names: Vec<_> = grammar
+            .rules
+            .iter()
+            .map(|rule| rule.name.as_str())
+            .collect();
+        assert_eq!(names, ["root", "item"]);
+        assert_eq!(grammar.rule("item").unwrap().line, 4);
+        assert_eq!(grammar.rule("item").unwrap().expr, literal("x"));
+        assert!(grammar.rule("missing").is_none());
+    }
+
+    #[test]
+    fn parses_expressions() {
+        assert_eq!(
+            parse_expr(r#""a" b | ."#),
+            GbnfExpr::Alternation(vec![
+                GbnfExpr::Sequence(vec![literal("a"), rule_ref("b")]),
+                GbnfExpr::AnyChar,
+            ])
+        );
+
+        assert_eq!(
+            parse_expr(r#"("a" | "b")+"#),
+            repeat(
+                GbnfExpr::Alternation(vec![literal("a"), literal("b")]),
+                (1, None)
+            )
+        );
+
+        assert_eq!(
+            parse_expr(r#"[^"\\a-z]"#),
+            GbnfExpr::CharClass {
+                negated: true,
+                ranges: vec![('"', '"'), ('\\', '\\'), ('a', 'z')],
+            }
+        );
+    }
+
+    #[test]
+    fn parses_repetitions() {
+        let cases = [
+            ("a?", (0, Some(1))),
+            ("a*", (0, None)),
+            ("a+", (1, None)),
+            ("a{3}", (3, Some(3))),
+            ("a{2,}", (2, None)),
+            ("a{,4}", (0, Some(4))),
+            ("a{ 2 , 4 }", (2, Some(4))),
+        ];
+
+        for (text, bounds) in cases {
+            assert_eq!(parse_expr(text), repeat(rule_ref("a"), bounds), "{}", text);
+        }
+    }
+
+    #[test]
+    fn resolves_escapes() {
+        assert_eq!(parse_expr(r#""\x41\u00e9\n\t\"\\""#), literal("Aé\n\t\"\\"));
+        assert_eq!(
+            parse_expr(r#"[\x2D\]]"#),
+            GbnfExpr::CharClass {
+                negated: false,
+                ranges: vec![('-', '-'), (']', ']')],
+            }
+        );
+    }
+
+    #[test]
+    fn continues_rules_after_alternations_and_in_parentheses() {
+        let grammar =
+            ParsedGrammar::parse("root ::= \"a\" |\n  \"b\"\nnext ::= (\n  \"c\"\n  \"d\"\n)")
+                .unwrap();
+
+        assert_eq!(
+            grammar.rules[0].expr,
+            GbnfExpr::Alternation(vec![literal("a"), literal("b")])
+        );
+        assert_eq!(
+            grammar.rules[1].expr,
+            GbnfExpr::Sequence(vec![literal("c"), literal("d")])
+        );
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let cases = [
+            r#""a"   b | c"#,
+            r#"("a" | "b")*   "c""#,
+            r#"[^"\\\x7F\x00-\x1F]"#,
+            r#"[\x2D\x5Ea-z]"#,
+            r#"x{3}   y{2,}   z{0,4}   w?   v+"#,
+            r#""say \"hi\"\n""#,
+            r#""""#,
+            r#"("a"   "b")?"#,
+            ".",
+        ];
+
+        for text in cases {
+            let expr = parse_expr(text);
+            let rendered = expr.to_string();
+            assert_eq!(
+                parse_expr(&rendered),
+                expr,
+                "{} rendered as {}",
+                text,
+                rendered
+            );
+        }
+    }
+
+    #[test]
+    fn renders_gbnf() {
+        assert_eq!(
+            parse_expr(r#"("a" | "b")*   "c""#).to_string(),
+            r#"("a" | "b")*   "c""#
+        );
+        assert_eq!(parse_expr("[-a]").to_string(), r#"[\x2Da]"#);
+        assert_eq!(parse_expr("x{2,4}").to_string(), "x{2,4}");
+    }
+
+    #[test]
+    fn reports_where_parsing_failed() {
+        let cases = [
+            ("root ::= \"abc", 1, 14, "unterminated string"),
+            ("root = \"a\"", 1, 6, "expected `::=`"),
+            ("root ::= \"a\"\n::= \"b\"", 2, 1, "expected a rule name"),
+            ("root ::= \"\\q\"", 1, 13, "unknown escape `\\q`"),
+            ("root ::= \"a\" )", 1, 14, "unexpected `)`"),
+            ("root ::= [abc", 1, 14, "unterminated character class"),
+            ("root ::= a{}", 1, 12, "expected a number"),
+        ];
+
+        for (text, line, column, message) in cases {
+            let err = ParsedGrammar::parse(text).unwrap_err();
+            assert_eq!(
+                err,
+                ParseError {
+                    line,
+                    column,
+                    message: message.to_string(),
+                },
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn parse_errors_display_their_position() {
+        let err = "root ::= \"abc".parse::<ParsedGrammar>().unwrap_err();
+        assert_eq!(err.to_string(), "line 1, column 14: unterminated string");
+    }
+}
@@ -0,0 +1,593 @@
+//! Converts a regular expression into GBNF for the contents of a JSON
+//! string, for fields with a `pattern`. Only the common subset of
+//! regular expressions is supported: literals, `.`, character classes
+//! and the `\d`, `\w`, and `\s` shorthands, groups, alternatives, and
+//! repetition. The whole string must match, as if the pattern was
+//! anchored at both ends.
+
+use std::fmt;
+
+use crate::parser::GbnfExpr;
+
+/// Characters that can only appear in a JSON string escaped.
+const NEEDS_ESCAPE: [(char, char); 4] =
+    [('\x00', '\x1F'), ('"', '"'), ('\\', '\\'), ('\x7F', '\x7F')];
+
+/// Why a pattern could not be converted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatternError {
+    /// Character offset in the pattern.
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid pattern at {}: {}", self.position, self.message)
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+/// The GBNF for the contents of a JSON string that matches the
+/// pattern. A leading `^` and a trailing `$` are allowed, but make no
+/// difference.
+pub fn pattern_to_gbnf(pattern: &str) -> Result<GbnfExpr, PatternError> {
+    let mut chars: Vec<char> = pattern.chars().collect();
+    let mut start = 0;
+
+    if chars.first() == Some(&'^') {
+        start = 1;
+    }
+
+    if chars.len() > start && ends_with_anchor(pattern) {
+        chars.pop();
+    }
+
+    let mut parser = PatternParser { chars, pos: start };
+    let expr = parser.alternation()?;
+
+    match parser.peek() {
+        None => Ok(expr),
+        Some(c) => Err(parser.error(format!("unexpected `{}`", c))),
+    }
+}
+
+/// Whether the pattern ends with a `$` anchor, rather than a `$`
+/// escaped by an odd number of backslashes.
+fn ends_with_anchor(pattern: &str) -> bool {
+    match pattern.strip_suffix('$') {
+        Some(rest) => rest.chars().rev().take_while(|&c| c == '\\').count() % 2 == 0,
+        None => false,
+    }
+}
+
+/// The pattern as JSON Schema reads it, which is not anchored.
+pub fn anchored_pattern(pattern: &str) -> String {
+    let pattern = pattern.strip_prefix('^').unwrap_or(pattern);
+    let pattern = match ends_with_anchor(pattern) {
+        true => &pattern[..pattern.len() - 1],
+        false => pattern,
+    };
+
+    format!("^(?:{})$", pattern)
+}
+
+/// `expr{min,max}`, spelled out with only `?` and `*`, which every
+/// GBNF implementation understands: `x{2,4}` is `x x (x x?)?`.
+pub(crate) fn bounded(expr: GbnfExpr, min: u32, max: Option<u32>) -> GbnfExpr {
+    let mut items = vec![expr.clone(); min as usize];
+
+    match max {
+        None => items.push(GbnfExpr::Repeat {
+            expr: Box::new(expr),
+            min: 0,
+            max: None,
+        }),
+        Some(max) => {
+            let mut optional = None;
+            for _ in min..max {
+                let mut sequence = vec![expr.clone()];
+                sequence.extend(optional);
+
+                let inner = match sequence.len() {
+                    1 => sequence.remove(0),
+                    _ => GbnfExpr::Sequence(sequence),
+                };
+
+                optional = Some(GbnfExpr::Repeat {
+                    expr: Box::new(inner),
+                    min: 0,
+                    max: Some(1),
+                });
+            }
+
+            items.extend(optional);
+        }
+    }
+
+    match items.len() {
+        1 => items.remove(0),
+        _ => GbnfExpr::Sequence(items),
+    }
+}
+
+/// The character as it is written in a JSON string.
+fn json_escaped(c: char) -> String {
+    match c {
+        '"' => "\\\"".to_string(),
+        '\\' => "\\\\".to_string(),
+        '\n' => "\\n".to_string(),
+        '\t' => "\\t".to_string(),
+        '\r' => "\\r".to_string(),
+        '\x08' => "\\b".to_string(),
+        '\x0C' => "\\f".to_string(),
+        c if needs_escape(c) => format!("\\u{:04x}", c as u32),
+        c => c.to_string(),
+    }
+}
+
+fn needs_escape(c: char) -> bool {
+    NEEDS_ESCAPE
+        .iter()
+        .any(|&(start, end)| start <= c && c <= end)
+}
+
+/// Any character that can appear in a JSON string unescaped.
+fn any_char() -> GbnfExpr {
+    GbnfExpr::CharClass {
+        negated: true,
+        ranges: NEEDS_ESCAPE.to_vec(),
+    }
+}
+
+/// A character class of the pattern, as it appears in a JSON string.
+/// Characters that must be escaped are taken out of the class, and
+/// become alternatives of their own. A negated class never matches
+/// them.
+fn json_class(negated: bool, ranges: Vec<(char, char)>) -> GbnfExpr {
+    if negated {
+        let mut ranges = ranges;
+        ranges.extend(NEEDS_ESCAPE);
+        return GbnfExpr::CharClass { negated, ranges };
+    }
+
+    let mut safe = vec![];
+    let mut escaped = vec![];
+
+    for (start, end) in ranges {
+        let mut run_start = None;
+        for c in start..=end {
+            match (needs_escape(c), run_start) {
+                (true, Some(run)) => {
+                    safe.push((run, char::from_u32(c as u32 - 1).unwrap_or(run)));
+                    run_start = None;
+                    escaped.push(GbnfExpr::Literal(json_escaped(c)));
+                }
+                (true, None) => escaped.push(GbnfExpr::Literal(json_escaped(c))),
+                (false, None) => run_start = Some(c),
+                (false, Some(_)) => (),
+            }
+        }
+
+        if let Some(run) = run_start {
+            safe.push((run, end));
+        }
+    }
+
+    let mut alternatives = vec![];
+    if !safe.is_empty() {
+        alternatives.push(GbnfExpr::CharClass {
+            negated: false,
+            ranges: safe,
+        });
+    }
+    alternatives.extend(escaped);
+
+    match alternatives.len() {
+        1 => alternatives.remove(0),
+        _ => GbnfExpr::Alternation(alternatives),
+    }
+}
+
+const DIGITS: [(char, char); 1] = [('0', '9')];
+const WORD: [(char, char); 4] = [('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+const SPACE: [(char, char); 4] = [(' ', ' '), ('\t', '\t'), ('\n', '\n'), ('\r', '\r')];
+
+/// The ranges of a shorthand class like `\d`, and whether it is
+/// negated.
+fn shorthand(c: char) -> Option<(bool, &'static [(char, char)])> {
+    match c {
+        'd' => Some((false, &DIGITS)),
+        'D' => Some((true, &DIGITS)),
+        'w' => Some((false, &WORD)),
+        'W' => Some((true, &WORD)),
+        's' => Some((false, &SPACE)),
+        'S' => Some((true, &SPACE)),
+        _ => None,
+    }
+}
+
+struct PatternParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl PatternParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn error(&self, message: impl Into<String>) -> PatternError {
+        PatternError {
+            position: self.pos,
+            message: message.into(),
+        }
+    }
+
+    fn alternation(&mut self) -> Result<GbnfExpr, PatternError> {
+        let mut alternatives = vec![self.sequence()?];
+
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alternatives.push(self.sequence()?);
+        }
+
+        match alternatives.len() {
+            1 => Ok(alternatives.remove(0)),
+            _ => Ok(GbnfExpr::Alternation(alternatives)),
+        }
+    }
+
+    fn sequence(&mut self) -> Result<GbnfExpr, PatternError> {
+        let mut items: Vec<GbnfExpr> = vec![];
+
+        while let Some(c) = self.peek() {
+            let atom = match c {
+                '|' | ')' => break,
+                '(' => self.group()?,
+                '[' => self.class()?,
+                '.' => {
+                    self.pos += 1;
+                    any_char()
+                }
+                '\\' => self.escape()?,
+                '*' | '+' | '?' | '{' => return Err(self.error("nothing to repeat")),
+                '^' | '$' => return Err(self.error("anchors are only allowed at the ends")),
+                c => {
+                    self.pos += 1;
+                    GbnfExpr::Literal(json_escaped(c))
+                }
+            };
+
+            let item = self.quantifier(atom)?;
+
+            // Runs of plain characters become a single literal.
+            match (items.last_mut(), &item) {
+                (Some(GbnfExpr::Literal(text)), GbnfExpr::Literal(next)) => text.push_str(next),
+                _ => items.push(item),
+            }
+        }
+
+        match items.len() {
+            1 => Ok(items.remove(0)),
+            _ => Ok(GbnfExpr::Sequence(items)),
+        }
+    }
+
+    fn group(&mut self) -> Result<GbnfExpr, PatternError> {
+        self.pos += 1;
+
+        if self.peek() == Some('?') {
+            match self.chars.get(self.pos + 1) {
+                Some(':') => self.pos += 2,
+                _ => return Err(self.error("only non-capturing groups are supported")),
+            }
+        }
+
+        let expr = self.alternation()?;
+        match self.peek() {
+            Some(')') => {
+                self.pos += 1;
+                Ok(expr)
+            }
+            _ => Err(self.error("unclosed group")),
+        }
+    }
+
+    fn quantifier(&mut self, atom: GbnfExpr) -> Result<GbnfExpr, PatternError> {
+        let (min, max) = match self.peek() {
+            Some('{') => self.bounds()?,
+            Some(c) => {
+                let bounds = match c {
+                    '?' => (0, Some(1)),
+                    '*' => (0, None),
+                    '+' => (1, None),
+                    _ => return Ok(atom),
+                };
+
+                self.pos += 1;
+                bounds
+            }
+            None => return Ok(atom),
+        };
+
+        // Lazy and greedy repetition match the same strings.
+        if self.peek() == Some('?') {
+            self.pos += 1;
+        }
+
+        if max.is_some_and(|max| max < min) {
+            return Err(self.error("repetition maximum is less than its minimum"));
+        }
+
+        Ok(bounded(atom, min, max))
+    }
+
+    /// `{n}`, `{min,}`, or `{min,max}`.
+    fn bounds(&mut self) -> Result<(u32, Option<u32>), PatternError> {
+        self.pos += 1;
+        let min = self
+            .integer()
+            .ok_or_else(|| self.error("expected a number"))?;
+
+        let max = match self.peek() {
+            Some(',') => {
+                self.pos += 1;
+                self.integer()
+            }
+            _ => Some(min),
+        };
+
+        match self.peek() {
+            Some('}') => {
+                self.pos += 1;
+                Ok((min, max))
+            }
+            _ => Err(self.error("expected `}`")),
+        }
+    }
+
+    fn integer(&mut self) -> Option<u32> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        digits.parse().ok()
+    }
+
+    /// An escaped character outside of a class.
+    fn escape(&mut self) -> Result<GbnfExpr, PatternError> {
+        self.pos += 1;
+        let c = self
+            .peek()
+            .ok_or_else(|| self.error("pattern ends in the middle of an escape"))?;
+        self.pos += 1;
+
+        if let Some((negated, ranges)) = shorthand(c) {
+            return Ok(json_class(negated, ranges.to_vec()));
+        }
+
+        Ok(GbnfExpr::Literal(json_escaped(self.escaped_char(c)?)))
+    }
+
+    /// The character an escape like `\n` or `\.` stands for.
+    fn escaped_char(&self, c: char) -> Result<char, PatternError> {
+        match c {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            c if c.is_ascii_alphanumeric() => {
+                Err(self.error(format!("unsupported escape `\\{}`", c)))
+            }
+            c => Ok(c),
+        }
+    }
+
+    fn class(&mut self) -> Result<GbnfExpr, PatternError> {
+        self.pos += 1;
+
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+
+        let mut ranges = vec![];
+        let mut first = true;
+
+        loop {
+            let c = self
+                .peek()
+                .ok_or_else(|| self.error("unclosed character class"))?;
+            self.pos += 1;
+
+            // A bracket right at the start is part of the class.
+            let start = match c {
+                ']' if !first => break,
+                '\\' => {
+                    let escaped = self
+                        .peek()
+                        .ok_or_else(|| self.error("unclosed character class"))?;
+                    self.pos += 1;
+
+                    match shorthand(escaped) {
+                        Some((false, shorthand)) => {
+                            ranges.extend(shorthand);
+                            first = false;
+                            continue;
+                        }
+                        Some((true, _)) => {
+                            return Err(
+                                self.error("negated shorthands are not supported in classes")
+                            )
+                        }
+                        None => self.escaped_char(escaped)?,
+                    }
+                }
+                c => c,
+            };
+
+            first = false;
+
+            let is_range = self.peek() == Some('-')
+                && self
+                    .chars
+                    .get(self.pos + 1)
+                    .is_some_and(|&next| next != ']');
+
+            match is_range {
+                true => {
+                    self.pos += 1;
+                    let end = match self.peek() {
+                        Some('\\') => {
+                            self.pos += 1;
+                            let escaped = self
+                                .peek()
+                                .ok_or_else(|| self.error("unclosed character class"))?;
+                            self.escaped_char(escaped)?
+                        }
+                        Some(c) => c,
+                        None => return Err(self.error("unclosed character class")),
+                    };
+                    self.pos += 1;
+
+                    if end < start {
+                        return Err(self.error("range is out of order"));
+                    }
+                    ranges.push((start, end));
+                }
+                false => ranges.push((start, start)),
+            }
+        }
+
+        Ok(json_class(negated, ranges))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GbnfRecognizer, ParsedGrammar, Recognition};
+
+    /// Recognizes the contents of JSON strings that match the
+    /// pattern, as they are written in the JSON.
+    fn recognizer(pattern: &str) -> GbnfRecognizer {
+        let expr = pattern_to_gbnf(pattern).unwrap();
+        let grammar = ParsedGrammar::parse(&format!("root ::= {}", expr)).unwrap();
+        GbnfRecognizer::new(&grammar).unwrap()
+    }
+
+    #[test]
+    fn matches_json_string_contents() {
+        let cases: [(&str, &[&str], &[&str]); 10] = [
+            (
+                r"\d{2,4}",
+                &["12", "123", "1234"],
+                &["", "1", "12345", "1a"],
+            ),
+            (r"\d{3}", &["123"], &["12", "1234"]),
+            (r"[A-Z][a-z]+", &["Bob"], &["B", "bob", "BOB"]),
+            (r"^(?:ab|cd)?$", &["", "ab", "cd"], &["abcd", "a"]),
+            (r"\w+\s\w+", &["ab cd", r"ab\ncd"], &["abcd", "ab\ncd"]),
+            (r#"[a"]+"#, &["a", r#"\""#, r#"a\"a"#], &[r#"""#, "b"]),
+            (r"[\\x]", &[r"\\", "x"], &[r"\", "y"]),
+            (r#"[ -#]"#, &[" ", "!", r#"\""#, "#"], &[r#"""#, "$"]),
+            (r#"[^"]+"#, &["abc", "é"], &[r#"""#, r#"\""#, r"\\"]),
+            (r#"say "\w+""#, &[r#"say \"hi\""#], &[r#"say "hi""#]),
+        ];
+
+        for (pattern, accepted, rejected) in cases {
+            let recognizer = recognizer(pattern);
+            for text in accepted {
+                assert_eq!(
+                    recognizer.recognize(text),
+                    Recognition::Complete,
+                    "{} should match {}",
+                    pattern,
+                    text
+                );
+            }
+
+            for text in rejected {
+                assert_ne!(
+                    recognizer.recognize(text),
+                    Recognition::Complete,
+                    "{} should not match {}",
+                    pattern,
+                    text
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn spells_out_bounded_repetition() {
+        assert_eq!(
+            pattern_to_gbnf(r"\d{2,4}").unwrap().to_string(),
+            "[0-9]   [0-9]   ([0-9]   [0-9]?)?"
+        );
+        assert_eq!(
+            pattern_to_gbnf("a{2,}").unwrap().to_string(),
+            r#""a"   "a"   "a"*"#
+        );
+    }
+
+    #[test]
+    fn escapes_quotes_and_backslashes_in_classes() {
+        assert_eq!(
+            pattern_to_gbnf(r#"[a"\\]"#).unwrap().to_string(),
+            r#"[a] | "\\\"" | "\\\\""#
+        );
+    }
+
+    #[test]
+    fn reports_unsupported_patterns() {
+        let cases = [
+            ("(ab", 3, "unclosed group"),
+            ("(?<name>a)", 1, "only non-capturing groups are supported"),
+            ("*a", 0, "nothing to repeat"),
+            ("a^b", 1, "anchors are only allowed at the ends"),
+            ("a{3,2}", 6, "repetition maximum is less than its minimum"),
+            ("a{x}", 2, "expected a number"),
+            ("[ab", 3, "unclosed character class"),
+            (r"\q", 2, r"unsupported escape `\q`"),
+            ("a)", 1, "unexpected `)`"),
+        ];
+
+        for (pattern, position, message) in cases {
+            assert_eq!(
+                pattern_to_gbnf(pattern).unwrap_err(),
+                PatternError {
+                    position,
+                    message: message.to_string(),
+                },
+                "{}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn anchors_patterns_for_json_schema() {
+        assert_eq!(anchored_pattern("[a-z]+"), "^(?:[a-z]+)$");
+        assert_eq!(anchored_pattern("^a|b$"), "^(?:a|b)$");
+        assert_eq!(anchored_pattern(r"a\$"), r"^(?:a\$)$");
+        assert_eq!(anchored_pattern(r"a\\$"), r"^(?:a\\)$");
+        assert_eq!(anchored_pattern(r"a\\\$"), r"^(?:a\\\$)$");
+    }
+
+    #[test]
+    fn strips_anchors_after_escaped_backslashes() {
+        // A backslash, then the end of the string.
+        let backslash = recognizer(r"a\\$");
+        assert_eq!(backslash.recognize(r"a\\"), Recognition::Complete);
+        assert_ne!(backslash.recognize(r"a\\$"), Recognition::Complete);
+
+        // A backslash, then a dollar sign.
+        let dollar = recognizer(r"a\\\$");
+        assert_eq!(dollar.recognize(r"a\\$"), Recognition::Complete);
+        assert_ne!(dollar.recognize(r"a\\"), Recognition::Complete);
+    }
+}
@@ -0,0 +1,417 @@
+//! Checks text against a grammar without an LLM backend: whether the
+//! grammar accepts it, or could accept it if more text followed, or
+//! where it stopped following the grammar. Works like the grammar
+//! sampler of llama.cpp, which tracks every way the text so far can
+//! be matched as a stack of positions in the rules.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::lint::LintIssue;
+use crate::parser::{GbnfExpr, ParsedGrammar};
+use crate::GbnfComplex;
+
+/// How text relates to a grammar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recognition {
+    /// The grammar accepts the text as it is.
+    Complete,
+
+    /// The text is not accepted yet, but it is the start of text that
+    /// is.
+    Prefix,
+
+    /// No text that starts like this is accepted. The position is
+    /// the byte offset of the first character that does not fit.
+    Diverged { position: usize },
+}
+
+/// A single thing to match in a sequence.
+#[derive(Debug, Clone)]
+enum Element {
+    Chars {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+    Rule(usize),
+}
+
+impl Element {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Element::Chars { negated, ranges } => {
+                let in_class = ranges.iter().any(|&(start, end)| start <= c && c <= end);
+                in_class != *negated
+            }
+            Element::Rule(_) => false,
+        }
+    }
+}
+
+/// Rule, alternative, and index of the next element to match.
+type Frame = (usize, usize, usize);
+
+/// The frames of the rules being matched, innermost last. Empty once
+/// the root rule is done.
+type Stack = Vec<Frame>;
+
+/// Stacks deeper than this are dropped, so that grammars that
+/// repeat something which can match nothing still finish.
+const MAX_STACK_DEPTH: usize = 256;
+
+/// A grammar, compiled for recognizing text. Repetitions and groups
+/// become rules of their own, so that every rule is a list of
+/// alternatives, and every alternative a list of characters to match
+/// and rules.
+#[derive(Debug, Clone)]
+pub struct GbnfRecognizer {
+    rules: Vec<Vec<Vec<Element>>>,
+    root: usize,
+}
+
+impl GbnfRecognizer {
+    /// Compile a grammar. It must have a root rule, and must not be
+    /// left recursive or refer to rules it does not define.
+    pub fn new(grammar: &ParsedGrammar) -> Result<GbnfRecognizer, LintIssue> {
+        let blocking = grammar.lint().into_iter().find(|issue| {
+            matches!(
+                issue,
+                LintIssue::MissingRoot
+                    | LintIssue::UndefinedRule { .. }
+                    | LintIssue::LeftRecursion { .. }
+            )
+        });
+
+        if let Some(issue) = blocking {
+            return Err(issue);
+        }
+
+        let mut compiler = Compiler::default();
+        for rule in &grammar.rules {
+            let index = compiler.indices.len();
+            compiler.indices.entry(&rule.name).or_insert(index);
+        }
+
+        compiler.rules = vec![vec![]; compiler.indices.len()];
+        for rule in &grammar.rules {
+            let index = compiler.indices[rule.name.as_str()];
+
+            // Later definitions of the same rule are ignored.
+            if compiler.rules[index].is_empty() {
+                compiler.rules[index] = compiler.alternatives(&rule.expr);
+            }
+        }
+
+        Ok(GbnfRecognizer {
+            root: compiler.indices["root"],
+            rules: compiler.rules,
+        })
+    }
+
+    pub fn recognize(&self, text: &str) -> Recognition {
+        let mut stacks = HashSet::new();
+        for alternative in 0..self.rules[self.root].len() {
+            self.expand(vec![(self.root, alternative, 0)], &mut stacks);
+        }
+
+        for (position, c) in text.char_indices() {
+            let mut next = HashSet::new();
+
+            for stack in &stacks {
+                let Some(&(rule, alternative, index)) = stack.last() else {
+                    continue;
+                };
+
+                if self.rules[rule][alternative][index].matches(c) {
+                    let mut stack = stack.clone();
+                    if let Some(top) = stack.last_mut() {
+                        top.2 += 1;
+                    }
+
+                    self.expand(stack, &mut next);
+                }
+            }
+
+            if next.is_empty() {
+                return Recognition::Diverged { position };
+            }
+
+            stacks = next;
+        }
+
+        match stacks.iter().any(|stack| stack.is_empty()) {
+            true => Recognition::Complete,
+            false => Recognition::Prefix,
+        }
+    }
+
+    /// Follow the stack into rules and out of finished alternatives,
+    /// until it is at characters to match, or at the end of the root
+    /// rule.
+    fn expand(&self, stack: Stack, stacks: &mut HashSet<Stack>) {
+        let mut seen = HashSet::new();
+        self.expand_into(stack, stacks, &mut seen);
+    }
+
+    fn expand_into(
+        &self,
+        mut stack: Stack,
+        stacks: &mut HashSet<Stack>,
+        seen: &mut HashSet<Stack>,
+    ) {
+        if stack.len() > MAX_STACK_DEPTH || !seen.insert(stack.clone()) {
+            return;
+        }
+
+        let Some(&(rule, alternative, index)) = stack.last() else {
+            stacks.insert(stack);
+            return;
+        };
+
+        let elements = &self.rules[rule][alternative];
+
+        // The alternative is done, so the rule that referred to it
+        // goes on after the reference.
+        if index == elements.len() {
+            stack.pop();
+            if let Some(parent) = stack.last_mut() {
+                parent.2 += 1;
+            }
+
+            return self.expand_into(stack, stacks, seen);
+        }
+
+        let child = match elements[index] {
+            Element::Chars { .. } => {
+                stacks.insert(stack);
+                return;
+            }
+            Element::Rule(child) => child,
+        };
+
+        // A rule at the end of an alternative replaces it, since
+        // nothing is left to do after it. This keeps repetitions,
+        // which refer to themselves at the end, from growing the
+        // stack.
+        if index + 1 == elements.len() {
+            stack.pop();
+        }
+
+        for alternative in 0..self.rules[child].len() {
+            let mut next = stack.clone();
+            next.push((child, alternative, 0));
+            self.expand_into(next, stacks, seen);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Compiler<'a> {
+    indices: HashMap<&'a str, usize>,
+    rules: Vec<Vec<Vec<Element>>>,
+}
+
+impl Compiler<'_> {
+    fn add_rule(&mut self, alternatives: Vec<Vec<Element>>) -> Element {
+        self.rules.push(alternatives);
+        Element::Rule(self.rules.len() - 1)
+    }
+
+    fn alternatives(&mut self, expr: &GbnfExpr) -> Vec<Vec<Element>> {
+        match expr {
+            GbnfExpr::Alternation(exprs) => exprs.iter().map(|expr| self.sequence(expr)).collect(),
+            expr => vec![self.sequence(expr)],
+        }
+    }
+
+    fn sequence(&mut self, expr: &GbnfExpr) -> Vec<Element> {
+        let mut elements = vec![];
+        self.append(expr, &mut elements);
+        elements
+    }
+
+    fn append(&mut self, expr: &GbnfExpr, elements: &mut Vec<Element>) {
+        match expr {
+            GbnfExpr::Literal(text) => {
+                elements.extend(text.chars().map(|c| Element::Chars {
+                    negated: false,
+                    ranges: vec![(c, c)],
+                }));
+            }
+            GbnfExpr::Sequence(exprs) => {
+                for expr in exprs {
+                    self.append(expr, elements);
+                }
+            }
+            expr => elements.push(self.element(expr)),
+        }
+    }
+
+    /// A single element that matches the expression.
+    fn element(&mut self, expr: &GbnfExpr) -> Element {
+        match expr {
+            GbnfExpr::CharClass { negated, ranges } => Element::Chars {
+                negated: *negated,
+                ranges: ranges.clone(),
+            },
+            GbnfExpr::AnyChar => Element::Chars {
+                negated: true,
+                ranges: vec![],
+            },
+            GbnfExpr::RuleRef(name) => Element::Rule(self.indices[name.as_str()]),
+            GbnfExpr::Repeat { expr, min, max } => self.repeat(expr, *min, *max),
+            expr => {
+                let alternatives = self.alternatives(expr);
+                self.add_rule(alternatives)
+            }
+        }
+    }
+
+    /// `x{2,4}` becomes `x x (x x?)?`, and `x{2,}` becomes `x x r`,
+    /// with `r ::= x r | `.
+    fn repeat(&mut self, expr: &GbnfExpr, min: u32, max: Option<u32>) -> Element {
+        let item = self.element(expr);
+        let mut elements = vec![item.clone(); min as usize];
+
+        match max {
+            None => {
+                let index = self.rules.len();
+                self.rules.push(vec![]);
+                self.rules[index] = vec![vec![item, Element::Rule(index)], vec![]];
+                elements.push(Element::Rule(index));
+            }
+            Some(max) => {
+                let mut optional = None;
+                for _ in min..max {
+                    let mut sequence = vec![item.clone()];
+                    sequence.extend(optional);
+                    optional = Some(self.add_rule(vec![sequence, vec![]]));
+                }
+
+                elements.extend(optional);
+            }
+        }
+
+        match elements.len() {
+            1 => elements.remove(0),
+            _ => self.add_rule(vec![elements]),
+        }
+    }
+}
+
+impl GbnfComplex {
+    /// A recognizer for the grammar of the type.
+    pub fn recognizer(&self) -> GbnfRecognizer {
+        let grammar =
+            ParsedGrammar::parse(&self.to_grammar()).expect("generated grammars always parse");
+
+        GbnfRecognizer::new(&grammar).expect("generated grammars are never left recursive")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GbnfField, GbnfFieldType, GbnfLimited, GbnfPrimitive};
+
+    fn field(name: &str, field_type: GbnfFieldType) -> GbnfField {
+        GbnfField {
+            field_name: name.to_string(),
+            field_type,
+            omittable: false,
+        }
+    }
+
+    /// `{"a": {"b": 1}, "c": ["x"]}`
+    fn nested() -> GbnfComplex {
+        let inner = GbnfComplex {
+            name: "Inner".to_string(),
+            fields: vec![field(
+                "b",
+                GbnfFieldType::Primitive(GbnfPrimitive::Unsigned),
+            )],
+        };
+
+        GbnfComplex {
+            name: "Outer".to_string(),
+            fields: vec![
+                field("a", GbnfFieldType::Complex(inner)),
+                field("c", GbnfFieldType::PrimitiveList(GbnfPrimitive::String)),
+            ],
+        }
+    }
+
+    #[test]
+    fn recognizes_compact_json() {
+        let recognizer = nested().recognizer();
+        assert_eq!(
+            recognizer.recognize(r#"{"a": {"b": 1}, "c": ["x", "y"]}"#),
+            Recognition::Complete
+        );
+        assert_eq!(
+            recognizer.recognize(r#"{"a": {"b": 1}, "c": []}"#),
+            Recognition::Complete
+        );
+    }
+
+    #[test]
+    fn recognizes_pretty_printed_json() {
+        let recognizer = nested().recognizer();
+        let pretty = "{\n  \"a\": {\n    \"b\": 1\n  },\n  \"c\": [\n    \"x\"\n  ]\n}";
+        assert_eq!(recognizer.recognize(pretty), Recognition::Complete);
+        assert_eq!(
+            recognizer.recognize("{\n  \"a\": {\n    \"b\": 1\n  },\n  \"c\": [ ]\n}"),
+            Recognition::Complete
+        );
+    }
+
+    #[test]
+    fn recognizes_truncated_pretty_printed_json() {
+        let recognizer = nested().recognizer();
+        assert_eq!(
+            recognizer.recognize("{\n  \"a\": {\n    \"b\": 1\n  },"),
+            Recognition::Prefix
+        );
+        assert_eq!(
+            recognizer.recognize("{\n  \"a\": {\n    \"b\": 1\n  }\n"),
+            Recognition::Prefix
+        );
+    }
+
+    #[test]
+    fn finds_where_json_diverges() {
+        let recognizer = nested().recognizer();
+        assert_eq!(
+            recognizer.recognize(r#"{"a": {"b": -1}}"#),
+            Recognition::Diverged { position: 12 }
+        );
+        assert_eq!(
+            recognizer.recognize(r#"{"a": {"b": 1}}"#),
+            Recognition::Diverged { position: 14 }
+        );
+    }
+
+    #[test]
+    fn escapes_quotes_and_backslashes() {
+        let quoted = GbnfLimited {
+            name: "Quoted".to_string(),
+            primitive: GbnfPrimitive::String,
+            values: vec![r#"say "hi""#.to_string(), r"C:\dir".to_string()],
+        };
+
+        let complex = GbnfComplex {
+            name: "Escaped".to_string(),
+            fields: vec![field(r#"a "b" \c"#, GbnfFieldType::Limited(quoted))],
+        };
+
+        let recognizer = complex.recognizer();
+        for value in [r#"say "hi""#, r"C:\dir"] {
+            let json = serde_json::json!({ r#"a "b" \c"#: value }).to_string();
+            assert_eq!(recognizer.recognize(&json), Recognition::Complete);
+        }
+
+        assert!(matches!(
+            recognizer.recognize(r#"{"a "b" \c": "say hi"}"#),
+            Recognition::Diverged { .. }
+        ));
+    }
+}
@@ -0,0 +1,288 @@
+//! Checks that the JSON a grammar allows has the shape of a type,
+//! for tests of hand-written or generated grammars. Documents are
+//! generated from the grammar until every alternative has been taken,
+//! and each is deserialized into the type.
+
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::lint::LintIssue;
+use crate::parser::{GbnfExpr, ParseError, ParsedGrammar};
+
+/// The most documents that are generated from a grammar, in case its
+/// alternatives are nested so deeply that taking them all would take
+/// too long.
+const MAX_SAMPLES: usize = 1024;
+
+/// Rules deeper than this are assumed to recurse forever.
+const MAX_DEPTH: usize = 64;
+
+/// Why a grammar does not match a type.
+#[derive(Debug)]
+pub enum ShapeError {
+    Parse(ParseError),
+    Lint(Vec<LintIssue>),
+
+    /// Generating from the grammar did not end.
+    TooDeep,
+
+    /// The grammar allows a document that does not deserialize.
+    Mismatch {
+        sample: String,
+        error: String,
+    },
+}
+
+impl fmt::Display for ShapeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShapeError::Parse(err) => write!(f, "grammar does not parse: {}", err),
+            ShapeError::Lint(issues) => {
+                let issues: Vec<_> = issues.iter().map(|issue| issue.to_string()).collect();
+                write!(f, "grammar has problems: {}", issues.join("; "))
+            }
+            ShapeError::TooDeep => write!(f, "grammar recurses too deeply"),
+            ShapeError::Mismatch { sample, error } => {
+                write!(
+                    f,
+                    "grammar allows {}, which does not deserialize: {}",
+                    sample, error
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ShapeError {}
+
+/// Check that the grammar is free of problems, and that what it
+/// generates deserializes into `T`. Meant for tests:
+///
+/// ```ignore
+/// gbnf::check_shape::<SceneSeed>(SceneSeed::to_grammar()).unwrap();
+/// ```
+pub fn check_shape<T: DeserializeOwned>(grammar: &str) -> Result<(), ShapeError> {
+    let grammar = ParsedGrammar::parse(grammar).map_err(ShapeError::Parse)?;
+
+    let issues = grammar.lint();
+    if !issues.is_empty() {
+        return Err(ShapeError::Lint(issues));
+    }
+
+    // Every document but the first needs at least one alternative it
+    // has not taken yet, and documents alternate between repeating as
+    // little and as much as they have to, so at least two are needed.
+    let mut sampler = Sampler::new(&grammar);
+    let mut samples = vec![];
+    for index in 0..MAX_SAMPLES {
+        let sample = sampler.sample(index % 2 == 0)?;
+        if !samples.contains(&sample) {
+            samples.push(sample);
+        }
+
+        if index > 0 && sampler.is_covered() {
+            break;
+        }
+    }
+
+    for sample in samples {
+        if let Err(err) = serde_json::from_str::<T>(&sample) {
+            return Err(ShapeError::Mismatch {
+                sample,
+                error: err.to_string(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Generates documents from a grammar. Each alternation takes its
+/// alternatives in turn, carrying on where it left off in the last
+/// document, so that every alternative is eventually taken.
+struct Sampler<'a> {
+    rules: HashMap<&'a str, &'a GbnfExpr>,
+
+    /// Whether everything is repeated as few times as possible, or at
+    /// least once.
+    fewest_repeats: bool,
+
+    /// How many times each alternation has been expanded, and how
+    /// many alternatives it has, by its address in the grammar.
+    alternations: HashMap<*const GbnfExpr, (usize, usize)>,
+    depth: usize,
+}
+
+impl<'a> Sampler<'a> {
+    fn new(grammar: &'a ParsedGrammar) -> Sampler<'a> {
+        // Linting made sure that every rule is defined, and only
+        // once.
+        let rules = grammar
+            .rules
+            .iter()
+            .map(|rule| (rule.name.as_str(), &rule.expr))
+            .collect();
+
+        Sampler {
+            rules,
+            fewest_repeats: true,
+            alternations: HashMap::new(),
+            depth: 0,
+        }
+    }
+
+    fn sample(&mut self, fewest_repeats: bool) -> Result<String, ShapeError> {
+        self.fewest_repeats = fewest_repeats;
+        self.depth = 0;
+
+        let mut out = String::new();
+        self.expand_rule("root", &mut out)?;
+        Ok(out)
+    }
+
+    /// Whether every alternative of the alternations reached so far
+    /// has been taken.
+    fn is_covered(&self) -> bool {
+        self.alternations
+            .values()
+            .all(|&(expanded, alternatives)| expanded >= alternatives)
+    }
+
+    fn expand_rule(&mut self, name: &str, out: &mut String) -> Result<(), ShapeError> {
+        let expr = self.rules[name];
+
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(ShapeError::TooDeep);
+        }
+
+        self.expand(expr, out)?;
+        self.depth -= 1;
+        Ok(())
+    }
+
+    fn expand(&mut self, expr: &GbnfExpr, out: &mut String) -> Result<(), ShapeError> {
+        match expr {
+            GbnfExpr::Literal(text) => out.push_str(text),
+            GbnfExpr::CharClass { negated, ranges } => out.push(class_char(*negated, ranges)),
+            GbnfExpr::AnyChar => out.push('a'),
+            GbnfExpr::RuleRef(name) => self.expand_rule(name, out)?,
+            GbnfExpr::Sequence(exprs) => {
+                for expr in exprs {
+                    self.expand(expr, out)?;
+                }
+            }
+            GbnfExpr::Alternation(exprs) => {
+                let (expanded, _) = self
+                    .alternations
+                    .entry(expr as *const GbnfExpr)
+                    .or_insert((0, exprs.len()));
+
+                let choice = *expanded % exprs.len();
+                *expanded += 1;
+                self.expand(&exprs[choice], out)?;
+            }
+            GbnfExpr::Repeat { expr, min, max } => {
+                let count = match self.fewest_repeats {
+                    true => *min,
+                    false => (*min).max(1).min(max.unwrap_or(u32::MAX)),
+                };
+
+                for _ in 0..count {
+                    self.expand(expr, out)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A character the class matches. Negated classes usually exclude
+/// quotes or brackets, so a plain letter or digit will do.
+fn class_char(negated: bool, ranges: &[(char, char)]) -> char {
+    let in_class = |c: char| ranges.iter().any(|&(start, end)| start <= c && c <= end);
+
+    match negated {
+        false => ranges.first().map_or('a', |&(start, _)| start),
+        true => ('a'..='z')
+            .chain('0'..='9')
+            .find(|&c| !in_class(c))
+            .unwrap_or(' '),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_derive::Deserialize;
+
+    #[allow(dead_code)]
+    #[derive(Deserialize)]
+    struct Counted {
+        count: u8,
+    }
+
+    #[test]
+    fn accepts_a_grammar_of_the_type() {
+        let grammar = r#"root ::= "{\"count\": "   ("0" | [1-9] [0-9]?)   "}""#;
+        check_shape::<Counted>(grammar).unwrap();
+        check_shape::<Vec<u8>>(r#"root ::= "["   ("1"   (", "   "2")*)?   "]""#).unwrap();
+    }
+
+    #[test]
+    fn takes_every_alternative() {
+        // Only the last of many alternatives does not deserialize.
+        let mut counts: Vec<String> = (0..40).map(|count| format!("\"{}\"", count)).collect();
+        counts.push(r#""\"forty\"""#.to_string());
+        let grammar = format!(
+            r#"root ::= "{{\"count\": "   ({})   "}}""#,
+            counts.join(" | ")
+        );
+
+        match check_shape::<Counted>(&grammar) {
+            Err(ShapeError::Mismatch { sample, .. }) => {
+                assert_eq!(sample, r#"{"count": "forty"}"#)
+            }
+            other => panic!("expected a mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn takes_alternatives_inside_repetitions() {
+        let grammar =
+            "root ::= \"[\"   (item   (\",\"   item)*)?   \"]\"\nitem ::= \"1\" | \"2\" | \"null\"";
+        match check_shape::<Vec<u8>>(grammar) {
+            Err(ShapeError::Mismatch { sample, .. }) => assert!(sample.contains("null")),
+            other => panic!("expected a mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_grammars_that_do_not_parse_or_lint() {
+        assert!(matches!(
+            check_shape::<Counted>("root ::= \"{"),
+            Err(ShapeError::Parse(_))
+        ));
+
+        match check_shape::<Counted>("root ::= item") {
+            Err(ShapeError::Lint(issues)) => assert_eq!(
+                issues,
+                vec![LintIssue::UndefinedRule {
+                    rule: "item".to_string(),
+                    referenced_by: "root".to_string(),
+                }]
+            ),
+            other => panic!("expected lint issues, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stops_on_endless_recursion() {
+        assert!(matches!(
+            check_shape::<Counted>(r#"root ::= "["   root   "]""#),
+            Err(ShapeError::TooDeep)
+        ));
+    }
+}
@@ -0,0 +1,8 @@
+//! The derive rejects types whose grammar it cannot generate, or
+//! would generate wrongly, at compile time.
+
+#[test]
+fn unsupported_types_do_not_compile() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/*.rs");
+}
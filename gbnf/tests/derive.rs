@@ -0,0 +1,346 @@
+//! The derive follows serde's attributes, so the grammar of a type
+//! accepts exactly the JSON that serde makes of it.
+
+use gbnf::prelude::*;
+use gbnf::{GbnfRecognizer, ParsedGrammar, Recognition};
+use gbnf_derive::Gbnf;
+use serde::Serialize;
+use serde_derive::{Deserialize, Serialize};
+
+/// The text of a rule in the grammar.
+fn rule<'a>(grammar: &'a str, name: &str) -> &'a str {
+    let prefix = format!("{} ::= ", name);
+    grammar
+        .lines()
+        .find_map(|line| line.strip_prefix(&prefix))
+        .unwrap_or_else(|| panic!("no rule {} in:\n{}", name, grammar))
+}
+
+fn recognize(grammar: &str, json: &str) -> Recognition {
+    let grammar = ParsedGrammar::parse(grammar).unwrap();
+    GbnfRecognizer::new(&grammar).unwrap().recognize(json)
+}
+
+/// The grammar accepts what serde serializes the value to.
+fn assert_accepts<T: Serialize>(grammar: &str, value: &T) {
+    let json = serde_json::to_string(value).unwrap();
+    assert_eq!(recognize(grammar, &json), Recognition::Complete, "{}", json);
+}
+
+fn assert_rejects(grammar: &str, json: &str) {
+    assert!(
+        matches!(recognize(grammar, json), Recognition::Diverged { .. }),
+        "{}",
+        json
+    );
+}
+
+#[derive(Serialize, Deserialize, Gbnf)]
+#[serde(rename_all = "snake_case")]
+enum Mood {
+    Happy,
+    NotSure,
+    #[serde(rename = "blue")]
+    Sad,
+    #[serde(skip)]
+    #[allow(dead_code)]
+    Hidden,
+}
+
+#[derive(Serialize, Deserialize, Gbnf)]
+struct Feeling {
+    mood: Mood,
+    moods: Vec<Mood>,
+}
+
+#[test]
+fn unit_enums_are_their_variant_names() {
+    let grammar = Feeling::to_grammar();
+    assert_eq!(
+        rule(grammar, "Mood"),
+        r#""\"happy\"" | "\"not_sure\"" | "\"blue\"""#
+    );
+
+    for mood in [Mood::Happy, Mood::NotSure, Mood::Sad] {
+        let moods = vec![Mood::Happy, Mood::Sad];
+        assert_accepts(grammar, &Feeling { mood, moods });
+    }
+
+    assert_rejects(grammar, r#"{"mood": "hidden", "moods": []}"#);
+    assert_rejects(grammar, r#"{"mood": "Happy", "moods": []}"#);
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+#[derive(Serialize, Deserialize, Gbnf)]
+struct Lamp {
+    name: String,
+    color: Option<String>,
+
+    #[gbnf(omittable)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner: Option<String>,
+
+    #[serde(default, skip_serializing_if = "is_zero")]
+    brightness: u32,
+}
+
+#[derive(Serialize, Deserialize, Gbnf, Default)]
+#[serde(default)]
+struct LampSettings {
+    #[serde(skip_serializing_if = "is_zero")]
+    brightness: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timer: Option<u32>,
+}
+
+#[test]
+fn options_can_be_null() {
+    let grammar = Lamp::to_grammar();
+    assert!(rule(grammar, "Lamp").contains(r#""\"color\":"   ws  (string | "null")"#));
+
+    let lamp = Lamp {
+        name: "desk".to_string(),
+        color: None,
+        owner: None,
+        brightness: 0,
+    };
+
+    assert_accepts(grammar, &lamp);
+    assert_rejects(grammar, r#"{"name": null, "color": null}"#);
+    assert_rejects(grammar, r#"{"name": "desk"}"#);
+}
+
+#[test]
+fn omittable_and_defaulted_fields_can_be_left_out() {
+    let grammar = Lamp::to_grammar();
+
+    for (owner, brightness) in [(None, 0), (Some("Ann"), 0), (None, 7), (Some("Ann"), 7)] {
+        let lamp = Lamp {
+            name: "desk".to_string(),
+            color: Some("red".to_string()),
+            owner: owner.map(String::from),
+            brightness,
+        };
+
+        assert_accepts(grammar, &lamp);
+    }
+
+    assert_rejects(
+        grammar,
+        r#"{"name": "desk", "color": null, "brightness": null}"#,
+    );
+}
+
+#[test]
+fn fields_of_defaulted_structs_can_be_left_out() {
+    let grammar = LampSettings::to_grammar();
+
+    for (brightness, timer) in [(0, None), (3, None), (0, Some(60)), (3, Some(60))] {
+        assert_accepts(grammar, &LampSettings { brightness, timer });
+    }
+
+    assert_eq!(recognize(grammar, "{}"), Recognition::Complete);
+    assert_eq!(recognize(grammar, "{ }"), Recognition::Complete);
+}
+
+#[derive(Serialize, Deserialize, Gbnf)]
+#[serde(rename_all = "camelCase")]
+struct Person {
+    first_name: String,
+
+    #[serde(rename = "surname")]
+    last_name: String,
+
+    #[serde(rename(serialize = "years", deserialize = "years"))]
+    age: u32,
+
+    #[serde(skip)]
+    #[allow(dead_code)]
+    secret: String,
+
+    r#type: String,
+
+    #[serde(rename = r#"motto "in quotes" \ slashed"#)]
+    motto: String,
+}
+
+#[test]
+fn fields_are_renamed_and_skipped_like_serde() {
+    let grammar = Person::to_grammar();
+    let person = rule(grammar, "Person");
+    for key in [
+        r#""\"firstName\":""#,
+        r#""\"surname\":""#,
+        r#""\"years\":""#,
+        r#""\"type\":""#,
+        r#""\"motto \\\"in quotes\\\" \\\\ slashed\":""#,
+    ] {
+        assert!(person.contains(key), "{} not in {}", key, person);
+    }
+
+    assert!(!person.contains("secret"));
+
+    let person = Person {
+        first_name: "Ann".to_string(),
+        last_name: "Lee".to_string(),
+        age: 40,
+        secret: "hidden".to_string(),
+        r#type: "smith".to_string(),
+        motto: r#"she said "hammer" \ then left"#.to_string(),
+    };
+
+    assert_accepts(grammar, &person);
+    assert_rejects(
+        grammar,
+        r#"{"first_name": "Ann", "surname": "Lee", "years": 40, "type": "", "motto": ""}"#,
+    );
+}
+
+#[derive(Serialize, Deserialize, Gbnf)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    Wait,
+    Say(String),
+    Give { item: String, count: u32 },
+}
+
+#[derive(Serialize, Deserialize, Gbnf)]
+#[serde(
+    tag = "type",
+    rename_all = "snake_case",
+    rename_all_fields = "camelCase"
+)]
+enum Event {
+    DoorOpened { door_name: String },
+    Silence,
+}
+
+#[derive(Serialize, Deserialize, Gbnf)]
+#[serde(tag = "kind", content = "data")]
+enum Message {
+    Text(String),
+    Ping,
+    Point { x: i32, y: i32 },
+}
+
+#[derive(Serialize, Deserialize, Gbnf)]
+struct Turn {
+    action: Action,
+    events: Vec<Event>,
+    message: Message,
+}
+
+fn turns() -> Vec<Turn> {
+    let actions = [
+        Action::Wait,
+        Action::Say("hello".to_string()),
+        Action::Give {
+            item: "mug".to_string(),
+            count: 2,
+        },
+    ];
+
+    let messages = [
+        Message::Text("hi".to_string()),
+        Message::Ping,
+        Message::Point { x: -1, y: 2 },
+    ];
+
+    actions
+        .into_iter()
+        .zip(messages)
+        .map(|(action, message)| Turn {
+            action,
+            events: vec![
+                Event::DoorOpened {
+                    door_name: "front".to_string(),
+                },
+                Event::Silence,
+            ],
+            message,
+        })
+        .collect()
+}
+
+#[test]
+fn externally_tagged_variants_are_keyed_by_name() {
+    let grammar = Turn::to_grammar();
+    assert_eq!(
+        rule(grammar, "Action"),
+        "ActionWait | ActionSay | ActionGive"
+    );
+    assert_eq!(rule(grammar, "ActionWait"), r#""\"wait\"""#);
+    assert!(rule(grammar, "ActionGive").contains(r#""\"give\":"   ws  ActionGiveFields"#));
+
+    for turn in turns() {
+        assert_accepts(grammar, &turn);
+    }
+}
+
+#[test]
+fn internally_tagged_variants_have_a_tag_field() {
+    let grammar = Turn::to_grammar();
+    assert_eq!(rule(grammar, "EventDoorOpenedTag"), r#""\"door_opened\"""#);
+    assert!(rule(grammar, "EventDoorOpened").contains(r#""\"doorName\":""#));
+
+    let turn = r#"{"action": "wait", "events": [%], "message": {"kind": "Ping"}}"#;
+    assert_eq!(
+        recognize(grammar, &turn.replace('%', r#"{"type": "silence"}"#)),
+        Recognition::Complete
+    );
+    assert_rejects(grammar, &turn.replace('%', r#"{"type": "Silence"}"#));
+    assert_rejects(grammar, &turn.replace('%', r#"{"type": "door_opened"}"#));
+}
+
+#[test]
+fn adjacently_tagged_variants_have_a_tag_and_content_field() {
+    let grammar = Turn::to_grammar();
+    assert!(rule(grammar, "MessageText").contains(r#""\"data\":"   ws  string"#));
+    assert!(!rule(grammar, "MessagePing").contains("data"));
+
+    let turn = r#"{"action": "wait", "events": [], "message": %}"#;
+    assert_rejects(grammar, &turn.replace('%', r#"{"kind": "Text"}"#));
+    assert_rejects(grammar, &turn.replace('%', r#"{"Text": "hi"}"#));
+}
+
+type Count = u32;
+type Name = String;
+
+#[derive(Serialize, Deserialize, Gbnf)]
+struct Shelf {
+    #[gbnf(range = 1..=9)]
+    count: Count,
+    #[gbnf(range = -5..=5)]
+    tilt: Option<i8>,
+    #[gbnf(max_len = 4)]
+    label: Name,
+}
+
+#[test]
+fn constraints_apply_through_aliases_and_options() {
+    let grammar = Shelf::to_grammar();
+    assert_accepts(
+        grammar,
+        &Shelf {
+            count: 9,
+            tilt: Some(-5),
+            label: "jars".to_string(),
+        },
+    );
+    assert_accepts(
+        grammar,
+        &Shelf {
+            count: 1,
+            tilt: None,
+            label: String::new(),
+        },
+    );
+
+    let shelf = r#"{"count": %, "tilt": null, "label": "jars"}"#;
+    assert_rejects(grammar, &shelf.replace('%', "10"));
+    assert_rejects(grammar, &shelf.replace('%', "0"));
+    assert_rejects(grammar, r#"{"count": 1, "tilt": null, "label": "glass"}"#);
+}
@@ -0,0 +1,205 @@
+//! The JSON Schema of a type allows the same documents as its grammar.
+
+use gbnf::prelude::*;
+use gbnf::{GbnfRecognizer, ParsedGrammar, Recognition};
+use gbnf_derive::Gbnf;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+#[derive(Serialize, Deserialize, Gbnf)]
+#[serde(rename_all = "snake_case")]
+enum Kind {
+    Bakery,
+    Smithy,
+}
+
+#[derive(Serialize, Deserialize, Gbnf)]
+struct Stock {
+    item: String,
+    count: u32,
+}
+
+#[derive(Serialize, Deserialize, Gbnf)]
+struct Shop {
+    #[gbnf(max_len = 5)]
+    name: String,
+
+    #[gbnf(range = 1..=10)]
+    rating: u8,
+
+    #[gbnf(range = -5..5)]
+    offset: i32,
+
+    owner: Option<String>,
+
+    #[gbnf(omittable)]
+    motto: Option<String>,
+
+    kind: Kind,
+    stock: Vec<Stock>,
+    open: bool,
+}
+
+/// Whether the value is valid against the schema. Only covers the
+/// keywords that the schemas of derived types use.
+fn valid(schema: &Value, value: &Value) -> bool {
+    if let Some(schemas) = schema.get("anyOf") {
+        let schemas = schemas.as_array().unwrap();
+        return schemas.iter().any(|schema| valid(schema, value));
+    }
+
+    if let Some(values) = schema.get("enum") {
+        return values.as_array().unwrap().contains(value);
+    }
+
+    let in_range = |number: f64| {
+        let min = schema.get("minimum").and_then(Value::as_f64);
+        let max = schema.get("maximum").and_then(Value::as_f64);
+        min.is_none_or(|min| number >= min) && max.is_none_or(|max| number <= max)
+    };
+
+    match schema["type"].as_str().unwrap() {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "integer" => value.as_i64().is_some_and(|number| in_range(number as f64)),
+        "number" => value.as_f64().is_some_and(in_range),
+        "string" => value.as_str().is_some_and(|text| {
+            let max = schema.get("maxLength").and_then(Value::as_u64);
+            max.is_none_or(|max| text.chars().count() as u64 <= max)
+        }),
+        "array" => value
+            .as_array()
+            .is_some_and(|items| items.iter().all(|item| valid(&schema["items"], item))),
+        "object" => value.as_object().is_some_and(|object| {
+            let properties = schema["properties"].as_object().unwrap();
+            let required = schema["required"].as_array().unwrap();
+
+            required
+                .iter()
+                .all(|key| object.contains_key(key.as_str().unwrap()))
+                && object.iter().all(|(key, value)| {
+                    properties
+                        .get(key)
+                        .is_some_and(|schema| valid(schema, value))
+                })
+        }),
+        other => panic!("unexpected type {}", other),
+    }
+}
+
+fn recognizes(value: &Value) -> bool {
+    let grammar = ParsedGrammar::parse(Shop::to_grammar()).unwrap();
+    let recognizer = GbnfRecognizer::new(&grammar).unwrap();
+    recognizer.recognize(&value.to_string()) == Recognition::Complete
+}
+
+fn shop() -> Value {
+    json!({
+        "name": "Crumb",
+        "rating": 7,
+        "offset": -2,
+        "owner": "Ann",
+        "motto": "Fresh",
+        "kind": "bakery",
+        "stock": [{ "item": "bread", "count": 3 }],
+        "open": true,
+    })
+}
+
+/// The shop with one field changed, or removed if the value is None.
+/// The other fields keep their order, which the grammar fixes.
+fn shop_with(key: &str, value: Option<Value>) -> Value {
+    let mut shop = shop();
+    match value {
+        Some(value) => shop[key] = value,
+        None => {
+            shop.as_object_mut().unwrap().shift_remove(key);
+        }
+    }
+    shop
+}
+
+#[test]
+fn lists_the_required_fields() {
+    let schema: Value = serde_json::from_str(Shop::to_json_schema()).unwrap();
+    let required: Vec<&str> = schema["required"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|key| key.as_str().unwrap())
+        .collect();
+
+    assert_eq!(
+        required,
+        vec!["name", "rating", "offset", "owner", "kind", "stock", "open"]
+    );
+    assert_eq!(schema["additionalProperties"], json!(false));
+}
+
+#[test]
+fn constrains_values_like_the_grammar() {
+    let schema: Value = serde_json::from_str(Shop::to_json_schema()).unwrap();
+    let properties = &schema["properties"];
+
+    assert_eq!(
+        properties["name"],
+        json!({ "type": "string", "maxLength": 5 })
+    );
+    assert_eq!(
+        properties["rating"],
+        json!({ "type": "integer", "minimum": 1, "maximum": 10 })
+    );
+    assert_eq!(
+        properties["offset"],
+        json!({ "type": "integer", "minimum": -5, "maximum": 4 })
+    );
+    assert_eq!(
+        properties["owner"],
+        json!({ "anyOf": [{ "type": "string" }, { "type": "null" }] })
+    );
+    assert_eq!(properties["kind"], json!({ "enum": ["bakery", "smithy"] }));
+    assert_eq!(properties["stock"]["type"], json!("array"));
+    assert_eq!(
+        properties["stock"]["items"]["required"],
+        json!(["item", "count"])
+    );
+}
+
+#[test]
+fn allows_the_same_documents_as_the_grammar() {
+    let schema: Value = serde_json::from_str(Shop::to_json_schema()).unwrap();
+
+    let cases = [
+        (shop(), true),
+        (shop_with("motto", None), true),
+        (shop_with("motto", Some(Value::Null)), true),
+        (shop_with("owner", Some(Value::Null)), true),
+        (shop_with("owner", None), false),
+        (shop_with("name", Some(json!("Loaf"))), true),
+        (shop_with("name", Some(json!("Crumbs"))), false),
+        (shop_with("rating", Some(json!(1))), true),
+        (shop_with("rating", Some(json!(10))), true),
+        (shop_with("rating", Some(json!(0))), false),
+        (shop_with("rating", Some(json!(11))), false),
+        (shop_with("rating", Some(json!(1.5))), false),
+        (shop_with("offset", Some(json!(-5))), true),
+        (shop_with("offset", Some(json!(4))), true),
+        (shop_with("offset", Some(json!(5))), false),
+        (shop_with("offset", Some(json!(-6))), false),
+        (shop_with("kind", Some(json!("smithy"))), true),
+        (shop_with("kind", Some(json!("tavern"))), false),
+        (shop_with("stock", Some(json!([]))), true),
+        (shop_with("stock", Some(json!([{ "item": "bun" }]))), false),
+        (
+            shop_with("stock", Some(json!([{ "item": "bun", "count": -1 }]))),
+            false,
+        ),
+        (shop_with("open", Some(json!("yes"))), false),
+        (shop_with("extra", Some(json!(1))), false),
+    ];
+
+    for (shop, allowed) in cases {
+        assert_eq!(valid(&schema, &shop), allowed, "schema: {}", shop);
+        assert_eq!(recognizes(&shop), allowed, "grammar: {}", shop);
+    }
+}
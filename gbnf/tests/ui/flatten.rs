@@ -0,0 +1,18 @@
+use gbnf::prelude::*;
+use gbnf_derive::Gbnf;
+use serde_derive::Deserialize;
+
+#[derive(Deserialize, Gbnf)]
+struct Position {
+    x: i32,
+    y: i32,
+}
+
+#[derive(Deserialize, Gbnf)]
+struct Marker {
+    name: String,
+    #[serde(flatten)]
+    position: Position,
+}
+
+fn main() {}
@@ -0,0 +1,17 @@
+use gbnf::prelude::*;
+use gbnf_derive::Gbnf;
+use serde_derive::Deserialize;
+
+#[derive(Deserialize, Gbnf)]
+struct Lamp {
+    lit: bool,
+}
+
+#[derive(Deserialize, Gbnf)]
+#[serde(tag = "kind")]
+enum Thing {
+    Lamp(Lamp),
+    Rock,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use gbnf_derive::Gbnf;
+use serde_derive::Deserialize;
+
+#[derive(Deserialize, Gbnf)]
+struct Shelf {
+    #[gbnf(max_len = 10)]
+    value: u32,
+}
+
+fn main() {}
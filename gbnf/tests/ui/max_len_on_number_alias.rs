@@ -0,0 +1,13 @@
+use gbnf::prelude::*;
+use gbnf_derive::Gbnf;
+use serde_derive::Deserialize;
+
+type Count = u32;
+
+#[derive(Deserialize, Gbnf)]
+struct Shelf {
+    #[gbnf(max_len = 10)]
+    count: Count,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use gbnf_derive::Gbnf;
+use serde_derive::Deserialize;
+
+#[derive(Deserialize, Gbnf)]
+struct Marker {
+    name: String,
+    #[gbnf(omittable)]
+    label: String,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use gbnf_derive::Gbnf;
+use serde_derive::Deserialize;
+
+#[derive(Deserialize, Gbnf)]
+struct Shelf {
+    #[gbnf(pattern = "[a-z]+")]
+    value: Vec<String>,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use gbnf_derive::Gbnf;
+use serde_derive::Deserialize;
+
+#[derive(Deserialize, Gbnf)]
+struct Shelf {
+    #[gbnf(range = 0..=10)]
+    value: f64,
+}
+
+fn main() {}
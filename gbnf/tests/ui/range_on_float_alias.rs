@@ -0,0 +1,13 @@
+use gbnf::prelude::*;
+use gbnf_derive::Gbnf;
+use serde_derive::Deserialize;
+
+type Weight = f64;
+
+#[derive(Deserialize, Gbnf)]
+struct Shelf {
+    #[gbnf(range = 0..=10)]
+    weight: Weight,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use gbnf_derive::Gbnf;
+use serde_derive::Deserialize;
+
+#[derive(Deserialize, Gbnf)]
+#[serde(untagged)]
+enum Answer {
+    Yes(bool),
+    Text(String),
+}
+
+fn main() {}
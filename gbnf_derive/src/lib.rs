@@ -1,34 +1,408 @@
 use proc_macro::{Span, TokenStream};
 use quote::{quote, ToTokens};
+use syn::ext::IdentExt;
+use syn::meta::ParseNestedMeta;
 use syn::parse::{Parse, ParseStream};
 use syn::punctuated::Punctuated;
+use syn::token::Paren;
 use syn::{braced, parse_macro_input};
-use syn::{DeriveInput, Field, Ident, LitStr, Token};
+use syn::{
+    Attribute, Data, DataEnum, DeriveInput, Expr, ExprRange, Field, Fields, GenericArgument, Ident,
+    Lit, LitInt, LitStr, PathArguments, RangeLimits, Token, Type, UnOp, Variant, Visibility,
+};
 
-#[derive(Debug)]
 struct GbnfStructDef {
+    attrs: Vec<Attribute>,
+    vis: Visibility,
     name: Ident,
     fields: Punctuated<Field, Token![,]>,
 }
 
 impl Parse for GbnfStructDef {
     fn parse(input: ParseStream) -> syn::Result<Self> {
-        // let _ = Discard tokens we don't care about.
-        let _: Option<Token![pub]> = input.parse()?;
-        let _: Option<Token![struct]> = input.parse()?;
+        let attrs = input.call(Attribute::parse_outer)?;
+        let vis: Visibility = input.parse()?;
+        let _: Token![struct] = input.parse()?;
 
         let content;
         let name: Ident = input.parse()?;
         let _ = braced!(content in input);
 
         Ok(GbnfStructDef {
+            attrs,
+            vis,
             name,
             fields: content.parse_terminated(Field::parse_named, Token![,])?,
         })
     }
 }
 
-fn generate_gbnf(input: TokenStream, create_struct: bool) -> TokenStream {
+/// The serde attributes that change which JSON a type can be
+/// deserialized from, and so what the grammar must allow.
+#[derive(Default)]
+struct SerdeAttrs {
+    rename: Option<LitStr>,
+    rename_all: Option<LitStr>,
+    rename_all_fields: Option<LitStr>,
+    tag: Option<LitStr>,
+    content: Option<LitStr>,
+    untagged: bool,
+    skip: bool,
+    default: bool,
+    flatten: bool,
+}
+
+impl SerdeAttrs {
+    fn parse(attrs: &[Attribute]) -> syn::Result<SerdeAttrs> {
+        let mut serde = SerdeAttrs::default();
+
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+            attr.parse_nested_meta(|meta| {
+                let key = meta.path.get_ident().map(Ident::to_string);
+                match key.as_deref() {
+                    Some("rename") => serde.rename = deserialize_name(&meta)?,
+                    Some("rename_all") => serde.rename_all = deserialize_name(&meta)?,
+                    Some("rename_all_fields") => serde.rename_all_fields = deserialize_name(&meta)?,
+                    Some("tag") => serde.tag = Some(meta.value()?.parse()?),
+                    Some("content") => serde.content = Some(meta.value()?.parse()?),
+                    Some("untagged") => serde.untagged = true,
+                    Some("skip") | Some("skip_deserializing") => serde.skip = true,
+                    Some("default") => {
+                        serde.default = true;
+                        skip_meta(&meta)?;
+                    }
+                    Some("flatten") => serde.flatten = true,
+                    _ => skip_meta(&meta)?,
+                }
+                Ok(())
+            })?;
+        }
+
+        Ok(serde)
+    }
+}
+
+/// The name of a `rename = "..."` style attribute, or its deserialize
+/// name if serialization and deserialization are named separately.
+fn deserialize_name(meta: &ParseNestedMeta) -> syn::Result<Option<LitStr>> {
+    if meta.input.peek(Token![=]) {
+        return Ok(Some(meta.value()?.parse()?));
+    }
+
+    let mut name = None;
+    meta.parse_nested_meta(|nested| {
+        match nested.path.is_ident("deserialize") {
+            true => name = Some(nested.value()?.parse()?),
+            false => skip_meta(&nested)?,
+        }
+        Ok(())
+    })?;
+
+    Ok(name)
+}
+
+/// Consume the value of an attribute we don't care about, e.g.
+/// `with = "..."`.
+fn skip_meta(meta: &ParseNestedMeta) -> syn::Result<()> {
+    if meta.input.peek(Token![=]) {
+        let _: syn::Expr = meta.value()?.parse()?;
+    } else if meta.input.peek(Paren) {
+        meta.parse_nested_meta(|nested| skip_meta(&nested))?;
+    }
+
+    Ok(())
+}
+
+/// A limit on the values of a field, from its gbnf attribute.
+enum FieldConstraint {
+    MaxLength(usize),
+    Pattern(String),
+    Range { min: Option<i64>, max: Option<i64> },
+}
+
+/// The gbnf attributes of a field.
+#[derive(Default)]
+struct GbnfAttrs {
+    /// `#[gbnf(omittable)]` lets the field be left out of the
+    /// generated JSON.
+    omittable: bool,
+
+    /// `#[gbnf(max_len = 100)]`, `#[gbnf(pattern = "...")]`, or
+    /// `#[gbnf(range = 0..=150)]`.
+    constraint: Option<FieldConstraint>,
+}
+
+impl GbnfAttrs {
+    fn parse(field: &Field) -> syn::Result<GbnfAttrs> {
+        let mut gbnf = GbnfAttrs::default();
+
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("gbnf"))
+        {
+            attr.parse_nested_meta(|meta| {
+                let key = meta.path.get_ident().map(Ident::to_string);
+                let constraint = match key.as_deref() {
+                    Some("omittable") => {
+                        gbnf.omittable = true;
+                        return Ok(());
+                    }
+                    Some("max_len") => {
+                        let length: LitInt = meta.value()?.parse()?;
+                        FieldConstraint::MaxLength(length.base10_parse()?)
+                    }
+                    Some("pattern") => {
+                        let pattern: LitStr = meta.value()?.parse()?;
+                        gbnf::pattern_to_gbnf(&pattern.value())
+                            .map_err(|err| syn::Error::new_spanned(&pattern, err))?;
+
+                        FieldConstraint::Pattern(pattern.value())
+                    }
+                    Some("range") => range_constraint(&meta.value()?.parse()?)?,
+                    _ => return Err(meta.error("unknown gbnf attribute")),
+                };
+
+                match gbnf.constraint {
+                    Some(_) => Err(meta.error("a field can only have one constraint")),
+                    None => {
+                        gbnf.constraint = Some(constraint);
+                        Ok(())
+                    }
+                }
+            })?;
+        }
+
+        Ok(gbnf)
+    }
+}
+
+/// An integer literal in a range, which may be negative.
+fn range_bound(expr: &Expr) -> syn::Result<i64> {
+    match expr {
+        Expr::Lit(lit) => match &lit.lit {
+            Lit::Int(int) => int.base10_parse(),
+            _ => Err(syn::Error::new_spanned(expr, "expected an integer")),
+        },
+        Expr::Unary(unary) if matches!(unary.op, UnOp::Neg(_)) => {
+            range_bound(&unary.expr).map(|bound| -bound)
+        }
+        _ => Err(syn::Error::new_spanned(expr, "expected an integer")),
+    }
+}
+
+/// `min..max`, `min..=max`, or either with one side left open.
+fn range_constraint(range: &ExprRange) -> syn::Result<FieldConstraint> {
+    let min = range.start.as_deref().map(range_bound).transpose()?;
+    let max = match (range.end.as_deref(), range.limits) {
+        (Some(end), RangeLimits::HalfOpen(_)) => Some(range_bound(end)? - 1),
+        (Some(end), RangeLimits::Closed(_)) => Some(range_bound(end)?),
+        (None, _) => None,
+    };
+
+    if let (Some(min), Some(max)) = (min, max) {
+        if min > max {
+            return Err(syn::Error::new_spanned(range, "the range is empty"));
+        }
+    }
+
+    Ok(FieldConstraint::Range { min, max })
+}
+
+/// The JSON key of a field, as serde would deserialize it.
+fn field_key(
+    field: &Field,
+    serde: &SerdeAttrs,
+    rename_all: Option<&LitStr>,
+) -> syn::Result<String> {
+    if let Some(rename) = &serde.rename {
+        return Ok(rename.value());
+    }
+
+    let name = field.ident.as_ref().expect("no ident").unraw().to_string();
+    match rename_all {
+        Some(rule) => rename_field(&name, &rule.value())
+            .ok_or_else(|| syn::Error::new_spanned(rule, "unknown rename rule")),
+        None => Ok(name),
+    }
+}
+
+/// Whether the type is written as an `Option`.
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "Option"),
+        _ => false,
+    }
+}
+
+/// What a field's type is, as far as can be told from its name.
+enum TypeKind {
+    Integer {
+        unsigned: bool,
+    },
+    Float,
+    Boolean,
+    String,
+    List,
+
+    /// E.g. a type alias, or a type of its own.
+    Unknown,
+}
+
+/// The kind of a type, or of the type in an `Option`, which can be
+/// constrained all the same.
+fn type_kind(ty: &Type) -> TypeKind {
+    let segment = match ty {
+        Type::Array(_) | Type::Slice(_) => return TypeKind::List,
+        Type::Path(path) => path.path.segments.last(),
+        _ => None,
+    };
+
+    let Some(segment) = segment else {
+        return TypeKind::Unknown;
+    };
+
+    match segment.ident.to_string().as_str() {
+        "Option" => match &segment.arguments {
+            PathArguments::AngleBracketed(args) => match args.args.first() {
+                Some(GenericArgument::Type(inner)) => type_kind(inner),
+                _ => TypeKind::Unknown,
+            },
+            _ => TypeKind::Unknown,
+        },
+        "Vec" => TypeKind::List,
+        "i8" | "i16" | "i32" | "i64" | "isize" => TypeKind::Integer { unsigned: false },
+        "u8" | "u16" | "u32" | "u64" | "usize" => TypeKind::Integer { unsigned: true },
+        "f32" | "f64" => TypeKind::Float,
+        "bool" => TypeKind::Boolean,
+        "String" | "char" => TypeKind::String,
+        _ => TypeKind::Unknown,
+    }
+}
+
+/// Reject a constraint that cannot apply to the field's type. If the
+/// type can't be told from its name, the compiler checks it instead,
+/// with the assertion this returns.
+fn check_constraint(ty: &Type, constraint: &FieldConstraint) -> syn::Result<Option<impl ToTokens>> {
+    let error = |msg: &str| Err(syn::Error::new_spanned(ty, msg));
+    let empty_range = "the range has no values of this unsigned type";
+
+    match (constraint, type_kind(ty)) {
+        (FieldConstraint::Range { max, .. }, TypeKind::Integer { unsigned }) => {
+            match unsigned && max.is_some_and(|max| max < 0) {
+                true => error(empty_range),
+                false => Ok(None),
+            }
+        }
+        (FieldConstraint::Range { max, .. }, TypeKind::Unknown) => {
+            let negative = max.is_some_and(|max| max < 0);
+            Ok(Some(quote! {
+                const _: () = assert!(
+                    !#negative || !<#ty as gbnf::GbnfRangeConstrainable>::UNSIGNED,
+                    #empty_range
+                );
+            }))
+        }
+        (FieldConstraint::Range { .. }, TypeKind::List) => {
+            error("a range can only constrain a single integer, not a list")
+        }
+        (FieldConstraint::Range { .. }, _) => error("a range can only constrain integer fields"),
+        (_, TypeKind::String) => Ok(None),
+        (_, TypeKind::Unknown) => Ok(Some(quote! {
+            const _: fn() = || {
+                fn string_constrainable<T: gbnf::GbnfStringConstrainable>() {}
+                string_constrainable::<#ty>();
+            };
+        })),
+        (FieldConstraint::MaxLength(_), _) => error("max_len can only constrain string fields"),
+        (FieldConstraint::Pattern(_), _) => error("a pattern can only constrain string fields"),
+    }
+}
+
+/// The GBNF fields of a struct, or of a struct variant of an enum.
+fn field_gbnfs(
+    fields: &Punctuated<Field, Token![,]>,
+    rename_all: Option<&LitStr>,
+    all_default: bool,
+) -> syn::Result<Vec<impl ToTokens>> {
+    let mut gbnfs = vec![];
+    for field in fields {
+        let serde = SerdeAttrs::parse(&field.attrs)?;
+
+        // Skipped fields are never read from the JSON, so the model
+        // need not generate them.
+        if serde.skip {
+            continue;
+        }
+
+        // A flattened field's keys are mixed in with the struct's own
+        // keys, which a complex type can't express.
+        if serde.flatten {
+            return Err(syn::Error::new_spanned(
+                field,
+                "#[serde(flatten)] is not supported by the Gbnf derive",
+            ));
+        }
+
+        let field_type = &field.ty;
+        let key = field_key(field, &serde, rename_all)?;
+        let field_ident = LitStr::new(&key, Span::call_site().into());
+
+        // Fields that serde can fill in by itself may be left out.
+        // Serde reads a missing Option as None, so those can be made
+        // omittable too, but nothing else can.
+        let gbnf = GbnfAttrs::parse(field)?;
+        let defaulted = serde.default || all_default;
+        if gbnf.omittable && !defaulted && !is_option(field_type) {
+            return Err(syn::Error::new_spanned(
+                field,
+                "only Option fields and fields with a serde default can be omittable",
+            ));
+        }
+
+        let omittable = gbnf.omittable || defaulted;
+
+        let assertion = match &gbnf.constraint {
+            Some(constraint) => check_constraint(field_type, constraint)?,
+            None => None,
+        };
+
+        let omittable = omittable.then(|| quote! { .omittable() });
+        let constrained = gbnf.constraint.map(|constraint| {
+            let constraint = match constraint {
+                FieldConstraint::MaxLength(length) => quote! { GbnfConstraint::MaxLength(#length) },
+                FieldConstraint::Pattern(pattern) => {
+                    quote! { GbnfConstraint::Pattern(#pattern.to_string()) }
+                }
+                FieldConstraint::Range { min, max } => {
+                    let min = min
+                        .map(|min| quote! { Some(#min) })
+                        .unwrap_or(quote! { None });
+                    let max = max
+                        .map(|max| quote! { Some(#max) })
+                        .unwrap_or(quote! { None });
+                    quote! { GbnfConstraint::Range { min: #min, max: #max } }
+                }
+            };
+
+            quote! { .constrained(#constraint) }
+        });
+
+        let gbnf = quote! { gbnf_field!(#field_ident, #field_type) #omittable #constrained };
+        gbnfs.push(match assertion {
+            Some(assertion) => quote! { { #assertion #gbnf } },
+            None => gbnf,
+        });
+    }
+
+    Ok(gbnfs)
+}
+
+fn generate_gbnf(input: TokenStream, create_struct: bool) -> syn::Result<TokenStream> {
     // To define complex types, we take a struct into the macro, and
     // then output a bunch of calls to gbnf_field (wrapped in gbnf
     // complex).
@@ -37,71 +411,446 @@ fn generate_gbnf(input: TokenStream, create_struct: bool) -> TokenStream {
     // run, and then shove the resulting GBNF rule into the type as a
     // static string.
 
-    if let Ok(expr_struct) = syn::parse::<GbnfStructDef>(input) {
-        let struct_name_str = LitStr::new(&expr_struct.name.to_string(), Span::call_site().into());
-        let struct_name = expr_struct.name;
-        let fields = expr_struct.fields.iter();
+    let expr_struct = syn::parse::<GbnfStructDef>(input)?;
+    let container = SerdeAttrs::parse(&expr_struct.attrs)?;
 
-        let gbnfs = expr_struct.fields.iter().map(|field| {
-            let field_type = &field.ty;
-            let field_ident = field
-                .ident
-                .as_ref()
-                .map(|i| i.to_string())
-                .map(|field_name| LitStr::new(&field_name, Span::call_site().into()))
-                .expect("no ident");
+    let struct_name_str = LitStr::new(&expr_struct.name.to_string(), Span::call_site().into());
+    let struct_name = &expr_struct.name;
+
+    let gbnfs = field_gbnfs(
+        &expr_struct.fields,
+        container.rename_all.as_ref(),
+        container.default,
+    )?;
 
-            quote! { gbnf_field!(#field_ident, #field_type) }
+    let struct_frag = if create_struct {
+        // The gbnf attributes are only for us, and not known outside
+        // of the derive.
+        let fields = expr_struct.fields.iter().cloned().map(|mut field| {
+            field.attrs.retain(|attr| !attr.path().is_ident("gbnf"));
+            field
         });
 
-        let struct_frag = if create_struct {
-            quote! {
-                pub struct #struct_name {
-                    #(#fields),*
+        let attrs = &expr_struct.attrs;
+        let vis = &expr_struct.vis;
+
+        quote! {
+            #(#attrs)*
+            #vis struct #struct_name {
+                #(#fields),*
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let code = quote! {
+        #struct_frag
+
+        impl #struct_name {
+            pub fn to_grammar() -> &'static str {
+                use std::sync::OnceLock;
+                static GRAMMAR: OnceLock<String> = OnceLock::new();
+                GRAMMAR.get_or_init(|| Self::to_gbnf().as_complex().to_grammar())
+            }
+
+            pub fn to_json_schema() -> &'static str {
+                use std::sync::OnceLock;
+                static SCHEMA: OnceLock<String> = OnceLock::new();
+                SCHEMA.get_or_init(|| Self::to_gbnf().as_complex().to_json_schema())
+            }
+        }
+
+        impl AsGbnf for #struct_name {
+            fn to_gbnf() -> gbnf::GbnfFieldType {
+                GbnfFieldType::Complex(
+                    GbnfComplex {
+                        name: String::from(#struct_name_str),
+                        fields: vec![#(#gbnfs),*]
+                    }
+                )
+            }
+        }
+    };
+
+    Ok(code.into())
+}
+
+/// Lowercase the first letter, as serde does for camel case. Only
+/// ASCII letters are changed, like serde.
+fn lower_first(name: &str) -> String {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_ascii_lowercase().to_string() + chars.as_str(),
+        None => String::new(),
+    }
+}
+
+/// Rename a field the way serde's `rename_all` would. Fields are
+/// expected to be in snake case already.
+fn rename_field(field: &str, rule: &str) -> Option<String> {
+    let pascal_case = || {
+        field
+            .split('_')
+            .map(|word| {
+                let mut chars = word.chars();
+                match chars.next() {
+                    Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                    None => String::new(),
                 }
+            })
+            .collect::<String>()
+    };
+
+    let renamed = match rule {
+        "lowercase" | "snake_case" => field.to_string(),
+        "UPPERCASE" | "SCREAMING_SNAKE_CASE" => field.to_ascii_uppercase(),
+        "PascalCase" => pascal_case(),
+        "camelCase" => lower_first(&pascal_case()),
+        "kebab-case" => field.replace('_', "-"),
+        "SCREAMING-KEBAB-CASE" => field.to_ascii_uppercase().replace('_', "-"),
+        _ => return None,
+    };
+
+    Some(renamed)
+}
+
+/// Rename a variant the way serde's `rename_all` would.
+fn rename_variant(variant: &str, rule: &str) -> Option<String> {
+    let snake_case = || {
+        let mut snake = String::new();
+        for (i, ch) in variant.char_indices() {
+            if i > 0 && ch.is_uppercase() {
+                snake.push('_');
             }
-        } else {
-            quote! {}
-        };
+            snake.push(ch.to_ascii_lowercase());
+        }
+        snake
+    };
 
-        let code = quote! {
-            #struct_frag
+    let renamed = match rule {
+        "lowercase" => variant.to_ascii_lowercase(),
+        "UPPERCASE" => variant.to_ascii_uppercase(),
+        "PascalCase" => variant.to_string(),
+        "camelCase" => lower_first(variant),
+        "snake_case" => snake_case(),
+        "SCREAMING_SNAKE_CASE" => snake_case().to_ascii_uppercase(),
+        "kebab-case" => snake_case().replace('_', "-"),
+        "SCREAMING-KEBAB-CASE" => snake_case().to_ascii_uppercase().replace('_', "-"),
+        _ => return None,
+    };
 
-            impl #struct_name {
-                pub fn to_grammar() -> &'static str {
-                    use std::sync::OnceLock;
-                    static GRAMMAR: OnceLock<String> = OnceLock::new();
-                    GRAMMAR.get_or_init(|| Self::to_gbnf().as_complex().to_grammar())
+    Some(renamed)
+}
+
+/// The name of a variant in the JSON, as serde would deserialize it.
+fn variant_name(
+    variant: &Variant,
+    serde: &SerdeAttrs,
+    container: &SerdeAttrs,
+) -> syn::Result<String> {
+    if let Some(rename) = &serde.rename {
+        return Ok(rename.value());
+    }
+
+    let name = variant.ident.to_string();
+    match &container.rename_all {
+        Some(rule) => rename_variant(&name, &rule.value())
+            .ok_or_else(|| syn::Error::new_spanned(rule, "unknown rename rule")),
+        None => Ok(name),
+    }
+}
+
+/// A field that can only be the given string, e.g. the tag of an
+/// internally tagged enum.
+fn literal_field(field_name: &str, rule_name: &str, value: &str) -> impl ToTokens {
+    quote! {
+        GbnfField {
+            field_name: String::from(#field_name),
+            field_type: GbnfFieldType::Limited(GbnfLimited {
+                name: String::from(#rule_name),
+                primitive: GbnfPrimitive::String,
+                values: vec![String::from(#value)],
+            }),
+            omittable: false,
+        }
+    }
+}
+
+/// A variant of an enum with data, as one of the alternatives of a
+/// union. Follows serde's enum representations: externally tagged by
+/// default, internally tagged with `tag`, and adjacently tagged with
+/// `tag` and `content`.
+fn variant_gbnf(
+    enum_name: &Ident,
+    variant: &Variant,
+    container: &SerdeAttrs,
+) -> syn::Result<Option<impl ToTokens>> {
+    let serde = SerdeAttrs::parse(&variant.attrs)?;
+    if serde.skip {
+        return Ok(None);
+    }
+
+    let name = variant_name(variant, &serde, container)?;
+    let alt_name = format!("{}{}", enum_name, variant.ident);
+
+    // What the variant holds, if anything.
+    let content = match &variant.fields {
+        Fields::Unit => None,
+        // Serde flattens the content of an internally tagged newtype
+        // variant into the object with the tag, which only works if
+        // the content is an object. That cannot be told from the
+        // type's name.
+        Fields::Unnamed(_) if container.tag.is_some() && container.content.is_none() => {
+            let msg = "can only generate GBNF from struct and unit variants of internally \
+                       tagged enums";
+            return Err(syn::Error::new_spanned(variant, msg));
+        }
+        Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+            let field_type = &fields.unnamed[0].ty;
+            Some(quote! { gbnf_field_type!(#field_type) })
+        }
+        Fields::Unnamed(_) => {
+            let msg = "can only generate GBNF from tuple variants with one field";
+            return Err(syn::Error::new_spanned(variant, msg));
+        }
+        Fields::Named(fields) => {
+            let rename_all = serde
+                .rename_all
+                .as_ref()
+                .or(container.rename_all_fields.as_ref());
+            let gbnfs = field_gbnfs(&fields.named, rename_all, false)?;
+            let fields_name = format!("{}Fields", alt_name);
+            Some(quote! {
+                GbnfFieldType::Complex(GbnfComplex {
+                    name: String::from(#fields_name),
+                    fields: vec![#(#gbnfs),*],
+                })
+            })
+        }
+    };
+
+    let tag = container.tag.as_ref().map(LitStr::value);
+    let content_key = container.content.as_ref().map(LitStr::value);
+    let tag_name = format!("{}Tag", alt_name);
+
+    let code = match (tag, content_key, content) {
+        // Unit variants of externally tagged enums are just their name.
+        (None, _, None) => quote! {
+            GbnfFieldType::Limited(GbnfLimited {
+                name: String::from(#alt_name),
+                primitive: GbnfPrimitive::String,
+                values: vec![String::from(#name)],
+            })
+        },
+
+        // {"Variant": content}
+        (None, _, Some(content)) => quote! {
+            GbnfFieldType::Complex(GbnfComplex {
+                name: String::from(#alt_name),
+                fields: vec![GbnfField {
+                    field_name: String::from(#name),
+                    field_type: #content,
+                    omittable: false,
+                }],
+            })
+        },
+
+        // {"tag": "Variant"}
+        (Some(tag), _, None) => {
+            let tag_field = literal_field(&tag, &tag_name, &name);
+            quote! {
+                GbnfFieldType::Complex(GbnfComplex {
+                    name: String::from(#alt_name),
+                    fields: vec![#tag_field],
+                })
+            }
+        }
+
+        // {"tag": "Variant", "content": content}
+        (Some(tag), Some(content_key), Some(content)) => {
+            let tag_field = literal_field(&tag, &tag_name, &name);
+            quote! {
+                GbnfFieldType::Complex(GbnfComplex {
+                    name: String::from(#alt_name),
+                    fields: vec![
+                        #tag_field,
+                        GbnfField {
+                            field_name: String::from(#content_key),
+                            field_type: #content,
+                            omittable: false,
+                        },
+                    ],
+                })
+            }
+        }
+
+        // {"tag": "Variant", ...fields of the content}. The content is
+        // always a struct variant's fields.
+        (Some(tag), None, Some(content)) => {
+            let tag_field = literal_field(&tag, &tag_name, &name);
+            quote! {
+                {
+                    let mut complex = #content.as_complex();
+                    complex.name = String::from(#alt_name);
+                    complex.fields.insert(0, #tag_field);
+                    GbnfFieldType::Complex(complex)
                 }
             }
+        }
+    };
+
+    Ok(Some(code))
+}
+
+/// Unit-only enums become a set of string literals, one for each
+/// variant, as serde would serialize them. Other enums become a union
+/// of their variants.
+fn generate_enum_gbnf(input: &DeriveInput, data: &DataEnum) -> syn::Result<TokenStream> {
+    let container = SerdeAttrs::parse(&input.attrs)?;
+    let enum_name = &input.ident;
+    let enum_name_str = LitStr::new(&enum_name.to_string(), Span::call_site().into());
+
+    if container.untagged {
+        let msg = "can only generate GBNF from tagged enums";
+        return Err(syn::Error::new_spanned(enum_name, msg));
+    }
+
+    let unit_only = data
+        .variants
+        .iter()
+        .all(|variant| matches!(variant.fields, Fields::Unit));
+
+    if unit_only && container.tag.is_none() {
+        let mut values = vec![];
+        for variant in &data.variants {
+            let serde = SerdeAttrs::parse(&variant.attrs)?;
+            if !serde.skip {
+                values.push(variant_name(variant, &serde, &container)?);
+            }
+        }
 
-            impl AsGbnf for #struct_name {
+        let code = quote! {
+            impl AsGbnf for #enum_name {
                 fn to_gbnf() -> gbnf::GbnfFieldType {
-                    GbnfFieldType::Complex(
-                        GbnfComplex {
-                            name: String::from(#struct_name_str),
-                            fields: vec![#(#gbnfs),*]
+                    GbnfFieldType::Limited(
+                        GbnfLimited {
+                            name: String::from(#enum_name_str),
+                            primitive: GbnfPrimitive::String,
+                            values: vec![#(String::from(#values)),*]
                         }
                     )
                 }
             }
         };
 
-        code.into()
-    } else {
-        panic!("Can only generate GBNF from structs of owned data (pub or private)");
+        return Ok(code.into());
     }
+
+    let mut alternatives = vec![];
+    for variant in &data.variants {
+        if let Some(alternative) = variant_gbnf(enum_name, variant, &container)? {
+            alternatives.push(alternative);
+        }
+    }
+
+    let code = quote! {
+        impl AsGbnf for #enum_name {
+            fn to_gbnf() -> gbnf::GbnfFieldType {
+                GbnfFieldType::Union(
+                    GbnfUnion {
+                        name: String::from(#enum_name_str),
+                        alternatives: vec![#(#alternatives),*]
+                    }
+                )
+            }
+        }
+    };
+
+    Ok(code.into())
 }
 
 /// Create a GBNF complex type as a Rust struct.
 #[proc_macro]
 pub fn gbnf_complex(input: TokenStream) -> TokenStream {
-    generate_gbnf(input, true)
+    generate_gbnf(input, true).unwrap_or_else(|err| err.to_compile_error().into())
 }
 
-/// Add the ability to convert a Rust type into a GBNF grammar.
-#[proc_macro_derive(Gbnf)]
+/// Add the ability to convert a Rust type into a GBNF grammar. Structs
+/// become complex types, unit-only enums become limited values, and
+/// other enums become a union of their variants.
+/// `Option` fields can be null, and fields with a serde default can be
+/// left out entirely, as can `Option` fields marked
+/// `#[gbnf(omittable)]`. Serde's renaming, skipping and defaults are
+/// followed, so the grammar matches what serde deserializes.
+/// `#[serde(flatten)]` and untagged enums are not supported, and
+/// internally tagged enums can only have struct and unit variants.
+/// String fields can be limited with `#[gbnf(max_len = 100)]` or
+/// `#[gbnf(pattern = "[A-Z][a-z]+")]`, and integer fields with
+/// `#[gbnf(range = 0..=150)]`. A limit on a field of another type, or
+/// a negative range on an unsigned field, is a compile error.
+#[proc_macro_derive(Gbnf, attributes(gbnf))]
 pub fn gbnf(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
-    generate_gbnf(input.to_token_stream().into(), false)
+    match &input.data {
+        Data::Enum(data) => {
+            generate_enum_gbnf(&input, data).unwrap_or_else(|err| err.to_compile_error().into())
+        }
+        _ => generate_gbnf(input.to_token_stream().into(), false)
+            .unwrap_or_else(|err| err.to_compile_error().into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renames_fields_like_serde() {
+        let cases = [
+            ("lowercase", "item_name"),
+            ("UPPERCASE", "ITEM_NAME"),
+            ("PascalCase", "ItemName"),
+            ("camelCase", "itemName"),
+            ("snake_case", "item_name"),
+            ("SCREAMING_SNAKE_CASE", "ITEM_NAME"),
+            ("kebab-case", "item-name"),
+            ("SCREAMING-KEBAB-CASE", "ITEM-NAME"),
+        ];
+
+        for (rule, renamed) in cases {
+            assert_eq!(rename_field("item_name", rule).as_deref(), Some(renamed));
+        }
+
+        assert_eq!(rename_field("item_name", "Title Case"), None);
+    }
+
+    #[test]
+    fn renames_variants_like_serde() {
+        let cases = [
+            ("lowercase", "nonbinary"),
+            ("UPPERCASE", "NONBINARY"),
+            ("PascalCase", "NonBinary"),
+            ("camelCase", "nonBinary"),
+            ("snake_case", "non_binary"),
+            ("SCREAMING_SNAKE_CASE", "NON_BINARY"),
+            ("kebab-case", "non-binary"),
+            ("SCREAMING-KEBAB-CASE", "NON-BINARY"),
+        ];
+
+        for (rule, renamed) in cases {
+            assert_eq!(rename_variant("NonBinary", rule).as_deref(), Some(renamed));
+        }
+    }
+
+    #[test]
+    fn camel_case_handles_any_first_letter() {
+        assert_eq!(rename_field("_", "camelCase").as_deref(), Some(""));
+        assert_eq!(rename_field("_item", "camelCase").as_deref(), Some("item"));
+        assert_eq!(
+            rename_field("élan_vital", "camelCase").as_deref(),
+            Some("élanVital")
+        );
+        assert_eq!(rename_variant("Élan", "camelCase").as_deref(), Some("Élan"));
+    }
 }